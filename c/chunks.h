@@ -0,0 +1,154 @@
+/*
+ * Variable length values on top of 32-byte storage slots. A value stored at
+ * +key+ keeps its length as a 64-bit little endian integer in +key+ itself,
+ * while the content is split into 32-byte chunks kept at keys derived via
+ * csal_key_chunk, the last chunk is padded with zeros.
+ *
+ * This header is included by both generator.h and validator.h, following the
+ * same convention as dummy VM: reads check +changes+ first, then
+ * +existing_values+, writes go to both of them in validators, and only to
+ * +changes+ in generators.
+ */
+#ifndef CSAL_CHUNKS_H_
+#define CSAL_CHUNKS_H_
+
+#include "gas.h"
+#include "keys.h"
+
+/*
+ * Longest value accepted, matching MAX_VALUE_LENGTH of the Rust crate. Lengths
+ * above it are rejected both when storing and when found in state, which also
+ * keeps chunk counts from overflowing.
+ */
+#define CSAL_MAX_VALUE_LENGTH (1 << 20)
+#define CSAL_ERROR_VALUE_TOO_LONG -47
+
+int csal_store_bytes(csal_change_t *existing_values, csal_change_t *changes,
+                     const uint8_t key[CSAL_KEY_BYTES], const uint8_t *data,
+                     uint64_t length);
+/*
+ * +length+ contains the size of +buffer+ when calling, and is set to the
+ * actual length of the value upon return. At most the size of +buffer+ bytes
+ * are copied.
+ */
+int csal_load_bytes(csal_change_t *existing_values, csal_change_t *changes,
+                    const uint8_t key[CSAL_KEY_BYTES], uint8_t *buffer,
+                    uint64_t *length);
+
+#ifndef CSAL_NO_IMPLEMENTATION
+int _csal_chunk_read(csal_change_t *existing_values, csal_change_t *changes,
+                     const uint8_t key[CSAL_KEY_BYTES],
+                     uint8_t value[CSAL_VALUE_BYTES]) {
+  /* Charged as a single access on both sides, see gas.h */
+  _csal_gas_enter_access();
+  if (csal_change_fetch(changes, key, value) != 0 &&
+      csal_change_fetch(existing_values, key, value) != 0) {
+    /* Values not provided are treated as zeros */
+    memset(value, 0, CSAL_VALUE_BYTES);
+  }
+  _csal_gas_leave_access();
+  return 0;
+}
+
+int _csal_chunk_write(csal_change_t *existing_values, csal_change_t *changes,
+                      const uint8_t key[CSAL_KEY_BYTES],
+                      const uint8_t value[CSAL_VALUE_BYTES]) {
+  int ret = 0;
+  _csal_gas_enter_access();
+#ifndef CSAL_GENERATOR
+  ret = csal_change_insert(existing_values, key, value);
+#else
+  (void)existing_values;
+#endif
+  if (ret == 0) {
+    ret = csal_change_insert(changes, key, value);
+  }
+  _csal_gas_leave_access();
+  return ret;
+}
+
+int csal_store_bytes(csal_change_t *existing_values, csal_change_t *changes,
+                     const uint8_t key[CSAL_KEY_BYTES], const uint8_t *data,
+                     uint64_t length) {
+  uint8_t value[CSAL_VALUE_BYTES];
+  uint8_t chunk_key[CSAL_KEY_BYTES];
+  uint64_t old_length = 0;
+  int ret = _csal_chunk_read(existing_values, changes, key, value);
+  if (ret != 0) {
+    return ret;
+  }
+  ret = csal_value_to_u64(value, &old_length);
+  if (ret != 0) {
+    return ret;
+  }
+  if (length > CSAL_MAX_VALUE_LENGTH || old_length > CSAL_MAX_VALUE_LENGTH) {
+    return CSAL_ERROR_VALUE_TOO_LONG;
+  }
+  uint64_t chunks = (length + CSAL_VALUE_BYTES - 1) / CSAL_VALUE_BYTES;
+  uint64_t old_chunks = (old_length + CSAL_VALUE_BYTES - 1) / CSAL_VALUE_BYTES;
+  for (uint64_t i = 0; i < chunks; i++) {
+    uint64_t offset = i * CSAL_VALUE_BYTES;
+    uint64_t size = length - offset;
+    if (size > CSAL_VALUE_BYTES) {
+      size = CSAL_VALUE_BYTES;
+    }
+    memset(value, 0, CSAL_VALUE_BYTES);
+    memcpy(value, &data[offset], size);
+    csal_key_chunk(key, i, chunk_key);
+    ret = _csal_chunk_write(existing_values, changes, chunk_key, value);
+    if (ret != 0) {
+      return ret;
+    }
+  }
+  /* Clear chunks left by a longer previous value */
+  memset(value, 0, CSAL_VALUE_BYTES);
+  for (uint64_t i = chunks; i < old_chunks; i++) {
+    csal_key_chunk(key, i, chunk_key);
+    ret = _csal_chunk_write(existing_values, changes, chunk_key, value);
+    if (ret != 0) {
+      return ret;
+    }
+  }
+  csal_value_from_u64(length, value);
+  return _csal_chunk_write(existing_values, changes, key, value);
+}
+
+int csal_load_bytes(csal_change_t *existing_values, csal_change_t *changes,
+                    const uint8_t key[CSAL_KEY_BYTES], uint8_t *buffer,
+                    uint64_t *length) {
+  uint8_t value[CSAL_VALUE_BYTES];
+  uint8_t chunk_key[CSAL_KEY_BYTES];
+  uint64_t actual_length = 0;
+  int ret = _csal_chunk_read(existing_values, changes, key, value);
+  if (ret != 0) {
+    return ret;
+  }
+  ret = csal_value_to_u64(value, &actual_length);
+  if (ret != 0) {
+    return ret;
+  }
+  if (actual_length > CSAL_MAX_VALUE_LENGTH) {
+    return CSAL_ERROR_VALUE_TOO_LONG;
+  }
+  uint64_t copied = *length;
+  if (copied > actual_length) {
+    copied = actual_length;
+  }
+  for (uint64_t offset = 0; offset < copied; offset += CSAL_VALUE_BYTES) {
+    csal_key_chunk(key, offset / CSAL_VALUE_BYTES, chunk_key);
+    ret = _csal_chunk_read(existing_values, changes, chunk_key, value);
+    if (ret != 0) {
+      return ret;
+    }
+    uint64_t size = copied - offset;
+    if (size > CSAL_VALUE_BYTES) {
+      size = CSAL_VALUE_BYTES;
+    }
+    memcpy(&buffer[offset], value, size);
+  }
+  *length = actual_length;
+  return 0;
+}
+#endif /* CSAL_NO_IMPLEMENTATION */
+
+#endif /* CSAL_CHUNKS_H_ */
@@ -0,0 +1,189 @@
+/*
+ * Code storage on top of chunked values. When CSAL_ENABLE_CODE_PROGRAMS is
+ * defined before including generator.h or validator.h, a program passed to the
+ * generator or validator can take one of the following forms:
+ *
+ * * CSALDPLY | code: stores code in account state, chunked under the key
+ * derived by csal_key_code, no VM is executed.
+ * * CSALCALL | code hash: loads previously stored code by its hash, then
+ * executes it with the VM.
+ * * Anything else is executed with the VM directly.
+ *
+ * Otherwise all programs are executed with the VM as they are, so existing
+ * programs starting with the magic bytes keep their behavior. Generator and
+ * validator must agree on this setting. Deploying code that is already stored
+ * is a no-op. A running program can always invoke stored code via csal_call,
+ * see below.
+ */
+#ifndef CSAL_CODE_H_
+#define CSAL_CODE_H_
+
+#include "chunks.h"
+#include "gas.h"
+
+#define CSAL_ERROR_CODE_NOT_FOUND -41
+#define CSAL_ERROR_CODE_TOO_LARGE -42
+#define CSAL_ERROR_INVALID_CALL -43
+#define CSAL_ERROR_CALL_TOO_DEEP -45
+
+#define CSAL_DEPLOY_MAGIC "CSALDPLY"
+#define CSAL_CALL_MAGIC "CSALCALL"
+#define CSAL_PROGRAM_MAGIC_BYTES 8
+
+#ifndef CSAL_MAX_CODE_SIZE
+#define CSAL_MAX_CODE_SIZE (64 * 1024)
+#endif
+
+/* Maximum number of nested csal_call invocations in progress */
+#ifndef CSAL_MAX_CALL_DEPTH
+#define CSAL_MAX_CALL_DEPTH 4
+#endif
+
+int csal_execute_program(const uint8_t *program, uint32_t length,
+                         csal_change_t *existing_values,
+                         csal_change_t *changes);
+/*
+ * Executes code stored under +code_hash+ with the VM against the same state,
+ * meant to be called from within execute_vm. When the callee returns a non-zero
+ * value, all its writes are rolled back before the value is returned. Gas
+ * consumed by the callee is counted towards the caller.
+ */
+int csal_call(const uint8_t code_hash[32], csal_change_t *existing_values,
+              csal_change_t *changes);
+/*
+ * Loads code stored under +code_hash+ into +buffer+, which holds
+ * CSAL_MAX_CODE_SIZE bytes, then creates a savepoint for the call. Both steps
+ * are charged as a single state access. Generators do this via syscall 3081,
+ * see generator.h.
+ */
+int _csal_prepare_call(const uint8_t code_hash[32],
+                       csal_change_t *existing_values, csal_change_t *changes,
+                       uint8_t *buffer, uint32_t *length,
+                       csal_savepoint_t *savepoint);
+
+#ifndef CSAL_NO_IMPLEMENTATION
+/* Each call level needs its own buffer, since callers are still running */
+static uint8_t _csal_code_buffer[CSAL_MAX_CALL_DEPTH + 1][CSAL_MAX_CODE_SIZE];
+static uint32_t _csal_call_depth = 0;
+
+int _csal_load_code(const uint8_t code_hash[32],
+                    csal_change_t *existing_values, csal_change_t *changes,
+                    uint8_t *buffer, uint32_t *length) {
+  uint8_t key[CSAL_KEY_BYTES];
+  csal_key_code(code_hash, key);
+  uint64_t code_length = CSAL_MAX_CODE_SIZE;
+  int ret =
+      csal_load_bytes(existing_values, changes, key, buffer, &code_length);
+  if (ret != 0) {
+    return ret;
+  }
+  if (code_length == 0) {
+    return CSAL_ERROR_CODE_NOT_FOUND;
+  }
+  if (code_length > CSAL_MAX_CODE_SIZE) {
+    return CSAL_ERROR_CODE_TOO_LARGE;
+  }
+  *length = (uint32_t)code_length;
+  return 0;
+}
+
+#ifndef CSAL_GENERATOR
+int _csal_prepare_call(const uint8_t code_hash[32],
+                       csal_change_t *existing_values, csal_change_t *changes,
+                       uint8_t *buffer, uint32_t *length,
+                       csal_savepoint_t *savepoint) {
+  _csal_gas_enter_access();
+  int ret =
+      _csal_load_code(code_hash, existing_values, changes, buffer, length);
+  if (ret == 0) {
+    ret = csal_savepoint(existing_values, changes, savepoint);
+  }
+  _csal_gas_leave_access();
+  return ret;
+}
+#endif
+
+int _csal_deploy_code(const uint8_t *code, uint32_t length,
+                      csal_change_t *existing_values, csal_change_t *changes) {
+  uint8_t hash[32];
+  uint8_t key[CSAL_KEY_BYTES];
+  uint8_t value[CSAL_VALUE_BYTES];
+  csal_code_hash(code, length, hash);
+  csal_key_code(hash, key);
+  uint64_t stored_length = 0;
+  int ret = _csal_chunk_read(existing_values, changes, key, value);
+  if (ret != 0) {
+    return ret;
+  }
+  ret = csal_value_to_u64(value, &stored_length);
+  if (ret != 0) {
+    return ret;
+  }
+  if (stored_length != 0) {
+    return 0;
+  }
+  return csal_store_bytes(existing_values, changes, key, code, length);
+}
+
+int csal_execute_program(const uint8_t *program, uint32_t length,
+                         csal_change_t *existing_values,
+                         csal_change_t *changes) {
+#ifdef CSAL_ENABLE_CODE_PROGRAMS
+  if (length >= CSAL_PROGRAM_MAGIC_BYTES &&
+      memcmp(program, CSAL_DEPLOY_MAGIC, CSAL_PROGRAM_MAGIC_BYTES) == 0) {
+    return _csal_deploy_code(&program[CSAL_PROGRAM_MAGIC_BYTES],
+                             length - CSAL_PROGRAM_MAGIC_BYTES, existing_values,
+                             changes);
+  }
+  if (length >= CSAL_PROGRAM_MAGIC_BYTES &&
+      memcmp(program, CSAL_CALL_MAGIC, CSAL_PROGRAM_MAGIC_BYTES) == 0) {
+    if (length != CSAL_PROGRAM_MAGIC_BYTES + 32) {
+      return CSAL_ERROR_INVALID_CALL;
+    }
+    uint32_t code_length = 0;
+    int ret = _csal_load_code(&program[CSAL_PROGRAM_MAGIC_BYTES],
+                              existing_values, changes, _csal_code_buffer[0],
+                              &code_length);
+    if (ret != 0) {
+      return ret;
+    }
+    csal_gas_start();
+    return execute_vm(_csal_code_buffer[0], code_length, existing_values,
+                      changes);
+  }
+#endif
+  csal_gas_start();
+  return execute_vm(program, length, existing_values, changes);
+}
+
+int csal_call(const uint8_t code_hash[32], csal_change_t *existing_values,
+              csal_change_t *changes) {
+  if (_csal_call_depth >= CSAL_MAX_CALL_DEPTH) {
+    return CSAL_ERROR_CALL_TOO_DEEP;
+  }
+  uint8_t *buffer = _csal_code_buffer[_csal_call_depth + 1];
+  uint32_t code_length = 0;
+  csal_savepoint_t savepoint;
+  int ret = _csal_prepare_call(code_hash, existing_values, changes, buffer,
+                               &code_length, &savepoint);
+  if (ret != 0) {
+    return ret;
+  }
+  _csal_call_depth++;
+  int call_ret = execute_vm(buffer, code_length, existing_values, changes);
+  _csal_call_depth--;
+  if (call_ret != 0) {
+    ret = csal_rollback(existing_values, changes, &savepoint);
+    if (ret != 0) {
+      return ret;
+    }
+  }
+  ret = csal_release(existing_values, changes, &savepoint);
+  if (ret != 0) {
+    return ret;
+  }
+  return call_ret;
+}
+#endif /* CSAL_NO_IMPLEMENTATION */
+
+#endif /* CSAL_CODE_H_ */
@@ -0,0 +1,115 @@
+/*
+ * Gas metering tied to CKB cycles, available to VMs running in both the
+ * generator and the validator.
+ *
+ * Consumed gas counts cycles spent since the program started executing. State
+ * accesses, such as csal_change_insert, csal_change_fetch and savepoints, are
+ * implemented differently in the generator and the validator, so cycles spent
+ * inside them are excluded, and each access is charged
+ * CSAL_STATE_ACCESS_CYCLES instead. Accesses nest, an operation built from
+ * several accesses, like reading a chunk, is charged as a single one. As long
+ * as the VM takes the same path, both sides report identical gas.
+ *
+ * Remaining gas is counted against a limit set via csal_gas_set_limit, which
+ * the VM must take from data both sides see, such as the program itself.
+ * Cycle limits are left out on purpose, as they differ between the generator
+ * and the chain.
+ *
+ * Metering is off by default, in which case no gas is ever consumed. Define
+ * CSAL_ENABLE_GAS_METERING to turn it on, cycles are then read via syscall
+ * 2042, which the generator provides. CKB v0.35 has no such syscall, so
+ * metering is for off-chain use only: validators built with it fail on chain,
+ * and must define CSAL_GAS_OFF_CHAIN to acknowledge they only run off chain,
+ * such as in ckb-debugger. Define CSAL_GAS_CUSTOM_CYCLES and provide
+ * _csal_current_cycles to use a different cycle source.
+ */
+#ifndef CSAL_GAS_H_
+#define CSAL_GAS_H_
+
+#include <stdint.h>
+#if defined(CSAL_ENABLE_GAS_METERING) && !defined(CSAL_GAS_CUSTOM_CYCLES)
+#include <ckb_syscalls.h>
+#if !defined(CSAL_GENERATOR) && !defined(CSAL_GAS_OFF_CHAIN)
+#error "Gas metering needs a syscall CKB lacks, see CSAL_GAS_OFF_CHAIN"
+#endif
+#endif
+
+#ifndef CSAL_STATE_ACCESS_CYCLES
+#define CSAL_STATE_ACCESS_CYCLES 1000
+#endif
+
+#define _CSAL_CURRENT_CYCLES_SYSCALL_NUMBER 2042
+
+/*
+ * Resets metering, called right before a program starts executing. The limit
+ * is reset to UINT64_MAX.
+ */
+void csal_gas_start();
+void csal_gas_set_limit(uint64_t limit);
+uint64_t csal_gas_consumed();
+/* Returns gas left before the limit is reached, or 0 once it is exceeded */
+uint64_t csal_gas_remaining();
+
+#ifndef CSAL_NO_IMPLEMENTATION
+static uint64_t _csal_gas_limit = UINT64_MAX;
+
+void csal_gas_set_limit(uint64_t limit) { _csal_gas_limit = limit; }
+
+uint64_t csal_gas_remaining() {
+  uint64_t consumed = csal_gas_consumed();
+  if (consumed >= _csal_gas_limit) {
+    return 0;
+  }
+  return _csal_gas_limit - consumed;
+}
+
+#ifndef CSAL_ENABLE_GAS_METERING
+void _csal_gas_enter_access() {}
+void _csal_gas_leave_access() {}
+void csal_gas_start() { _csal_gas_limit = UINT64_MAX; }
+uint64_t csal_gas_consumed() { return 0; }
+#else
+static uint64_t _csal_gas_start_cycles = 0;
+static uint64_t _csal_gas_excluded_cycles = 0;
+static uint64_t _csal_gas_access_start_cycles = 0;
+static uint64_t _csal_gas_accesses = 0;
+static uint32_t _csal_gas_access_depth = 0;
+
+#ifndef CSAL_GAS_CUSTOM_CYCLES
+uint64_t _csal_current_cycles() {
+  return (uint64_t)syscall(_CSAL_CURRENT_CYCLES_SYSCALL_NUMBER, 0, 0, 0, 0, 0,
+                           0);
+}
+#endif
+
+void _csal_gas_enter_access() {
+  if (_csal_gas_access_depth++ == 0) {
+    _csal_gas_access_start_cycles = _csal_current_cycles();
+  }
+}
+
+void _csal_gas_leave_access() {
+  if (--_csal_gas_access_depth == 0) {
+    _csal_gas_excluded_cycles +=
+        _csal_current_cycles() - _csal_gas_access_start_cycles;
+    _csal_gas_accesses++;
+  }
+}
+
+void csal_gas_start() {
+  _csal_gas_limit = UINT64_MAX;
+  _csal_gas_excluded_cycles = 0;
+  _csal_gas_accesses = 0;
+  _csal_gas_access_depth = 0;
+  _csal_gas_start_cycles = _csal_current_cycles();
+}
+
+uint64_t csal_gas_consumed() {
+  return _csal_current_cycles() - _csal_gas_start_cycles -
+         _csal_gas_excluded_cycles +
+         _csal_gas_accesses * CSAL_STATE_ACCESS_CYCLES;
+}
+#endif /* CSAL_ENABLE_GAS_METERING */
+#endif /* CSAL_NO_IMPLEMENTATION */
+
+#endif /* CSAL_GAS_H_ */
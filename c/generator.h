@@ -19,45 +19,162 @@
 #define CSAL_KEY_BYTES 32
 #define CSAL_VALUE_BYTES 32
 
+/*
+ * Marks generator builds. Here existing_values and changes are both backed by
+ * the same state kept by the host, so a write only needs to be issued once.
+ */
+#define CSAL_GENERATOR
+
+#define CSAL_ERROR_INSUFFICIENT_CAPACITY -20
+#define CSAL_ERROR_PROGRAM_TOO_LARGE -44
+#define CSAL_ERROR_INVALID_SAVEPOINT -46
+
+/*
+ * Maximum length of programs loaded via syscall, the host side limit
+ * (max_program_length in Config) should not exceed this.
+ */
+#ifndef CSAL_MAX_PROGRAM_SIZE
+#define CSAL_MAX_PROGRAM_SIZE (256 * 1024)
+#endif
+
 typedef void *csal_change_t;
+/*
+ * Writes since each savepoint are tracked by the host, only an ID is kept.
+ * IDs follow the order savepoints are created in, same as validator.h.
+ */
+typedef struct {
+  uint64_t id;
+} csal_savepoint_t;
 
 int csal_change_insert(csal_change_t *state, const uint8_t key[CSAL_KEY_BYTES],
                        const uint8_t value[CSAL_VALUE_BYTES]);
 int csal_change_fetch(csal_change_t *state, const uint8_t key[CSAL_KEY_BYTES],
                       uint8_t value[CSAL_VALUE_BYTES]);
+int csal_change_delete(csal_change_t *state, const uint8_t key[CSAL_KEY_BYTES]);
+/*
+ * Loads the executed program with the same semantics as partial loading in
+ * CKB syscalls: at most *length bytes starting from offset are copied into
+ * buffer, then *length is set to the full length of data after offset.
+ */
+int csal_load_program(uint8_t *buffer, uint64_t *length, size_t offset);
+/* See validator.h for explanations on savepoints */
+int csal_savepoint(csal_change_t *existing_values, csal_change_t *changes,
+                   csal_savepoint_t *savepoint);
+int csal_rollback(csal_change_t *existing_values, csal_change_t *changes,
+                  const csal_savepoint_t *savepoint);
+int csal_release(csal_change_t *existing_values, csal_change_t *changes,
+                 const csal_savepoint_t *savepoint);
 
 /* See validator.h for explanations on execute_vm */
 extern int execute_vm(const uint8_t *source, uint32_t length,
                       csal_change_t *existing_values, csal_change_t *changes);
 
 #include <ckb_syscalls.h>
+#include "code.h"
+#include "gas.h"
+
+static uint8_t _csal_program_buffer[CSAL_MAX_PROGRAM_SIZE];
 
 int main(int argc, char *argv[]) {
+  /* Generator don't need any setup for now, the actual APIs will be implemented
+   * via syscalls */
+  csal_change_t existing_values = NULL;
+  csal_change_t changes = NULL;
+  if (argc == 1) {
+    /* Program is not passed in argv, load it via syscall instead */
+    uint64_t length = CSAL_MAX_PROGRAM_SIZE;
+    int ret = csal_load_program(_csal_program_buffer, &length, 0);
+    if (ret != 0) {
+      return ret;
+    }
+    if (length > CSAL_MAX_PROGRAM_SIZE) {
+      return CSAL_ERROR_PROGRAM_TOO_LARGE;
+    }
+    return csal_execute_program(_csal_program_buffer, (uint32_t)length,
+                                &existing_values, &changes);
+  }
   if (argc != 3) {
     ckb_debug(
-        "Usage: generator <executed program length in 32-bit unsigned little "
-        "endian integer> <executed program>");
+        "Usage: generator [<executed program length in 32-bit unsigned little "
+        "endian integer> <executed program>]");
     return -1;
   }
   uint32_t length = *((uint32_t *)argv[1]);
-  /* Generator don't need any setup for now, the actual APIs will be implemented
-   * via syscalls */
-  csal_change_t existing_values = NULL;
-  csal_change_t changes = NULL;
-  return execute_vm((const uint8_t *)argv[2], length, &existing_values,
-                    &changes);
+  return csal_execute_program((const uint8_t *)argv[2], length,
+                              &existing_values, &changes);
 }
 
 #define _CSAL_CHANGE_INSERT_SYSCALL_NUMBER 3073
 #define _CSAL_CHANGE_FETCH_SYSCALL_NUMBER 3074
+#define _CSAL_LOAD_PROGRAM_SYSCALL_NUMBER 3075
+#define _CSAL_SAVEPOINT_SYSCALL_NUMBER 3076
+#define _CSAL_ROLLBACK_SYSCALL_NUMBER 3077
+#define _CSAL_RELEASE_SYSCALL_NUMBER 3078
+#define _CSAL_CHANGE_DELETE_SYSCALL_NUMBER 3079
+#define _CSAL_CALL_SYSCALL_NUMBER 3081
 
 int csal_change_insert(csal_change_t *state, const uint8_t key[CSAL_KEY_BYTES],
                        const uint8_t value[CSAL_VALUE_BYTES]) {
-  return syscall(_CSAL_CHANGE_INSERT_SYSCALL_NUMBER, key, value, 0, 0, 0, 0);
+  _csal_gas_enter_access();
+  int ret =
+      syscall(_CSAL_CHANGE_INSERT_SYSCALL_NUMBER, key, value, 0, 0, 0, 0);
+  _csal_gas_leave_access();
+  return ret;
 }
 int csal_change_fetch(csal_change_t *state, const uint8_t key[CSAL_KEY_BYTES],
                       uint8_t value[CSAL_VALUE_BYTES]) {
-  return syscall(_CSAL_CHANGE_FETCH_SYSCALL_NUMBER, key, value, 0, 0, 0, 0);
+  _csal_gas_enter_access();
+  int ret = syscall(_CSAL_CHANGE_FETCH_SYSCALL_NUMBER, key, value, 0, 0, 0, 0);
+  _csal_gas_leave_access();
+  return ret;
+}
+int csal_change_delete(csal_change_t *state,
+                       const uint8_t key[CSAL_KEY_BYTES]) {
+  _csal_gas_enter_access();
+  int ret = syscall(_CSAL_CHANGE_DELETE_SYSCALL_NUMBER, key, 0, 0, 0, 0, 0);
+  _csal_gas_leave_access();
+  return ret;
+}
+int _csal_prepare_call(const uint8_t code_hash[32],
+                       csal_change_t *existing_values, csal_change_t *changes,
+                       uint8_t *buffer, uint32_t *length,
+                       csal_savepoint_t *savepoint) {
+  uint64_t code_length = CSAL_MAX_CODE_SIZE;
+  _csal_gas_enter_access();
+  int ret = syscall(_CSAL_CALL_SYSCALL_NUMBER, code_hash, buffer, &code_length,
+                    &savepoint->id, 0, 0);
+  _csal_gas_leave_access();
+  if (ret == 0) {
+    *length = (uint32_t)code_length;
+  }
+  return ret;
+}
+int csal_load_program(uint8_t *buffer, uint64_t *length, size_t offset) {
+  return syscall(_CSAL_LOAD_PROGRAM_SYSCALL_NUMBER, buffer, length, offset, 0,
+                 0, 0);
+}
+int csal_savepoint(csal_change_t *existing_values, csal_change_t *changes,
+                   csal_savepoint_t *savepoint) {
+  _csal_gas_enter_access();
+  int ret =
+      syscall(_CSAL_SAVEPOINT_SYSCALL_NUMBER, &savepoint->id, 0, 0, 0, 0, 0);
+  _csal_gas_leave_access();
+  return ret;
+}
+int csal_rollback(csal_change_t *existing_values, csal_change_t *changes,
+                  const csal_savepoint_t *savepoint) {
+  _csal_gas_enter_access();
+  int ret =
+      syscall(_CSAL_ROLLBACK_SYSCALL_NUMBER, savepoint->id, 0, 0, 0, 0, 0);
+  _csal_gas_leave_access();
+  return ret;
+}
+int csal_release(csal_change_t *existing_values, csal_change_t *changes,
+                 const csal_savepoint_t *savepoint) {
+  _csal_gas_enter_access();
+  int ret = syscall(_CSAL_RELEASE_SYSCALL_NUMBER, savepoint->id, 0, 0, 0, 0, 0);
+  _csal_gas_leave_access();
+  return ret;
 }
 
 #endif /* CSAL_SMT_GENERATOR_H_ */
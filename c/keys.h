@@ -0,0 +1,143 @@
+/*
+ * Key derivation for structured storage on top of flat 32-byte keys, matching
+ * the keys module of the Rust crate, so on-chain VMs and off-chain querying
+ * code derive identical keys:
+ *
+ * * namespace: blake2b(0x00 | name)
+ * * mapping slot: blake2b(0x01 | base | key)
+ * * array index: blake2b(0x02 | base | index as 64-bit little endian)
+ * * value chunk: blake2b(0x03 | base | index as 64-bit little endian)
+ * * stored code: blake2b(0x04 | code hash), where code hash is blake2b(code)
+ *
+ * Integer values are stored in little endian, padded with zeros to 32 bytes.
+ */
+#ifndef CSAL_KEYS_H_
+#define CSAL_KEYS_H_
+
+#include <blake2b.h>
+#include <stddef.h>
+#include <stdint.h>
+#include <string.h>
+
+#define CSAL_ERROR_VALUE_OVERFLOW -40
+
+#define CSAL_KEY_TAG_NAMESPACE 0
+#define CSAL_KEY_TAG_MAPPING 1
+#define CSAL_KEY_TAG_ARRAY 2
+#define CSAL_KEY_TAG_CHUNK 3
+#define CSAL_KEY_TAG_CODE 4
+
+void csal_key_namespace(const uint8_t *name, size_t length, uint8_t key[32]);
+void csal_key_mapping(const uint8_t base[32], const uint8_t *mapping_key,
+                      size_t length, uint8_t key[32]);
+void csal_key_array(const uint8_t base[32], uint64_t index, uint8_t key[32]);
+void csal_key_chunk(const uint8_t base[32], uint64_t index, uint8_t key[32]);
+void csal_code_hash(const uint8_t *code, size_t length, uint8_t hash[32]);
+void csal_key_code(const uint8_t code_hash[32], uint8_t key[32]);
+void csal_value_from_u64(uint64_t v, uint8_t value[32]);
+int csal_value_to_u64(const uint8_t value[32], uint64_t *v);
+void csal_value_from_u128(uint64_t low, uint64_t high, uint8_t value[32]);
+int csal_value_to_u128(const uint8_t value[32], uint64_t *low,
+                       uint64_t *high);
+
+#ifndef CSAL_NO_IMPLEMENTATION
+void csal_key_namespace(const uint8_t *name, size_t length, uint8_t key[32]) {
+  uint8_t tag = CSAL_KEY_TAG_NAMESPACE;
+  blake2b_state blake2b_ctx;
+  blake2b_init(&blake2b_ctx, 32);
+  blake2b_update(&blake2b_ctx, &tag, 1);
+  blake2b_update(&blake2b_ctx, name, length);
+  blake2b_final(&blake2b_ctx, key, 32);
+}
+
+void csal_key_mapping(const uint8_t base[32], const uint8_t *mapping_key,
+                      size_t length, uint8_t key[32]) {
+  uint8_t tag = CSAL_KEY_TAG_MAPPING;
+  blake2b_state blake2b_ctx;
+  blake2b_init(&blake2b_ctx, 32);
+  blake2b_update(&blake2b_ctx, &tag, 1);
+  blake2b_update(&blake2b_ctx, base, 32);
+  blake2b_update(&blake2b_ctx, mapping_key, length);
+  blake2b_final(&blake2b_ctx, key, 32);
+}
+
+void _csal_key_indexed(uint8_t tag, const uint8_t base[32], uint64_t index,
+                       uint8_t key[32]) {
+  uint8_t index_bytes[8];
+  for (int i = 0; i < 8; i++) {
+    index_bytes[i] = (index >> (i * 8)) & 0xFF;
+  }
+  blake2b_state blake2b_ctx;
+  blake2b_init(&blake2b_ctx, 32);
+  blake2b_update(&blake2b_ctx, &tag, 1);
+  blake2b_update(&blake2b_ctx, base, 32);
+  blake2b_update(&blake2b_ctx, index_bytes, 8);
+  blake2b_final(&blake2b_ctx, key, 32);
+}
+
+void csal_key_array(const uint8_t base[32], uint64_t index, uint8_t key[32]) {
+  _csal_key_indexed(CSAL_KEY_TAG_ARRAY, base, index, key);
+}
+
+void csal_key_chunk(const uint8_t base[32], uint64_t index, uint8_t key[32]) {
+  _csal_key_indexed(CSAL_KEY_TAG_CHUNK, base, index, key);
+}
+
+void csal_code_hash(const uint8_t *code, size_t length, uint8_t hash[32]) {
+  blake2b_state blake2b_ctx;
+  blake2b_init(&blake2b_ctx, 32);
+  blake2b_update(&blake2b_ctx, code, length);
+  blake2b_final(&blake2b_ctx, hash, 32);
+}
+
+void csal_key_code(const uint8_t code_hash[32], uint8_t key[32]) {
+  uint8_t tag = CSAL_KEY_TAG_CODE;
+  blake2b_state blake2b_ctx;
+  blake2b_init(&blake2b_ctx, 32);
+  blake2b_update(&blake2b_ctx, &tag, 1);
+  blake2b_update(&blake2b_ctx, code_hash, 32);
+  blake2b_final(&blake2b_ctx, key, 32);
+}
+
+void csal_value_from_u64(uint64_t v, uint8_t value[32]) {
+  csal_value_from_u128(v, 0, value);
+}
+
+int csal_value_to_u64(const uint8_t value[32], uint64_t *v) {
+  uint64_t high = 0;
+  int ret = csal_value_to_u128(value, v, &high);
+  if (ret != 0) {
+    return ret;
+  }
+  if (high != 0) {
+    return CSAL_ERROR_VALUE_OVERFLOW;
+  }
+  return 0;
+}
+
+void csal_value_from_u128(uint64_t low, uint64_t high, uint8_t value[32]) {
+  memset(value, 0, 32);
+  for (int i = 0; i < 8; i++) {
+    value[i] = (low >> (i * 8)) & 0xFF;
+    value[i + 8] = (high >> (i * 8)) & 0xFF;
+  }
+}
+
+int csal_value_to_u128(const uint8_t value[32], uint64_t *low,
+                       uint64_t *high) {
+  for (int i = 16; i < 32; i++) {
+    if (value[i] != 0) {
+      return CSAL_ERROR_VALUE_OVERFLOW;
+    }
+  }
+  *low = 0;
+  *high = 0;
+  for (int i = 7; i >= 0; i--) {
+    *low = (*low << 8) | value[i];
+    *high = (*high << 8) | value[i + 8];
+  }
+  return 0;
+}
+#endif /* CSAL_NO_IMPLEMENTATION */
+
+#endif /* CSAL_KEYS_H_ */
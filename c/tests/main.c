@@ -31,7 +31,29 @@ int hex2bin(uint8_t* buf, const char* src) {
 
 #define CSAL_VALIDATOR_TYPE 1
 #define CSAL_NO_VALIDATOR_SKELETON
+#define CSAL_ENABLE_GAS_METERING
+#define CSAL_GAS_CUSTOM_CYCLES
+
+/* Cycle source for gas metering, tests advance cycles by hand */
+static uint64_t test_cycles = 0;
+uint64_t _csal_current_cycles() { return test_cycles; }
+
 #include "../validator.h"
+#include "../keys.h"
+
+extern int execute_vm(const uint8_t* source, uint32_t length,
+                      csal_change_t* existing_values, csal_change_t* changes);
+#include "../code.h"
+#include "../vms/dummy/dummy_vm.c"
+
+/* Appends an operation of the dummy VM, same as push_op in Rust tests */
+uint32_t push_op(uint8_t* program, uint32_t length, uint8_t op, uint8_t key,
+                 uint8_t value) {
+  program[length] = op;
+  memset(&program[length + 1], key, CSAL_KEY_BYTES);
+  memset(&program[length + 1 + CSAL_KEY_BYTES], value, CSAL_VALUE_BYTES);
+  return length + 1 + CSAL_KEY_BYTES + CSAL_VALUE_BYTES;
+}
 
 UTEST(smt, verify_empty) {
   uint8_t key[32];
@@ -342,4 +364,243 @@ UTEST(smt, update) {
   ASSERT_EQ(0, memcmp(root_hash, expected_hash, 32));
 }
 
+UTEST(keys, derive) {
+  uint8_t ns[32];
+  uint8_t key[32];
+  uint8_t expected[32];
+  uint8_t address[20];
+  memset(address, 0x11, 20);
+
+  csal_key_namespace((const uint8_t*)"balances", 8, ns);
+  hex2bin(expected,
+          "0x9c226f8a2726eb95030ea8c06835ae4042ee9375058d30e1236cec98930992c9");
+  ASSERT_EQ(0, memcmp(ns, expected, 32));
+
+  csal_key_mapping(ns, address, 20, key);
+  hex2bin(expected,
+          "0xd55ee9dce37b5283520e857947c77dbe2244c46a98d8fdd573a94a52b9f20a99");
+  ASSERT_EQ(0, memcmp(key, expected, 32));
+
+  csal_key_array(ns, 5, key);
+  hex2bin(expected,
+          "0xf136d0d735aaf2006d1b66b4bd9475d18d18b2076f82d4388d396dc5f76b0a3d");
+  ASSERT_EQ(0, memcmp(key, expected, 32));
+}
+
+UTEST(keys, u128_value) {
+  uint8_t value[32];
+  uint64_t low, high;
+  csal_value_from_u128(0x0102030405060708, 0x1112131415161718, value);
+  ASSERT_EQ(0x08, (int)value[0]);
+  ASSERT_EQ(0x18, (int)value[8]);
+  ASSERT_EQ(0, (int)value[16]);
+  ASSERT_EQ(0, csal_value_to_u128(value, &low, &high));
+  ASSERT_EQ(0x0102030405060708ull, low);
+  ASSERT_EQ(0x1112131415161718ull, high);
+  ASSERT_EQ(CSAL_ERROR_VALUE_OVERFLOW, csal_value_to_u64(value, &low));
+  value[31] = 1;
+  ASSERT_EQ(CSAL_ERROR_VALUE_OVERFLOW, csal_value_to_u128(value, &low, &high));
+}
+
+UTEST(change, savepoints) {
+  uint8_t key[32];
+  uint8_t value[32];
+  uint8_t fetched[32];
+  csal_entry_t existing_entries[4];
+  csal_entry_t change_entries[4];
+  csal_change_t existing_values;
+  csal_change_t changes;
+  csal_savepoint_t outer;
+  csal_savepoint_t inner;
+  csal_change_init(&existing_values, existing_entries, 4);
+  csal_change_init(&changes, change_entries, 2);
+
+  memset(key, 0x11, 32);
+  memset(value, 0x22, 32);
+  ASSERT_EQ(0, csal_change_insert(&changes, key, value));
+
+  ASSERT_EQ(0, csal_savepoint(&existing_values, &changes, &outer));
+  memset(value, 0x33, 32);
+  ASSERT_EQ(0, csal_change_insert(&changes, key, value));
+  ASSERT_EQ(0, csal_savepoint(&existing_values, &changes, &inner));
+  /* Entries before the innermost savepoint are not overwritten in place */
+  ASSERT_EQ(CSAL_ERROR_INSUFFICIENT_CAPACITY,
+            csal_change_insert(&changes, key, value));
+  ASSERT_EQ(0, csal_change_insert(&existing_values, key, value));
+  ASSERT_EQ(0, csal_rollback(&existing_values, &changes, &outer));
+  ASSERT_EQ(0u, existing_values.length);
+  ASSERT_EQ(1u, changes.length);
+  ASSERT_EQ(0, csal_change_fetch(&changes, key, fetched));
+  memset(value, 0x22, 32);
+  ASSERT_EQ(0, memcmp(fetched, value, 32));
+  /* Savepoints created after outer are dropped */
+  ASSERT_EQ(CSAL_ERROR_INVALID_SAVEPOINT,
+            csal_rollback(&existing_values, &changes, &inner));
+
+  memset(value, 0x55, 32);
+  ASSERT_EQ(0, csal_change_insert(&changes, key, value));
+  ASSERT_EQ(0, csal_release(&existing_values, &changes, &outer));
+  ASSERT_EQ(0u, changes.savepoint);
+  ASSERT_EQ(0, csal_change_fetch(&changes, key, fetched));
+  ASSERT_EQ(0, memcmp(fetched, value, 32));
+}
+
+UTEST(gas, metering) {
+  uint8_t key[32];
+  uint8_t value[32];
+  csal_entry_t existing_entries[4];
+  csal_entry_t change_entries[4];
+  csal_change_t existing_values;
+  csal_change_t changes;
+  csal_savepoint_t savepoint;
+  csal_change_init(&existing_values, existing_entries, 4);
+  csal_change_init(&changes, change_entries, 4);
+  memset(key, 0x11, 32);
+  memset(value, 0x22, 32);
+
+  test_cycles = 1000;
+  csal_gas_start();
+  ASSERT_EQ(UINT64_MAX, csal_gas_remaining());
+  csal_gas_set_limit(10000);
+  test_cycles += 50;
+  ASSERT_EQ(50u, csal_gas_consumed());
+  ASSERT_EQ(10000u - 50u, csal_gas_remaining());
+
+  /* Cycles spent inside accesses are replaced by a fixed charge */
+  _csal_gas_enter_access();
+  test_cycles += 300;
+  ASSERT_EQ(0, csal_change_insert(&existing_values, key, value));
+  test_cycles += 200;
+  ASSERT_EQ(0, csal_change_insert(&changes, key, value));
+  _csal_gas_leave_access();
+  ASSERT_EQ(50u + CSAL_STATE_ACCESS_CYCLES, csal_gas_consumed());
+  ASSERT_EQ(10000u - 50u - CSAL_STATE_ACCESS_CYCLES, csal_gas_remaining());
+
+  /* Savepoints are charged the same way as inserts and fetches */
+  ASSERT_EQ(0, csal_savepoint(&existing_values, &changes, &savepoint));
+  ASSERT_EQ(0, csal_change_fetch(&changes, key, value));
+  ASSERT_EQ(0, csal_release(&existing_values, &changes, &savepoint));
+  ASSERT_EQ(50u + 4 * CSAL_STATE_ACCESS_CYCLES, csal_gas_consumed());
+
+  /* Remaining gas only depends on consumed gas and the limit */
+  test_cycles += 10000;
+  ASSERT_EQ(0u, csal_gas_remaining());
+  csal_gas_start();
+  ASSERT_EQ(UINT64_MAX, csal_gas_remaining());
+}
+
+/* Same calls as test_run_nested_calls in Rust tests */
+UTEST(code, nested_calls) {
+  uint8_t code[65];
+  uint8_t failing_code[65 * 2];
+  uint8_t program[65 * 2];
+  uint8_t key[32];
+  uint8_t value[32];
+  csal_entry_t existing_entries[32];
+  csal_entry_t change_entries[32];
+  csal_change_t existing_values;
+  csal_change_t changes;
+  csal_change_init(&existing_values, existing_entries, 32);
+  csal_change_init(&changes, change_entries, 32);
+  uint32_t code_length = push_op(code, 0, 'W', 5, 6);
+  uint32_t failing_length = push_op(failing_code, 0, 'W', 7, 7);
+  failing_length = push_op(failing_code, failing_length, 'R', 5, 9);
+  ASSERT_EQ(0, _csal_deploy_code(code, code_length, &existing_values,
+                                 &changes));
+  ASSERT_EQ(0, _csal_deploy_code(failing_code, failing_length,
+                                 &existing_values, &changes));
+
+  uint32_t length = push_op(program, 0, 'C', 0, 0);
+  csal_code_hash(code, code_length, &program[1]);
+  ASSERT_EQ(0, execute_vm(program, length, &existing_values, &changes));
+  memset(key, 5, 32);
+  ASSERT_EQ(0, csal_change_fetch(&changes, key, value));
+  ASSERT_EQ(6, (int)value[0]);
+
+  /* A failing callee halts the caller, after its writes are rolled back */
+  csal_code_hash(failing_code, failing_length, &program[1]);
+  length = push_op(program, length, 'W', 9, 9);
+  ASSERT_EQ(-101, execute_vm(program, length, &existing_values, &changes));
+  memset(key, 7, 32);
+  ASSERT_EQ(CSAL_ERROR_NOT_FOUND, csal_change_fetch(&changes, key, value));
+  ASSERT_EQ(CSAL_ERROR_NOT_FOUND,
+            csal_change_fetch(&existing_values, key, value));
+  memset(key, 9, 32);
+  ASSERT_EQ(CSAL_ERROR_NOT_FOUND, csal_change_fetch(&changes, key, value));
+
+  /* Missing code is reported as well */
+  memset(&program[1], 0, 32);
+  ASSERT_EQ(CSAL_ERROR_CODE_NOT_FOUND,
+            execute_vm(program, 65, &existing_values, &changes));
+}
+
+/*
+ * Same savepoint sequences as test_savepoints in Rust tests, which run them
+ * through the generator.
+ */
+UTEST(change, savepoint_sequences) {
+  uint8_t program[65 * 80];
+  uint8_t key[32];
+  uint8_t value[32];
+  csal_entry_t existing_entries[16];
+  csal_entry_t change_entries[16];
+  csal_change_t existing_values;
+  csal_change_t changes;
+  memset(key, 1, 32);
+  memset(value, 0, 32);
+
+  /* Savepoints created after the one rolled back to are dropped for good */
+  uint32_t length = 0;
+  length = push_op(program, length, 'S', 0, 0);
+  length = push_op(program, length, 'W', 1, 1);
+  length = push_op(program, length, 'S', 1, 0);
+  length = push_op(program, length, 'B', 0, 0);
+  length = push_op(program, length, 'W', 1, 2);
+  length = push_op(program, length, 'B', 1, 0);
+  csal_change_init(&existing_values, existing_entries, 16);
+  csal_change_init(&changes, change_entries, 16);
+  ASSERT_EQ(CSAL_ERROR_INVALID_SAVEPOINT,
+            execute_vm(program, length, &existing_values, &changes));
+  program[length - 65] = 'K';
+  csal_change_init(&existing_values, existing_entries, 16);
+  csal_change_init(&changes, change_entries, 16);
+  ASSERT_EQ(CSAL_ERROR_INVALID_SAVEPOINT,
+            execute_vm(program, length, &existing_values, &changes));
+
+  length = 0;
+  length = push_op(program, length, 'S', 0, 0);
+  length = push_op(program, length, 'W', 1, 1);
+  length = push_op(program, length, 'S', 1, 0);
+  length = push_op(program, length, 'W', 1, 2);
+  length = push_op(program, length, 'B', 1, 0);
+  length = push_op(program, length, 'R', 1, 1);
+  length = push_op(program, length, 'B', 0, 0);
+  length = push_op(program, length, 'R', 1, 0);
+  length = push_op(program, length, 'B', 0, 0);
+  length = push_op(program, length, 'S', 2, 0);
+  length = push_op(program, length, 'W', 1, 3);
+  length = push_op(program, length, 'K', 2, 0);
+  length = push_op(program, length, 'R', 1, 3);
+  length = push_op(program, length, 'K', 0, 0);
+  length = push_op(program, length, 'R', 1, 3);
+  csal_change_init(&existing_values, existing_entries, 16);
+  csal_change_init(&changes, change_entries, 16);
+  /* Read proofs provide values of keys absent in the tree as zeros */
+  ASSERT_EQ(0, csal_change_insert(&existing_values, key, value));
+  ASSERT_EQ(0, execute_vm(program, length, &existing_values, &changes));
+  csal_change_organize(&changes);
+  ASSERT_EQ(1u, changes.length);
+  memset(value, 3, 32);
+  ASSERT_EQ(0, memcmp(changes.entries[0].value, value, 32));
+
+  length = 0;
+  for (int i = 0; i <= CSAL_MAX_SAVEPOINTS; i++) {
+    length = push_op(program, length, 'S', 0, 0);
+  }
+  csal_change_init(&existing_values, existing_entries, 16);
+  csal_change_init(&changes, change_entries, 16);
+  ASSERT_EQ(CSAL_ERROR_INSUFFICIENT_CAPACITY,
+            execute_vm(program, length, &existing_values, &changes));
+}
+
 UTEST_MAIN();
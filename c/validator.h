@@ -35,9 +35,12 @@
 #include <stdlib.h>
 #include <string.h>
 
+#include "gas.h"
+
 #define CSAL_ERROR_INSUFFICIENT_CAPACITY -20
 #define CSAL_ERROR_NOT_FOUND -21
 #define CSAL_LAST_COMMON_ERROR CSAL_ERROR_NOT_FOUND
+#define CSAL_ERROR_INVALID_SAVEPOINT -46
 
 #define CSAL_KEY_BYTES 32
 #define CSAL_VALUE_BYTES 32
@@ -52,26 +55,80 @@ typedef struct {
   csal_entry_t *entries;
   uint32_t length;
   uint32_t capacity;
+  /*
+   * Length when the innermost savepoint was created. Entries before it are
+   * never overwritten in place, so rolling back only needs truncating.
+   */
+  uint32_t savepoint;
 } csal_change_t;
 
+/*
+ * Savepoints are identified by the order they are created in, same as in
+ * generator.h, so a savepoint is valid on both sides exactly when it is in
+ * the stack of active savepoints.
+ */
+typedef struct {
+  uint64_t id;
+} csal_savepoint_t;
+
+/* Maximum depth of nested savepoints, same as MAX_SAVEPOINTS in the host */
+#ifndef CSAL_MAX_SAVEPOINTS
+#define CSAL_MAX_SAVEPOINTS 64
+#endif
+
 void csal_change_init(csal_change_t *state, csal_entry_t *buffer,
                       uint32_t capacity);
 int csal_change_insert(csal_change_t *state, const uint8_t key[CSAL_KEY_BYTES],
                        const uint8_t value[CSAL_VALUE_BYTES]);
 int csal_change_fetch(csal_change_t *state, const uint8_t key[CSAL_KEY_BYTES],
                       uint8_t value[CSAL_VALUE_BYTES]);
+/* Deleting a key is recorded as writing a zero value to it */
+int csal_change_delete(csal_change_t *state, const uint8_t key[CSAL_KEY_BYTES]);
 void csal_change_organize(csal_change_t *state);
+/*
+ * Savepoints mark a point in execution that writes to +existing_values+ and
+ * +changes+ can be reverted to. csal_rollback discards all writes since
+ * +savepoint+ was created, which stays valid afterwards. csal_release keeps
+ * the writes, and merges them into the enclosing savepoint if any. Savepoints
+ * nest, rolling back or releasing one also drops all savepoints created after
+ * it, using a dropped savepoint fails with CSAL_ERROR_INVALID_SAVEPOINT.
+ * Creating more than CSAL_MAX_SAVEPOINTS nested savepoints fails with
+ * CSAL_ERROR_INSUFFICIENT_CAPACITY.
+ *
+ * Savepoints are tracked for the single pair of states a program runs on,
+ * initializing a state via csal_change_init drops all of them.
+ */
+int csal_savepoint(csal_change_t *existing_values, csal_change_t *changes,
+                   csal_savepoint_t *savepoint);
+int csal_rollback(csal_change_t *existing_values, csal_change_t *changes,
+                  const csal_savepoint_t *savepoint);
+int csal_release(csal_change_t *existing_values, csal_change_t *changes,
+                 const csal_savepoint_t *savepoint);
 
 #ifndef CSAL_NO_IMPLEMENTATION
+typedef struct {
+  uint64_t id;
+  uint32_t existing_values_length;
+  uint32_t changes_length;
+} _csal_savepoint_entry_t;
+
+static _csal_savepoint_entry_t _csal_savepoints[CSAL_MAX_SAVEPOINTS];
+static uint32_t _csal_savepoints_length = 0;
+static uint64_t _csal_next_savepoint_id = 0;
+
 void csal_change_init(csal_change_t *state, csal_entry_t *buffer,
                       uint32_t capacity) {
   state->entries = buffer;
   state->length = 0;
   state->capacity = capacity;
+  state->savepoint = 0;
+  _csal_savepoints_length = 0;
+  _csal_next_savepoint_id = 0;
 }
 
-int csal_change_insert(csal_change_t *state, const uint8_t key[CSAL_KEY_BYTES],
-                       const uint8_t value[CSAL_VALUE_BYTES]) {
+int _csal_change_insert(csal_change_t *state,
+                        const uint8_t key[CSAL_KEY_BYTES],
+                        const uint8_t value[CSAL_VALUE_BYTES]) {
   if (state->length < state->capacity) {
     /* Shortcut, append at last */
     memcpy(state->entries[state->length].key, key, CSAL_KEY_BYTES);
@@ -79,14 +136,14 @@ int csal_change_insert(csal_change_t *state, const uint8_t key[CSAL_KEY_BYTES],
     state->length++;
     return 0;
   }
-  /* Find the last matching key, and overwrites it */
+  /* Find the last matching key since savepoint, and overwrites it */
   int32_t i = state->length - 1;
-  for (; i >= 0; i--) {
+  for (; i >= (int32_t)state->savepoint; i--) {
     if (memcmp(key, state->entries[i].key, CSAL_KEY_BYTES) == 0) {
       break;
     }
   }
-  if (i < 0) {
+  if (i < (int32_t)state->savepoint) {
     /* No matching key found, we are running out of capacity */
     return CSAL_ERROR_INSUFFICIENT_CAPACITY;
   }
@@ -94,8 +151,8 @@ int csal_change_insert(csal_change_t *state, const uint8_t key[CSAL_KEY_BYTES],
   return 0;
 }
 
-int csal_change_fetch(csal_change_t *state, const uint8_t key[CSAL_KEY_BYTES],
-                      uint8_t value[CSAL_VALUE_BYTES]) {
+int _csal_change_fetch(csal_change_t *state, const uint8_t key[CSAL_KEY_BYTES],
+                       uint8_t value[CSAL_VALUE_BYTES]) {
   int32_t i = state->length - 1;
   for (; i >= 0; i--) {
     if (memcmp(key, state->entries[i].key, CSAL_KEY_BYTES) == 0) {
@@ -106,6 +163,30 @@ int csal_change_fetch(csal_change_t *state, const uint8_t key[CSAL_KEY_BYTES],
   return CSAL_ERROR_NOT_FOUND;
 }
 
+/* Cycles spent on state accesses are metered separately, see gas.h */
+int csal_change_insert(csal_change_t *state, const uint8_t key[CSAL_KEY_BYTES],
+                       const uint8_t value[CSAL_VALUE_BYTES]) {
+  _csal_gas_enter_access();
+  int ret = _csal_change_insert(state, key, value);
+  _csal_gas_leave_access();
+  return ret;
+}
+
+int csal_change_fetch(csal_change_t *state, const uint8_t key[CSAL_KEY_BYTES],
+                      uint8_t value[CSAL_VALUE_BYTES]) {
+  _csal_gas_enter_access();
+  int ret = _csal_change_fetch(state, key, value);
+  _csal_gas_leave_access();
+  return ret;
+}
+
+int csal_change_delete(csal_change_t *state,
+                       const uint8_t key[CSAL_KEY_BYTES]) {
+  uint8_t zero_value[CSAL_VALUE_BYTES];
+  memset(zero_value, 0, CSAL_VALUE_BYTES);
+  return csal_change_insert(state, key, zero_value);
+}
+
 int _csal_entry_cmp(const void *a, const void *b) {
   const csal_entry_t *ea = (const csal_entry_t *)a;
   const csal_entry_t *eb = (const csal_entry_t *)b;
@@ -143,14 +224,128 @@ void csal_change_organize(csal_change_t *state) {
   }
   state->length = sorted;
 }
+
+int _csal_savepoint(csal_change_t *existing_values, csal_change_t *changes,
+                    csal_savepoint_t *savepoint) {
+  if (_csal_savepoints_length >= CSAL_MAX_SAVEPOINTS) {
+    return CSAL_ERROR_INSUFFICIENT_CAPACITY;
+  }
+  _csal_savepoint_entry_t *entry = &_csal_savepoints[_csal_savepoints_length++];
+  entry->id = _csal_next_savepoint_id++;
+  entry->existing_values_length = existing_values->length;
+  entry->changes_length = changes->length;
+  existing_values->savepoint = existing_values->length;
+  changes->savepoint = changes->length;
+  savepoint->id = entry->id;
+  return 0;
+}
+
+/* Returns the depth of an active savepoint, or -1 if it has been dropped */
+int32_t _csal_savepoint_depth(const csal_savepoint_t *savepoint) {
+  for (int32_t i = _csal_savepoints_length - 1; i >= 0; i--) {
+    if (_csal_savepoints[i].id == savepoint->id) {
+      return i;
+    }
+  }
+  return -1;
+}
+
+int _csal_rollback(csal_change_t *existing_values, csal_change_t *changes,
+                   const csal_savepoint_t *savepoint) {
+  int32_t depth = _csal_savepoint_depth(savepoint);
+  if (depth < 0) {
+    return CSAL_ERROR_INVALID_SAVEPOINT;
+  }
+  const _csal_savepoint_entry_t *entry = &_csal_savepoints[depth];
+  existing_values->length = entry->existing_values_length;
+  existing_values->savepoint = entry->existing_values_length;
+  changes->length = entry->changes_length;
+  changes->savepoint = entry->changes_length;
+  _csal_savepoints_length = depth + 1;
+  return 0;
+}
+
+int _csal_release(csal_change_t *existing_values, csal_change_t *changes,
+                  const csal_savepoint_t *savepoint) {
+  int32_t depth = _csal_savepoint_depth(savepoint);
+  if (depth < 0) {
+    return CSAL_ERROR_INVALID_SAVEPOINT;
+  }
+  if (depth > 0) {
+    existing_values->savepoint =
+        _csal_savepoints[depth - 1].existing_values_length;
+    changes->savepoint = _csal_savepoints[depth - 1].changes_length;
+  } else {
+    existing_values->savepoint = 0;
+    changes->savepoint = 0;
+  }
+  _csal_savepoints_length = depth;
+  return 0;
+}
+
+/* Savepoints are state accesses as well, see gas.h */
+int csal_savepoint(csal_change_t *existing_values, csal_change_t *changes,
+                   csal_savepoint_t *savepoint) {
+  _csal_gas_enter_access();
+  int ret = _csal_savepoint(existing_values, changes, savepoint);
+  _csal_gas_leave_access();
+  return ret;
+}
+
+int csal_rollback(csal_change_t *existing_values, csal_change_t *changes,
+                  const csal_savepoint_t *savepoint) {
+  _csal_gas_enter_access();
+  int ret = _csal_rollback(existing_values, changes, savepoint);
+  _csal_gas_leave_access();
+  return ret;
+}
+
+int csal_release(csal_change_t *existing_values, csal_change_t *changes,
+                 const csal_savepoint_t *savepoint) {
+  _csal_gas_enter_access();
+  int ret = _csal_release(existing_values, changes, savepoint);
+  _csal_gas_leave_access();
+  return ret;
+}
 #endif /* CSAL_NO_IMPLEMENTATION */
 
+#include "chunks.h"
+
 #define CSAL_VALIDATOR_TYPE_SMT 1
 #define CSAL_VALIDATOR_TYPE_FULLSTORAGE 2
 
 #if (CSAL_VALIDATOR_TYPE == CSAL_VALIDATOR_TYPE_SMT)
+/*
+ * Hash function of the SMT, which must match the hasher of the account tree
+ * off chain. The default is blake2b personalized with "ckb-default-hash",
+ * same as CkbBlake2bHasher. Define CSAL_SMT_HASH_PERSONAL to a 16-byte string
+ * to use a different personalization, or define CSAL_SMT_CUSTOM_HASH and
+ * provide csal_smt_hash_state_t, csal_smt_hash_init, csal_smt_hash_update and
+ * csal_smt_hash_final to use a different hash function altogether.
+ */
+#ifndef CSAL_SMT_CUSTOM_HASH
 #include <blake2b.h>
 
+typedef blake2b_state csal_smt_hash_state_t;
+
+#ifdef CSAL_SMT_HASH_PERSONAL
+static inline void csal_smt_hash_init(csal_smt_hash_state_t *state) {
+  blake2b_param param;
+  memset(&param, 0, sizeof(blake2b_param));
+  param.digest_length = 32;
+  param.fanout = 1;
+  param.depth = 1;
+  memcpy(param.personal, CSAL_SMT_HASH_PERSONAL, BLAKE2B_PERSONALBYTES);
+  blake2b_init_param(state, &param);
+}
+#else
+#define csal_smt_hash_init(state) blake2b_init((state), 32)
+#endif
+#define csal_smt_hash_update(state, data, length) \
+  blake2b_update((state), (data), (length))
+#define csal_smt_hash_final(state, output) blake2b_final((state), (output), 32)
+#endif /* CSAL_SMT_CUSTOM_HASH */
+
 #if (CSAL_KEY_BYTES != 32)
 #error "SMT solution only works with 256 bit keys!"
 #endif
@@ -220,11 +415,11 @@ void _csal_merge(const uint8_t lhs[32], const uint8_t rhs[32],
   } else if (_csal_zero_value(rhs)) {
     memcpy(output, lhs, 32);
   } else {
-    blake2b_state blake2b_ctx;
-    blake2b_init(&blake2b_ctx, 32);
-    blake2b_update(&blake2b_ctx, lhs, 32);
-    blake2b_update(&blake2b_ctx, rhs, 32);
-    blake2b_final(&blake2b_ctx, output, 32);
+    csal_smt_hash_state_t hash_ctx;
+    csal_smt_hash_init(&hash_ctx);
+    csal_smt_hash_update(&hash_ctx, lhs, 32);
+    csal_smt_hash_update(&hash_ctx, rhs, 32);
+    csal_smt_hash_final(&hash_ctx, output);
   }
 }
 
@@ -237,7 +432,7 @@ void _csal_merge(const uint8_t lhs[32], const uint8_t rhs[32],
 
 int csal_smt_update_root(uint8_t buffer[32], const csal_change_t *pairs,
                          const uint8_t *proof, uint32_t proof_length) {
-  blake2b_state blake2b_ctx;
+  csal_smt_hash_state_t hash_ctx;
   uint8_t stack_keys[_CSAL_SMT_STACK_SIZE][CSAL_KEY_BYTES];
   uint8_t stack_values[_CSAL_SMT_STACK_SIZE][32];
   uint32_t proof_index = 0;
@@ -258,12 +453,12 @@ int csal_smt_update_root(uint8_t buffer[32], const csal_change_t *pairs,
         if (_csal_zero_value(pairs->entries[leave_index].value)) {
           memset(stack_values[stack_top], 0, 32);
         } else {
-          blake2b_init(&blake2b_ctx, 32);
-          blake2b_update(&blake2b_ctx, pairs->entries[leave_index].key,
-                         CSAL_KEY_BYTES);
-          blake2b_update(&blake2b_ctx, pairs->entries[leave_index].value,
-                         CSAL_VALUE_BYTES);
-          blake2b_final(&blake2b_ctx, stack_values[stack_top], 32);
+          csal_smt_hash_init(&hash_ctx);
+          csal_smt_hash_update(&hash_ctx, pairs->entries[leave_index].key,
+                               CSAL_KEY_BYTES);
+          csal_smt_hash_update(&hash_ctx, pairs->entries[leave_index].value,
+                               CSAL_VALUE_BYTES);
+          csal_smt_hash_final(&hash_ctx, stack_values[stack_top]);
         }
         stack_top++;
         leave_index++;
@@ -370,8 +565,9 @@ int csal_smt_verify(const uint8_t hash[32], const csal_change_t *pairs,
  *
  * 1. The actual on-chain storage will be modeled as a key-value store.
  * 2. All the values that will be read when executing the VM are provided in
- * +existing_values+. If the program tries to read from a key that is not
- * expected in the VM, a return value of all zeros should be used.
+ * +existing_values+, keys that do not exist are provided with all zeros, and
+ * their absence is proven as well. If the program tries to read from a key
+ * that is not expected in the VM, a return value of all zeros should be used.
  * 3. The VM should record all writes from the VM in +changes+ using the exact
  * same order as each write happens. If you are operating on +changes+ using
  * the provided +csal_change_insert+, this will be automatically ensured. When
@@ -387,6 +583,8 @@ int csal_smt_verify(const uint8_t hash[32], const csal_change_t *pairs,
 extern int execute_vm(const uint8_t *source, uint32_t length,
                       csal_change_t *existing_values, csal_change_t *changes);
 
+#include "code.h"
+
 #define MAXIMUM_READS 1024
 #define MAXIMUM_WRITES 1024
 #define SCRIPT_SIZE 128
@@ -604,7 +802,8 @@ int main() {
   csal_entry_t write_entries[MAXIMUM_WRITES];
   csal_change_t write_changes;
   csal_change_init(&write_changes, write_entries, MAXIMUM_WRITES);
-  ret = execute_vm(source, source_length, &read_changes, &write_changes);
+  ret = csal_execute_program(source, source_length, &read_changes,
+                             &write_changes);
   if (ret != CKB_SUCCESS) {
     return ret;
   }
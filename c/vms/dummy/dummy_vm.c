@@ -1,8 +1,13 @@
 /*
- * This is a dummy VM that only accepts the following 2 operations:
+ * This is a dummy VM that only accepts the following 7 operations:
  *
  * R <32 byte key> <32 byte value>
  * W <32 byte key> <32 byte value>
+ * C <32 byte code hash> <32 byte padding>
+ * D <32 byte key> <32 byte padding>
+ * S <1 byte slot> <63 byte padding>
+ * B <1 byte slot> <63 byte padding>
+ * K <1 byte slot> <63 byte padding>
  *
  * Hence the source length will always be a multiple of 65.
  *
@@ -11,12 +16,27 @@
  * otherwise it continues with the next operation.
  * W operation writes the value to storage. Future R operations on the same
  * key should read the newly written value.
+ * C operation invokes stored code with the given hash via csal_call. When the
+ * callee fails, its writes are rolled back, and the program halts with the
+ * error returned.
+ * D operation deletes the key from storage, future R operations on the same
+ * key should read all zeros.
+ * S operation creates a savepoint and keeps it in one of
+ * DUMMY_VM_SAVEPOINT_SLOTS slots, B operation rolls back to the savepoint in
+ * the slot, while K operation releases it. Failures of these operations halt
+ * the program with the error returned.
+ *
+ * Each R, W and D operation is charged as a single state access, no matter
+ * how many states it touches, so gas matches between both sides.
  */
 #include <stddef.h>
 #include <stdint.h>
 #include <stdlib.h>
 #include <string.h>
 
+/* Lets tests deploy code and call it by hash, see code.h */
+#define CSAL_ENABLE_CODE_PROGRAMS
+
 #ifdef BUILD_GENERATOR
 #include "generator.h"
 #else
@@ -24,21 +44,27 @@
 #include "validator.h"
 #endif
 
+#define DUMMY_VM_SAVEPOINT_SLOTS 4
+
 int execute_vm(const uint8_t *source, uint32_t length,
                csal_change_t *existing_values, csal_change_t *changes) {
   size_t operation_length = 1 + CSAL_KEY_BYTES + CSAL_VALUE_BYTES;
   if (length % operation_length != 0) {
     return -100;
   }
+  csal_savepoint_t savepoints[DUMMY_VM_SAVEPOINT_SLOTS];
+  memset(savepoints, 0, sizeof(savepoints));
   for (uint32_t i = 0; i < length; i += operation_length) {
     uint8_t read_value[CSAL_VALUE_BYTES];
     int ret;
     switch (source[i]) {
       case 'R':
+        _csal_gas_enter_access();
         ret = csal_change_fetch(changes, &source[i + 1], read_value);
         if (ret != 0) {
           ret = csal_change_fetch(existing_values, &source[i + 1], read_value);
         }
+        _csal_gas_leave_access();
         if (ret != 0) {
           return ret;
         }
@@ -48,13 +74,58 @@ int execute_vm(const uint8_t *source, uint32_t length,
         }
         break;
       case 'W':
+        ret = 0;
+        _csal_gas_enter_access();
+#ifndef CSAL_GENERATOR
         ret = csal_change_insert(existing_values, &source[i + 1],
                                  &source[i + 1 + CSAL_KEY_BYTES]);
+#endif
+        if (ret == 0) {
+          ret = csal_change_insert(changes, &source[i + 1],
+                                   &source[i + 1 + CSAL_KEY_BYTES]);
+        }
+        _csal_gas_leave_access();
         if (ret != 0) {
           return ret;
         }
-        ret = csal_change_insert(changes, &source[i + 1],
-                                 &source[i + 1 + CSAL_KEY_BYTES]);
+        break;
+      case 'D':
+        ret = 0;
+        _csal_gas_enter_access();
+#ifndef CSAL_GENERATOR
+        /* Generators keep a single state, see generator.h */
+        ret = csal_change_delete(existing_values, &source[i + 1]);
+#endif
+        if (ret == 0) {
+          ret = csal_change_delete(changes, &source[i + 1]);
+        }
+        _csal_gas_leave_access();
+        if (ret != 0) {
+          return ret;
+        }
+        break;
+      case 'C':
+        ret = csal_call(&source[i + 1], existing_values, changes);
+        if (ret != 0) {
+          return ret;
+        }
+        break;
+      case 'S':
+      case 'B':
+      case 'K':
+        if (source[i + 1] >= DUMMY_VM_SAVEPOINT_SLOTS) {
+          return -103;
+        }
+        if (source[i] == 'S') {
+          ret = csal_savepoint(existing_values, changes,
+                               &savepoints[source[i + 1]]);
+        } else if (source[i] == 'B') {
+          ret = csal_rollback(existing_values, changes,
+                              &savepoints[source[i + 1]]);
+        } else {
+          ret = csal_release(existing_values, changes,
+                             &savepoints[source[i + 1]]);
+        }
         if (ret != 0) {
           return ret;
         }
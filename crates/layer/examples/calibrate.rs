@@ -0,0 +1,62 @@
+//! Produces a calibration table for `CycleCalibration::from_table` from
+//! validator cycles measured offline.
+//!
+//! Each input line describes a sample transaction generated by an account:
+//!
+//!     <witness size> <reads> <writes> <proof bytes> <cycles>
+//!
+//! The first four columns are the fields of the `CalibrationSample` returned
+//! by `RunProofResult::calibration_sample`, cycles are the ones consumed by
+//! the validator when running the transaction offline, such as via
+//! ckb-debugger. Use programs doing nothing besides their reads and writes,
+//! and vary the number of reads and writes across samples, so every entry of
+//! the table can be fitted. Empty lines and lines starting with `#` are
+//! ignored.
+//!
+//! Usage: cargo run --example calibrate [samples file] > calibration.txt
+use ckb_simple_account_layer::{CalibrationSample, CycleCalibration};
+use std::env;
+use std::error::Error as StdError;
+use std::fs::read_to_string;
+use std::io::{stdin, Read};
+
+fn parse_samples(input: &str) -> Result<Vec<CalibrationSample>, Box<dyn StdError>> {
+    let mut samples = Vec::new();
+    for line in input.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let values = line
+            .split_whitespace()
+            .map(|value| value.parse::<u64>())
+            .collect::<Result<Vec<_>, _>>()?;
+        if values.len() != 5 {
+            return Err(format!("Invalid sample line: {}", line).into());
+        }
+        samples.push(CalibrationSample {
+            witness_size: values[0] as usize,
+            reads: values[1] as usize,
+            writes: values[2] as usize,
+            proof_bytes: values[3] as usize,
+            cycles: values[4],
+        });
+    }
+    Ok(samples)
+}
+
+fn main() -> Result<(), Box<dyn StdError>> {
+    let input = match env::args().nth(1) {
+        Some(path) => read_to_string(path)?,
+        None => {
+            let mut input = String::new();
+            stdin().read_to_string(&mut input)?;
+            input
+        }
+    };
+    let samples = parse_samples(&input)?;
+    let calibration = CycleCalibration::fit(&samples)?;
+    println!("# fitted from {} samples", samples.len());
+    print!("{}", calibration.to_table());
+    Ok(())
+}
@@ -0,0 +1,278 @@
+use crate::{
+    ckb::CkbSimpleAccount,
+    run,
+    smt::{
+        collect_leaves, CkbBlake2bHasher, ClearStore, SnapshotStore, StoreChanges, StoreTransaction,
+    },
+    Config,
+};
+use bytes::Bytes;
+use ckb_types::{
+    core::TransactionView,
+    packed::{CellDep, OutPoint},
+};
+use replace_with::replace_with_or_abort_and_return;
+use sparse_merkle_tree::{
+    traits::{Hasher, Store},
+    SparseMerkleTree, H256,
+};
+use std::error::Error as StdError;
+use std::fmt;
+
+/// Cells of an account transaction as laid out by a `StateBackend`. The account
+/// cell is always the first output, followed by `extra_outputs`, while the last
+/// account cell, if any, is always the first input, followed by `extra_inputs`.
+#[derive(Debug, PartialEq, Clone, Eq, Default)]
+pub struct TransactionLayout {
+    /// Kept in the type part of WitnessArgs of the account cell
+    pub witness: Bytes,
+    /// Data of the account cell
+    pub output_data: Bytes,
+    /// Data of extra cells created with the account type script, such as data
+    /// cells in fullstorage mode. They use the lock of the account cell, with
+    /// occupied capacities only.
+    pub extra_outputs: Vec<Bytes>,
+    /// Extra cells consumed together with the last account cell
+    pub extra_inputs: Vec<OutPoint>,
+    /// All cell deps of the transaction, including the validator
+    pub cell_deps: Vec<CellDep>,
+}
+
+/// State and commitment scheme behind an account. The backend decides what
+/// goes into the witness of an account transaction, what the account cell data
+/// commits to and which other cells the transaction has, while
+/// `CkbSimpleAccount` takes care of the account cell, pending transactions and
+/// chain following.
+///
+/// Changes are always staged first, so a transaction can be verified on top
+/// of pending transactions without touching committed state.
+pub trait StateBackend {
+    /// State changes introduced by a single account transaction
+    type Changes: Clone + fmt::Debug;
+    /// Information needed to revert applied changes
+    type Undo: Clone + fmt::Debug;
+
+    /// Commitment to committed state, as kept in account cell data
+    fn commitment(&self) -> Bytes;
+
+    /// Runs `program` on top of committed state with `pending` changes applied
+    /// in order. Returns the layout of the transaction, including the witness
+    /// proving the execution and the account cell data committing to the
+    /// resulting state.
+    fn generate(
+        &self,
+        config: &Config,
+        pending: &[&Self::Changes],
+        program: &Bytes,
+    ) -> Result<TransactionLayout, Box<dyn StdError>>;
+
+    /// Verifies that `output_data` commits to the state obtained by executing
+    /// `witness` on top of committed state with `pending` changes applied.
+    /// `transaction` is the whole account transaction, for checking extra cells.
+    fn verify(
+        &self,
+        config: &Config,
+        pending: &[&Self::Changes],
+        witness: &Bytes,
+        output_data: &Bytes,
+        transaction: &TransactionView,
+    ) -> Result<Self::Changes, Box<dyn StdError>>;
+
+    /// Tells the account cell apart from extra cells carrying the account type
+    /// script, by its data.
+    fn is_account_cell(_output_data: &[u8]) -> bool {
+        true
+    }
+
+    /// Changes removing all state, used when the account cell is destroyed
+    fn destroy(&self) -> Self::Changes;
+
+    /// Applies changes returned by `verify` or `destroy`, pending changes
+    /// staged before them must be applied first.
+    fn apply(&mut self, changes: Self::Changes) -> Result<Self::Undo, Box<dyn StdError>>;
+
+    /// Reverts applied changes, changes applied after them must be reverted first.
+    fn revert(&mut self, undo: Self::Undo) -> Result<(), Box<dyn StdError>>;
+}
+
+/// Backends whose committed state can be copied into an independent instance,
+/// so reads can go on against the copy while the original is modified.
+pub trait SnapshotBackend: StateBackend + Sized {
+    fn snapshot(&self) -> Result<Self, Box<dyn StdError>>;
+}
+
+/// Account state kept in a sparse merkle tree, account cell data holds the
+/// 32-byte root hash. The hasher must match the one compiled into the
+/// validator, see `_csal_merge` in `c/validator.h`.
+pub type SmtState<S, H = CkbBlake2bHasher> = SparseMerkleTree<H, H256, S>;
+
+/// An account using `SmtState`
+pub type SmtAccount<S, H = CkbBlake2bHasher> = CkbSimpleAccount<SmtState<S, H>>;
+
+#[derive(Debug, Clone)]
+pub struct SmtChanges {
+    root_hash: H256,
+    store_changes: StoreChanges,
+    /// Values of all written keys before the changes
+    old_values: Vec<(H256, H256)>,
+    cleared: bool,
+}
+
+impl SmtChanges {
+    /// Root hash of the tree after the changes
+    pub fn root_hash(&self) -> &H256 {
+        &self.root_hash
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SmtUndo {
+    root_hash: H256,
+    /// Values of all written keys before the changes, or all leaves of the
+    /// tree when it is cleared
+    old_values: Vec<(H256, H256)>,
+}
+
+/// Builds a tree reflecting the state after all pending changes
+fn tip_tree<'a, H: Hasher + Default, S: Store<H256>>(
+    tree: &'a SmtState<S, H>,
+    pending: &[&SmtChanges],
+) -> SmtState<StoreTransaction<'a, S>, H> {
+    let mut root_hash = *tree.root();
+    let mut changes = StoreChanges::default();
+    for pending_changes in pending {
+        root_hash = pending_changes.root_hash;
+        if pending_changes.cleared {
+            changes = StoreChanges::default();
+        } else {
+            changes.merge(pending_changes.store_changes.clone());
+        }
+    }
+    SparseMerkleTree::new(
+        root_hash,
+        StoreTransaction::with_changes(tree.store(), changes),
+    )
+}
+
+impl<H: Hasher + Default, S: Store<H256> + ClearStore> StateBackend for SmtState<S, H> {
+    type Changes = SmtChanges;
+    type Undo = SmtUndo;
+
+    fn commitment(&self) -> Bytes {
+        Bytes::from(self.root().as_slice().to_vec())
+    }
+
+    fn generate(
+        &self,
+        config: &Config,
+        pending: &[&SmtChanges],
+        program: &Bytes,
+    ) -> Result<TransactionLayout, Box<dyn StdError>> {
+        let tree = tip_tree(self, pending);
+        let result = run(config, &tree, program)?;
+        let proof = result.generate_proof(&tree)?;
+        let root_hash = result.committed_root_hash(&tree)?;
+        Ok(TransactionLayout {
+            witness: proof.serialize(program)?,
+            output_data: Bytes::from(root_hash.as_slice().to_vec()),
+            cell_deps: vec![config.validator_cell_dep()],
+            ..Default::default()
+        })
+    }
+
+    fn verify(
+        &self,
+        config: &Config,
+        pending: &[&SmtChanges],
+        witness: &Bytes,
+        output_data: &Bytes,
+        _transaction: &TransactionView,
+    ) -> Result<SmtChanges, Box<dyn StdError>> {
+        let tree = tip_tree(self, pending);
+        let result = run(config, &tree, witness)?;
+        let mut old_values = Vec::with_capacity(result.write_values.len());
+        for key in result.write_values.keys() {
+            old_values.push((*key, tree.get(key)?));
+        }
+        let mut new_tree: SmtState<StoreTransaction<StoreTransaction<S>>, H> =
+            SparseMerkleTree::new(*tree.root(), StoreTransaction::new(tree.store()));
+        result.commit(&mut new_tree)?;
+        let new_root_hash = *new_tree.root();
+        if output_data.len() != 32 || output_data != new_root_hash.as_slice() {
+            return Err("Invalid new root hash!".into());
+        }
+        Ok(SmtChanges {
+            root_hash: new_root_hash,
+            store_changes: new_tree.take_store().into_changes(),
+            old_values,
+            cleared: false,
+        })
+    }
+
+    fn destroy(&self) -> SmtChanges {
+        SmtChanges {
+            root_hash: H256::zero(),
+            store_changes: StoreChanges::default(),
+            old_values: Vec::new(),
+            cleared: true,
+        }
+    }
+
+    fn apply(&mut self, changes: SmtChanges) -> Result<SmtUndo, Box<dyn StdError>> {
+        let SmtChanges {
+            root_hash,
+            store_changes,
+            old_values,
+            cleared,
+        } = changes;
+        if cleared {
+            let undo = SmtUndo {
+                root_hash: *self.root(),
+                old_values: collect_leaves(self.store(), self.root())?,
+            };
+            replace_with_or_abort_and_return(self, |tree| {
+                let root_hash = *tree.root();
+                let mut store = tree.take_store();
+                match store.clear_store() {
+                    Ok(()) => (Ok(()), SparseMerkleTree::new(H256::zero(), store)),
+                    Err(e) => (Err(e), SparseMerkleTree::new(root_hash, store)),
+                }
+            })?;
+            return Ok(undo);
+        }
+        let undo = SmtUndo {
+            root_hash: *self.root(),
+            old_values,
+        };
+        replace_with_or_abort_and_return(self, |tree| {
+            let old_root_hash = *tree.root();
+            let mut store = tree.take_store();
+            match store_changes.commit_into(&mut store) {
+                Ok(()) => (Ok(()), SparseMerkleTree::new(root_hash, store)),
+                Err(e) => (Err(e), SparseMerkleTree::new(old_root_hash, store)),
+            }
+        })?;
+        Ok(undo)
+    }
+
+    fn revert(&mut self, undo: SmtUndo) -> Result<(), Box<dyn StdError>> {
+        for (key, value) in undo.old_values {
+            self.update(key, value)?;
+        }
+        if self.root() != &undo.root_hash {
+            return Err("Reverted root hash does not match!".into());
+        }
+        Ok(())
+    }
+}
+
+impl<H: Hasher + Default, S: Store<H256> + ClearStore + SnapshotStore> SnapshotBackend
+    for SmtState<S, H>
+{
+    fn snapshot(&self) -> Result<Self, Box<dyn StdError>> {
+        Ok(SparseMerkleTree::new(
+            *self.root(),
+            self.store().snapshot_store()?,
+        ))
+    }
+}
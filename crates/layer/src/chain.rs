@@ -0,0 +1,227 @@
+use crate::{
+    backend::StateBackend,
+    ckb::{CkbSimpleAccount, UndoRecord},
+};
+use blake2b_rs::Blake2bBuilder;
+use ckb_types::{
+    packed::{Byte32, Transaction},
+    prelude::*,
+};
+use std::collections::VecDeque;
+use std::error::Error as StdError;
+
+#[derive(Debug, PartialEq, Clone, Eq)]
+pub struct Block {
+    pub number: u64,
+    pub hash: Byte32,
+    pub parent_hash: Byte32,
+    pub transactions: Vec<Transaction>,
+}
+
+/// A source of blocks on the canonical chain, such as a CKB node or an indexer
+pub trait ChainSource {
+    /// Returns the block at `number` on current canonical chain, or `None` when
+    /// `number` is beyond current tip.
+    fn block(&self, number: u64) -> Result<Option<Block>, Box<dyn StdError>>;
+}
+
+#[derive(Debug, PartialEq, Clone, Eq)]
+pub enum FollowEvent {
+    /// The block is processed, with the number of account transactions in it
+    Applied(u64, Byte32, usize),
+    /// The block is no longer on the canonical chain, and is rolled back
+    RolledBack(u64, Byte32),
+}
+
+struct FollowedBlock<U> {
+    number: u64,
+    hash: Byte32,
+    undo_records: Vec<UndoRecord<U>>,
+}
+
+/// Follows a chain source block by block, advancing the account with the
+/// transactions touching it. Chain reorganizations are detected when a new
+/// block's parent hash doesn't match current tip, in which case blocks are rolled
+/// back one by one till the fork point. Only the latest `max_reorg_depth` blocks
+/// are kept for rolling back.
+pub struct ChainFollower<C: ChainSource, B: StateBackend> {
+    source: C,
+    account: CkbSimpleAccount<B>,
+    blocks: VecDeque<FollowedBlock<B::Undo>>,
+    next_number: u64,
+    max_reorg_depth: usize,
+    pruned: bool,
+}
+
+impl<C: ChainSource, B: StateBackend> ChainFollower<C, B> {
+    /// Creates a follower processing blocks from `start_number`, `account` must
+    /// reflect the state right before that block.
+    pub fn new(source: C, account: CkbSimpleAccount<B>, start_number: u64) -> Self {
+        ChainFollower {
+            source,
+            account,
+            blocks: VecDeque::new(),
+            next_number: start_number,
+            max_reorg_depth: 100,
+            pruned: false,
+        }
+    }
+
+    /// Sets the number of blocks kept for rolling back, which must be at least 1
+    pub fn max_reorg_depth(mut self, max_reorg_depth: usize) -> Result<Self, Box<dyn StdError>> {
+        if max_reorg_depth == 0 {
+            return Err("Reorganization depth must be at least 1!".into());
+        }
+        self.max_reorg_depth = max_reorg_depth;
+        Ok(self)
+    }
+
+    pub fn account(&self) -> &CkbSimpleAccount<B> {
+        &self.account
+    }
+
+    pub fn source(&self) -> &C {
+        &self.source
+    }
+
+    pub fn source_mut(&mut self) -> &mut C {
+        &mut self.source
+    }
+
+    /// Number and hash of the last processed block
+    pub fn tip(&self) -> Option<(u64, &Byte32)> {
+        self.blocks.back().map(|b| (b.number, &b.hash))
+    }
+
+    pub fn into_account(self) -> CkbSimpleAccount<B> {
+        self.account
+    }
+
+    /// Processes a single block, or rolls back a single block in case of chain
+    /// reorganization. `None` is returned when the follower is already at the tip.
+    ///
+    /// A block is processed as a whole: when any transaction in it fails, the
+    /// account is left as it was before the block. A block is only dropped from
+    /// kept history once all its transactions are rolled back.
+    pub fn step(&mut self) -> Result<Option<FollowEvent>, Box<dyn StdError>> {
+        let block = match self.source.block(self.next_number)? {
+            Some(block) => block,
+            None => return Ok(None),
+        };
+        if let Some(tip) = self.blocks.back() {
+            if tip.hash != block.parent_hash {
+                if self.blocks.len() == 1 && self.pruned {
+                    return Err("Chain reorganization is deeper than kept history!".into());
+                }
+                let tip = self.blocks.back_mut().unwrap();
+                while let Some(record) = tip.undo_records.last() {
+                    self.account.undo(record.clone())?;
+                    tip.undo_records.pop();
+                }
+                let tip = self.blocks.pop_back().unwrap();
+                self.next_number = tip.number;
+                return Ok(Some(FollowEvent::RolledBack(tip.number, tip.hash)));
+            }
+        }
+        let advances = self.account.prepare_block(&block.transactions)?;
+        let mut undo_records = Vec::with_capacity(advances.len());
+        for advance in advances {
+            match self.account.finish_advance(advance) {
+                Ok(record) => undo_records.push(record),
+                Err(e) => {
+                    for record in undo_records.into_iter().rev() {
+                        self.account.undo(record)?;
+                    }
+                    return Err(e);
+                }
+            }
+        }
+        let applied = undo_records.len();
+        self.blocks.push_back(FollowedBlock {
+            number: block.number,
+            hash: block.hash.clone(),
+            undo_records,
+        });
+        while self.blocks.len() > self.max_reorg_depth {
+            self.blocks.pop_front();
+            self.pruned = true;
+        }
+        self.next_number = block.number + 1;
+        Ok(Some(FollowEvent::Applied(
+            block.number,
+            block.hash,
+            applied,
+        )))
+    }
+
+    /// Processes blocks till current tip of the chain source, returning all events.
+    /// An error is returned if a reorganization is deeper than kept history.
+    pub fn sync(&mut self) -> Result<Vec<FollowEvent>, Box<dyn StdError>> {
+        let mut events = Vec::new();
+        while let Some(event) = self.step()? {
+            events.push(event);
+        }
+        Ok(events)
+    }
+}
+
+/// An in-memory chain, useful for testing account following logic offline.
+/// Block hashes are derived from block content, together with a nonce so blocks
+/// with the same content on different forks have different hashes.
+#[derive(Debug, Clone, Default)]
+pub struct MockChain {
+    blocks: Vec<Block>,
+    nonce: u64,
+}
+
+impl MockChain {
+    pub fn new() -> Self {
+        MockChain::default()
+    }
+
+    pub fn tip(&self) -> Option<&Block> {
+        self.blocks.last()
+    }
+
+    /// Appends a new block on top of current tip, returning its hash
+    pub fn push_block(&mut self, transactions: Vec<Transaction>) -> Byte32 {
+        let number = self.blocks.len() as u64;
+        let parent_hash = self
+            .blocks
+            .last()
+            .map(|b| b.hash.clone())
+            .unwrap_or_default();
+        let mut blake2b = Blake2bBuilder::new(32)
+            .personal(b"ckb-default-hash")
+            .build();
+        blake2b.update(parent_hash.as_slice());
+        blake2b.update(&number.to_le_bytes());
+        blake2b.update(&self.nonce.to_le_bytes());
+        for transaction in &transactions {
+            blake2b.update(transaction.clone().into_view().hash().as_slice());
+        }
+        let mut hash = [0u8; 32];
+        blake2b.finalize(&mut hash);
+        self.nonce += 1;
+        let hash: Byte32 = hash.pack();
+        self.blocks.push(Block {
+            number,
+            hash: hash.clone(),
+            parent_hash,
+            transactions,
+        });
+        hash
+    }
+
+    /// Removes all blocks after `number`, new blocks pushed afterwards form a fork
+    /// starting from block `number`.
+    pub fn rollback_to(&mut self, number: u64) {
+        self.blocks.truncate(number as usize + 1);
+    }
+}
+
+impl ChainSource for MockChain {
+    fn block(&self, number: u64) -> Result<Option<Block>, Box<dyn StdError>> {
+        Ok(self.blocks.get(number as usize).cloned())
+    }
+}
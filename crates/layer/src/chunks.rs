@@ -0,0 +1,75 @@
+use crate::{
+    backend::SmtState,
+    ckb::CkbSimpleAccount,
+    keys::{chunk_key, u64_to_value, value_to_u64},
+    smt::ClearStore,
+};
+use bytes::Bytes;
+use sparse_merkle_tree::{
+    traits::{Hasher, Store},
+    SparseMerkleTree, H256,
+};
+use std::error::Error as StdError;
+
+/// Longest value kept via `store_bytes`, which is `CSAL_MAX_VALUE_LENGTH` in
+/// `c/chunks.h`. Longer lengths found in state are rejected as well.
+pub const MAX_VALUE_LENGTH: u64 = 1 << 20;
+
+/// Number of 32-byte chunks needed to hold `length` bytes
+fn chunk_count(length: u64) -> Result<u64, Box<dyn StdError>> {
+    if length > MAX_VALUE_LENGTH {
+        return Err("Value is too long!".into());
+    }
+    Ok((length + 31) >> 5)
+}
+
+fn stored_length<H: Hasher + Default, S: Store<H256>>(
+    tree: &SparseMerkleTree<H, H256, S>,
+    key: &H256,
+) -> Result<u64, Box<dyn StdError>> {
+    value_to_u64(&tree.get(key)?).ok_or_else(|| "Invalid length slot!".into())
+}
+
+/// Stores a variable length value at `key` using the same layout as
+/// `c/chunks.h`: `key` holds the length, while the content is split into
+/// 32-byte chunks kept at `chunk_key(key, i)`. Chunks left by a longer previous
+/// value are cleared. Values longer than `MAX_VALUE_LENGTH` are rejected.
+pub fn store_bytes<H: Hasher + Default, S: Store<H256>>(
+    tree: &mut SparseMerkleTree<H, H256, S>,
+    key: &H256,
+    data: &[u8],
+) -> Result<(), Box<dyn StdError>> {
+    let chunks = chunk_count(data.len() as u64)?;
+    let old_chunks = chunk_count(stored_length(tree, key)?)?;
+    for (i, chunk) in data.chunks(32).enumerate() {
+        let mut value = [0u8; 32];
+        value[0..chunk.len()].copy_from_slice(chunk);
+        tree.update(chunk_key(key, i as u64), value.into())?;
+    }
+    for i in chunks..old_chunks {
+        tree.update(chunk_key(key, i), H256::zero())?;
+    }
+    tree.update(*key, u64_to_value(data.len() as u64))?;
+    Ok(())
+}
+
+/// Reassembles a variable length value stored at `key`
+pub fn load_bytes<H: Hasher + Default, S: Store<H256>>(
+    tree: &SparseMerkleTree<H, H256, S>,
+    key: &H256,
+) -> Result<Bytes, Box<dyn StdError>> {
+    let length = stored_length(tree, key)?;
+    let mut data = Vec::new();
+    for i in 0..chunk_count(length)? {
+        data.extend_from_slice(tree.get(&chunk_key(key, i))?.as_slice());
+    }
+    data.truncate(length as usize);
+    Ok(data.into())
+}
+
+impl<H: Hasher + Default, S: Store<H256> + ClearStore> CkbSimpleAccount<SmtState<S, H>> {
+    /// Reassembles a variable length value from committed state
+    pub fn load_bytes(&self, key: &H256) -> Result<Bytes, Box<dyn StdError>> {
+        load_bytes(&self.state, key)
+    }
+}
@@ -1,270 +1,910 @@
 use crate::{
-    run,
-    smt::{CkbBlake2bHasher, ClearStore},
+    backend::{SmtChanges, SmtState, SnapshotBackend, StateBackend, TransactionLayout},
+    smt::{collect_prefix_leaves, ClearStore, IterableStore},
+    snapshot::StateSnapshot,
     Config, Error,
 };
 use bytes::Bytes;
 use ckb_types::{
-    core::{DepType, TransactionBuilder, TransactionView},
+    core::{Capacity, TransactionBuilder, TransactionView},
     packed::{
-        BytesOpt, CellDep, CellInput, CellOutput, OutPoint, ScriptOpt, Transaction, WitnessArgs,
+        Byte32, BytesOpt, CellInput, CellOutput, OutPoint, Script, ScriptOpt, Transaction,
+        WitnessArgs,
     },
     prelude::*,
 };
-use replace_with::replace_with_or_abort_and_return;
-use sparse_merkle_tree::{traits::Store, SparseMerkleTree, H256};
-use std::collections::HashMap;
+use sparse_merkle_tree::{
+    traits::{Hasher, Store},
+    H256,
+};
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::error::Error as StdError;
+use std::fmt;
 
-pub struct CkbSimpleAccount<S: Store<H256> + ClearStore> {
+pub struct CkbSimpleAccount<B: StateBackend> {
     pub config: Config,
-    pub tree: SparseMerkleTree<CkbBlake2bHasher, H256, S>,
+    pub state: B,
     pub last_cell: Option<(OutPoint, CellOutput, Bytes)>,
+    pending: VecDeque<PendingTransaction<B::Changes>>,
+}
+
+/// State transition introduced by a single account transaction. An update
+/// without last cell destroys the account.
+#[derive(Debug, Clone)]
+pub(crate) struct AccountUpdate<C> {
+    pub(crate) last_cell: Option<(OutPoint, CellOutput, Bytes)>,
+    pub(crate) changes: C,
+}
+
+/// Information needed to revert an applied update, used when the block
+/// containing the transaction is rolled back.
+#[derive(Debug, Clone)]
+pub(crate) struct UndoRecord<U> {
+    last_cell: Option<(OutPoint, CellOutput, Bytes)>,
+    undo: U,
+}
+
+pub(crate) enum Advance<C> {
+    /// The committed transaction is the first pending transaction
+    Pending(Byte32),
+    Update(Box<AccountUpdate<C>>),
+}
+
+/// A transaction that is sent but not yet committed on chain. The state
+/// changes it introduces are kept as an overlay on top of the state of
+/// its predecessor.
+#[derive(Debug, Clone)]
+pub struct PendingTransaction<C> {
+    transaction: Transaction,
+    hash: Byte32,
+    consumed_cell: Option<OutPoint>,
+    update: AccountUpdate<C>,
+}
+
+impl<C> PendingTransaction<C> {
+    pub fn transaction(&self) -> &Transaction {
+        &self.transaction
+    }
+
+    pub fn hash(&self) -> &Byte32 {
+        &self.hash
+    }
+
+    /// State changes staged by this transaction
+    pub fn changes(&self) -> &C {
+        &self.update.changes
+    }
+
+    /// Account cell created by this transaction, `None` means this
+    /// transaction destroys the account
+    pub fn last_cell(&self) -> Option<&(OutPoint, CellOutput, Bytes)> {
+        self.update.last_cell.as_ref()
+    }
 }
 
-impl<S: Store<H256> + ClearStore + Default> CkbSimpleAccount<S> {
+impl PendingTransaction<SmtChanges> {
+    /// Root hash of the account once this transaction is committed
+    pub fn root_hash(&self) -> &H256 {
+        self.update.changes.root_hash()
+    }
+}
+
+impl<B: StateBackend + Default> CkbSimpleAccount<B> {
     pub fn empty(config: Config) -> Self {
-        CkbSimpleAccount {
-            config,
-            tree: SparseMerkleTree::default(),
-            last_cell: None,
-        }
+        CkbSimpleAccount::empty_with_state(config, B::default())
     }
 
     /// Given a list of transactions, this method tries to connect the transactions
     /// a chain based on consumed OutPoints, then it uses the chain of transactions
     /// to restore the underlying account.
+    ///
+    /// Transactions can be provided in any order, duplicates are ignored. The chain
+    /// starts from the genesis transaction, which creates the account cell without
+    /// consuming one. When two transactions consume the same account cell, or when
+    /// `consume_all_transactions` is set and some transactions cannot be connected to
+    /// the chain, `Error::Restore` is returned with a report of the problems found.
     pub fn restore_from_transactions(
         config: Config,
         transactions: &[Transaction],
         consume_all_transactions: bool,
     ) -> Result<Self, Box<dyn StdError>> {
-        let mut chain: Vec<(TransactionView, Option<OutPoint>)> = Vec::new();
-        let mut spent_cells: HashMap<Vec<u8>, (TransactionView, Option<OutPoint>)> =
-            HashMap::default();
-        let mut created_cells: HashMap<Vec<u8>, TransactionView> = HashMap::default();
-        for transaction in transactions {
-            let view = transaction.clone().into_view();
-            let outputs: Vec<(usize, CellOutput)> = view
-                .outputs()
-                .into_iter()
-                .enumerate()
-                .filter(|(_, o)| {
-                    o.type_().is_some() && o.type_().to_opt().unwrap() == config.type_script
-                })
-                .collect();
-            if outputs.len() > 1 {
-                return Err(Error::InvalidTransaction(
-                    view.hash(),
-                    "Invalid number of outputs!".to_string(),
-                )
-                .into());
-            }
-
-            let tx_hash = view.hash();
-            let entry = (
-                view,
-                outputs.get(0).map(|(i, _)| {
-                    OutPoint::new_builder()
-                        .tx_hash(tx_hash)
-                        .index((*i as u32).pack())
-                        .build()
-                }),
-            );
-            if chain.is_empty() {
-                chain.push(entry);
-            } else {
-                for input in entry.0.inputs() {
-                    spent_cells.insert(input.previous_output().as_slice().to_vec(), entry.clone());
-                }
-                if entry.1.is_some() {
-                    created_cells.insert(entry.1.unwrap().as_slice().to_vec(), entry.0);
-                }
-
-                loop {
-                    let mut inserted = false;
-                    for input in chain[0].0.inputs() {
-                        if let Some(tx) =
-                            created_cells.remove(&input.previous_output().as_slice().to_vec())
-                        {
-                            chain.insert(0, (tx, Some(input.previous_output())));
-                            inserted = true;
-                            break;
-                        }
-                    }
-                    if !inserted {
-                        break;
-                    }
-                }
-                loop {
-                    if let Some(op) = &chain[chain.len() - 1].1 {
-                        if let Some(next_entry) = spent_cells.remove(&op.as_slice().to_vec()) {
-                            chain.push(next_entry);
-                            continue;
-                        }
-                    }
-                    break;
-                }
-            }
-        }
-        if consume_all_transactions && chain.len() != transactions.len() {
-            return Err("Not all transactions can be chained together!".into());
+        let (chain, report) = chain_transactions::<B>(&config, transactions, None)?;
+        if report.is_ambiguous() || (consume_all_transactions && !report.is_clean()) {
+            return Err(Error::Restore(report).into());
         }
         let mut account = CkbSimpleAccount::empty(config);
-        for (view, _) in chain {
+        for view in chain {
             account.advance(&view.data())?;
         }
         Ok(account)
     }
 }
 
-impl<S: Store<H256> + ClearStore> CkbSimpleAccount<S> {
-    pub fn empty_with_tree(
+impl<H: Hasher + Default, S: Store<H256> + ClearStore + IterableStore + Default>
+    CkbSimpleAccount<SmtState<S, H>>
+{
+    /// Restores the account starting from a trusted checkpoint instead of the
+    /// genesis transaction, only transactions consuming the checkpoint cell and
+    /// its successors are replayed, together with later re-creations of the
+    /// account if the checkpoint cell ends up destroyed. The checkpoint must
+    /// include a last cell whose data matches the checkpoint root hash, and the
+    /// transaction creating the checkpoint cell must be among `transactions`
+    /// so the cell can be checked against it.
+    ///
+    /// Transactions at or before the checkpoint are reported in
+    /// `before_checkpoint` and don't count as unconnected transactions.
+    pub fn restore_from_checkpoint(
         config: Config,
-        tree: SparseMerkleTree<CkbBlake2bHasher, H256, S>,
-    ) -> Self {
+        checkpoint: &StateSnapshot,
+        transactions: &[Transaction],
+        consume_all_transactions: bool,
+    ) -> Result<Self, Box<dyn StdError>> {
+        let (out_point, output, data) = checkpoint
+            .last_cell
+            .as_ref()
+            .ok_or("Checkpoint does not have a last cell!")?;
+        let index: u32 = out_point.index().unpack();
+        let view = transactions
+            .iter()
+            .map(|transaction| transaction.clone().into_view())
+            .find(|view| view.hash() == out_point.tx_hash())
+            .ok_or("Transaction creating the checkpoint cell is missing!")?;
+        let matched = match (
+            view.output(index as usize),
+            view.outputs_data().get(index as usize),
+        ) {
+            (Some(o), Some(d)) => o.as_slice() == output.as_slice() && d.raw_data() == *data,
+            _ => false,
+        };
+        if !matched {
+            return Err("Checkpoint cell does not match its transaction!".into());
+        }
+        let (chain, report) =
+            chain_transactions::<SmtState<S, H>>(&config, transactions, Some(out_point))?;
+        if report.is_ambiguous() || (consume_all_transactions && !report.is_clean()) {
+            return Err(Error::Restore(report).into());
+        }
+        let mut account = CkbSimpleAccount::import_snapshot(config, checkpoint)?;
+        for view in chain {
+            account.advance(&view.data())?;
+        }
+        Ok(account)
+    }
+}
+
+/// Problems found when connecting transactions into a chain. Each provided
+/// transaction is either part of the restored chain or listed in exactly one
+/// of the categories below.
+#[derive(Debug, PartialEq, Clone, Eq, Default)]
+pub struct RestoreReport {
+    /// Hash of the transaction creating the current account cell, if found.
+    /// When the account ends up destroyed, this is the first creation replayed.
+    pub genesis: Option<Byte32>,
+    /// Account cell the chain starts from when restoring from a checkpoint
+    pub checkpoint: Option<OutPoint>,
+    /// Hashes of transactions at or before the checkpoint, which are covered
+    /// by the checkpoint and not replayed
+    pub before_checkpoint: Vec<Byte32>,
+    /// Hashes of transactions provided more than once, only the extra copies
+    /// are listed here
+    pub duplicates: Vec<Byte32>,
+    /// Account cells consumed by more than one transaction, together with the
+    /// hashes of all consuming transactions
+    pub forks: Vec<(OutPoint, Vec<Byte32>)>,
+    /// Hashes of transactions creating an account cell that is never destroyed,
+    /// when there are more than one of them
+    pub conflicting_geneses: Vec<Byte32>,
+    /// Hashes of transactions consuming an account cell that is created by none of
+    /// the provided transactions, meaning some transactions are missing in between
+    pub missing_links: Vec<Byte32>,
+    /// Hashes of all other transactions that are not connected to the chain,
+    /// such as the ones following a missing link or a fork
+    pub orphaned: Vec<Byte32>,
+}
+
+impl RestoreReport {
+    /// Returns true when all provided transactions are chained together
+    pub fn is_clean(&self) -> bool {
+        (self.genesis.is_some() || self.checkpoint.is_some())
+            && self.forks.is_empty()
+            && self.conflicting_geneses.is_empty()
+            && self.orphaned.is_empty()
+            && self.missing_links.is_empty()
+    }
+
+    /// Returns true when the chain cannot be restored even if unconnected
+    /// transactions are tolerated
+    fn is_ambiguous(&self) -> bool {
+        !self.forks.is_empty() || !self.conflicting_geneses.is_empty()
+    }
+}
+
+impl fmt::Display for RestoreReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}, {} duplicate(s), {} fork(s), {} conflicting genesis, {} missing link(s), {} orphaned",
+            if self.checkpoint.is_some() {
+                "from checkpoint"
+            } else if self.genesis.is_some() {
+                "genesis found"
+            } else {
+                "genesis missing"
+            },
+            self.duplicates.len(),
+            self.forks.len(),
+            self.conflicting_geneses.len(),
+            self.missing_links.len(),
+            self.orphaned.len()
+        )
+    }
+}
+
+struct ChainEntry {
+    view: TransactionView,
+    created_cell: Option<OutPoint>,
+    creation: bool,
+}
+
+/// Parses the witness of the account cell at `index`, it carries the program
+/// in output type when creating the account, and in input type when updating.
+fn account_witness(view: &TransactionView, index: usize) -> Result<WitnessArgs, Box<dyn StdError>> {
+    let witness = view.witnesses().get(index).ok_or("Witness is missing!")?;
+    Ok(WitnessArgs::from_slice(&witness.raw_data()).map_err(|_| "Witness format is invalid!")?)
+}
+
+/// Connects transactions into a chain starting from the genesis transaction, or
+/// from the transaction consuming `checkpoint` when provided. The chain follows
+/// consumed account cells and stops early at the first fork.
+///
+/// An account might be destroyed and created again, each creation starts a new
+/// segment of the chain. Segments ending with a destruction start and end with
+/// empty state, so they can be replayed in any order, while the segment of the
+/// current account cell, if any, must come last.
+fn chain_transactions<B: StateBackend>(
+    config: &Config,
+    transactions: &[Transaction],
+    checkpoint: Option<&OutPoint>,
+) -> Result<(Vec<TransactionView>, RestoreReport), Box<dyn StdError>> {
+    let mut report = RestoreReport::default();
+    let mut entries: Vec<ChainEntry> = Vec::new();
+    let mut seen: HashSet<Vec<u8>> = HashSet::default();
+    for transaction in transactions {
+        let view = transaction.clone().into_view();
+        if !seen.insert(view.hash().as_slice().to_vec()) {
+            report.duplicates.push(view.hash());
+            continue;
+        }
+        let outputs: Vec<usize> = view
+            .outputs_with_data_iter()
+            .enumerate()
+            .filter(|(_, (o, data))| is_account_cell::<B>(config, o, data))
+            .map(|(i, _)| i)
+            .collect();
+        if outputs.len() > 1 {
+            return Err(Error::InvalidTransaction(
+                view.hash(),
+                "Invalid number of outputs!".to_string(),
+            )
+            .into());
+        }
+        let created_cell = outputs.first().map(|i| {
+            OutPoint::new_builder()
+                .tx_hash(view.hash())
+                .index((*i as u32).pack())
+                .build()
+        });
+        // Initial creation provides the program in output type part of witness,
+        // updates use input type part instead.
+        let creation = outputs
+            .first()
+            .and_then(|i| account_witness(&view, *i).ok())
+            .map(|witness_args| {
+                witness_args.output_type().is_some() && witness_args.input_type().is_none()
+            })
+            .unwrap_or(false);
+        entries.push(ChainEntry {
+            view,
+            created_cell,
+            creation,
+        });
+    }
+
+    let created_cells: HashMap<Vec<u8>, usize> = entries
+        .iter()
+        .enumerate()
+        .filter_map(|(i, e)| {
+            e.created_cell
+                .as_ref()
+                .map(|op| (op.as_slice().to_vec(), i))
+        })
+        .collect();
+    let checkpoint_cell = checkpoint.map(|op| op.as_slice().to_vec());
+    let mut spenders: HashMap<Vec<u8>, Vec<usize>> = HashMap::default();
+    let mut roots = Vec::new();
+    for (i, entry) in entries.iter().enumerate() {
+        let consumed: Vec<Vec<u8>> = entry
+            .view
+            .input_pts_iter()
+            .map(|op| op.as_slice().to_vec())
+            .filter(|op| created_cells.contains_key(op) || checkpoint_cell.as_ref() == Some(op))
+            .collect();
+        if consumed.len() > 1 {
+            return Err(Error::InvalidTransaction(
+                entry.view.hash(),
+                "Multiple account cells are consumed!".to_string(),
+            )
+            .into());
+        }
+        match consumed.into_iter().next() {
+            Some(op) => spenders.entry(op).or_default().push(i),
+            None => roots.push(i),
+        }
+    }
+    let mut reported: HashSet<usize> = HashSet::default();
+    let account_cells = entries
+        .iter()
+        .filter_map(|e| e.created_cell.as_ref())
+        .filter(|op| Some(*op) != checkpoint)
+        .chain(checkpoint);
+    for op in account_cells {
+        if let Some(indices) = spenders.get(op.as_slice()) {
+            if indices.len() > 1 {
+                report.forks.push((
+                    op.clone(),
+                    indices.iter().map(|i| entries[*i].view.hash()).collect(),
+                ));
+                reported.extend(indices);
+            }
+        }
+    }
+
+    let next = |op: &OutPoint| match spenders.get(op.as_slice()) {
+        Some(indices) if indices.len() == 1 => Some(indices[0]),
+        _ => None,
+    };
+    // Follows the chain from `start` till the account cell is no longer
+    // consumed by exactly one transaction, or till the checkpoint cell
+    let follow = |start: usize| {
+        let mut segment = vec![start];
+        while let Some(i) = entries[*segment.last().unwrap()]
+            .created_cell
+            .as_ref()
+            .filter(|op| Some(*op) != checkpoint)
+            .and_then(&next)
+        {
+            segment.push(i);
+        }
+        segment
+    };
+    let mut destroyed = Vec::new();
+    let mut live = Vec::new();
+    let mut covered = Vec::new();
+    for i in roots {
+        if !entries[i].creation {
+            report.missing_links.push(entries[i].view.hash());
+            reported.insert(i);
+            continue;
+        }
+        let segment = follow(i);
+        match entries[*segment.last().unwrap()].created_cell.as_ref() {
+            Some(op) if Some(op) == checkpoint => covered.push(segment),
+            Some(_) => live.push(segment),
+            None => destroyed.push(segment),
+        }
+    }
+    let mut chain_indices = Vec::new();
+    if let Some(op) = checkpoint {
+        report.checkpoint = Some(op.clone());
+        if let Some(i) = next(op) {
+            chain_indices = follow(i);
+        }
+        let alive = chain_indices
+            .last()
+            .map(|i| entries[*i].created_cell.is_some())
+            .unwrap_or(true);
+        if alive {
+            // All other creations come before the checkpoint, unless they are
+            // never destroyed, in which case they conflict with it
+            covered.append(&mut destroyed);
+            for segment in live.drain(..) {
+                report
+                    .conflicting_geneses
+                    .push(entries[segment[0]].view.hash());
+                reported.insert(segment[0]);
+            }
+        }
+        for i in covered.into_iter().flatten() {
+            report.before_checkpoint.push(entries[i].view.hash());
+            reported.insert(i);
+        }
+    }
+    if live.len() > 1 {
+        for segment in &live {
+            report
+                .conflicting_geneses
+                .push(entries[segment[0]].view.hash());
+            reported.insert(segment[0]);
+        }
+    } else {
+        // The current account cell is created by the live segment, an account
+        // that ends up destroyed is represented by its first creation. With a
+        // checkpoint, genesis is only set when the account is created again.
+        let genesis = match checkpoint {
+            Some(_) => live.first(),
+            None => live.first().or_else(|| destroyed.first()),
+        };
+        report.genesis = genesis.map(|segment| entries[segment[0]].view.hash());
+        for segment in destroyed.into_iter().chain(live) {
+            chain_indices.extend(segment);
+        }
+    }
+    let chained: HashSet<usize> = chain_indices.iter().cloned().collect();
+    report.orphaned = entries
+        .iter()
+        .enumerate()
+        .filter(|(i, _)| !chained.contains(i) && !reported.contains(i))
+        .map(|(_, e)| e.view.hash())
+        .collect();
+    let chain = chain_indices
+        .into_iter()
+        .map(|i| entries[i].view.clone())
+        .collect();
+    Ok((chain, report))
+}
+
+impl<B: StateBackend> CkbSimpleAccount<B> {
+    pub fn empty_with_state(config: Config, state: B) -> Self {
         CkbSimpleAccount {
             config,
-            tree,
+            state,
             last_cell: None,
+            pending: VecDeque::new(),
         }
     }
 
-    pub fn new(
-        config: Config,
-        tree: SparseMerkleTree<CkbBlake2bHasher, H256, S>,
-        last_cell: (OutPoint, CellOutput, Bytes),
-    ) -> Self {
+    pub fn new(config: Config, state: B, last_cell: (OutPoint, CellOutput, Bytes)) -> Self {
         CkbSimpleAccount {
             config,
-            tree,
+            state,
             last_cell: Some(last_cell),
+            pending: VecDeque::new(),
         }
     }
 
-    /// Runs program with latest SMT tree, and generate a transaction skeleton that can
+    /// Commitment to committed state, which matches data of the last cell
+    pub fn commitment(&self) -> Bytes {
+        self.state.commitment()
+    }
+
+    /// Runs program with latest state, and generate a transaction skeleton that can
     /// be used to alter on-chain state. Notice this method does not take transaction
     /// fees into account, nor will it gather enough capacity in initial cell creation.
     /// So typically, you would want to start from the transaction skeleton generated here
     /// and modify the transaction. Due to the same reason, this method doesn't consider
     /// signature generation in inputs as well.
+    ///
+    /// When there are pending transactions, the skeleton is built on top of the
+    /// state left by the last pending transaction.
     pub fn generate(&self, program: &Bytes) -> Result<Transaction, Box<dyn StdError>> {
-        let result = run(&self.config, &self.tree, program)?;
-        let proof = result.generate_proof(&self.tree)?;
-        let root_hash = result.committed_root_hash(&self.tree)?;
-        let data = BytesOpt::new_builder()
-            .set(Some(proof.serialize(program)?.pack()))
-            .build();
-        let mut witness_builder = WitnessArgs::new_builder();
-        if self.last_cell.is_none() {
-            witness_builder = witness_builder.output_type(data);
-        } else {
-            witness_builder = witness_builder.input_type(data);
+        generate_transaction(
+            &self.config,
+            &self.state,
+            &self.pending_changes(),
+            self.tip_last_cell(),
+            program,
+        )
+    }
+
+    /// Updates internal state based on provided transaction. Typically, the
+    /// transaction provided here comes from a committed block on chain.
+    ///
+    /// If the transaction is the first pending transaction, its staged changes are
+    /// applied directly. Otherwise the transaction conflicts with the pending ones,
+    /// all of which are dropped.
+    pub fn advance(&mut self, transaction: &Transaction) -> Result<(), Box<dyn StdError>> {
+        let advance = self.prepare_advance(transaction)?;
+        self.finish_advance(advance).map(|_| ())
+    }
+
+    /// Read-only part of `advance`, this is where the program in the transaction
+    /// gets executed and verified.
+    pub(crate) fn prepare_advance(
+        &self,
+        transaction: &Transaction,
+    ) -> Result<Advance<B::Changes>, Box<dyn StdError>> {
+        let hash = transaction.clone().into_view().hash();
+        if self
+            .pending
+            .front()
+            .map(|p| p.hash == hash)
+            .unwrap_or(false)
+        {
+            return Ok(Advance::Pending(hash));
         }
-        let mut output_builder = CellOutput::new_builder()
-            .type_(
-                ScriptOpt::new_builder()
-                    .set(Some(self.config.type_script.clone()))
-                    .build(),
-            )
-            .capacity(if self.last_cell.is_none() {
-                self.config.capacity.pack()
-            } else {
-                self.last_cell.as_ref().unwrap().1.capacity()
-            });
-        if self.config.lock_script.is_none() {
-            if self.last_cell.is_none() {
-                return Err("No valid lock script to use!".into());
+        let update = prepare_update(
+            &self.config,
+            &self.state,
+            &[],
+            self.last_cell.as_ref(),
+            transaction,
+        )?;
+        Ok(Advance::Update(Box::new(update)))
+    }
+
+    /// Same as `prepare_advance` for all transactions of a block touching the
+    /// account, each one is verified on top of the ones before it. Nothing is
+    /// applied, so a failure in any transaction leaves the account untouched.
+    pub(crate) fn prepare_block(
+        &self,
+        transactions: &[Transaction],
+    ) -> Result<Vec<Advance<B::Changes>>, Box<dyn StdError>> {
+        let mut advances = Vec::new();
+        let mut staged: Vec<B::Changes> = Vec::new();
+        let mut last_cell = self.last_cell.clone();
+        // Index of the next pending transaction, `None` once a transaction other
+        // than the pending ones is staged, which drops them all
+        let mut next_pending = Some(0);
+        for transaction in transactions {
+            let view = transaction.clone().into_view();
+            let remaining: Vec<&PendingTransaction<B::Changes>> = match next_pending {
+                Some(index) => self.pending.iter().skip(index).collect(),
+                None => Vec::new(),
+            };
+            let cells = last_cell
+                .iter()
+                .chain(remaining.iter().filter_map(|p| p.update.last_cell.as_ref()))
+                .map(|(op, _, _)| op);
+            if !touches_cells(&self.config, &view, cells) {
+                continue;
             }
-            output_builder = output_builder.lock(self.last_cell.as_ref().unwrap().1.lock());
-        } else {
-            output_builder = output_builder.lock(self.config.lock_script.clone().unwrap());
+            if let Some(pending) = remaining.first().filter(|p| p.hash == view.hash()) {
+                staged.push(pending.update.changes.clone());
+                last_cell = pending.update.last_cell.clone();
+                next_pending = next_pending.map(|index| index + 1);
+                advances.push(Advance::Pending(view.hash()));
+                continue;
+            }
+            let update = prepare_update(
+                &self.config,
+                &self.state,
+                &staged.iter().collect::<Vec<_>>(),
+                last_cell.as_ref(),
+                transaction,
+            )?;
+            staged.push(update.changes.clone());
+            last_cell = update.last_cell.clone();
+            next_pending = None;
+            advances.push(Advance::Update(Box::new(update)));
         }
-        let mut transaction_builder = TransactionBuilder::default()
-            .cell_dep(
-                CellDep::new_builder()
-                    .out_point(self.config.validator_outpoint.clone())
-                    .dep_type(DepType::Code.into())
-                    .build(),
-            )
-            .witness(witness_builder.build().as_bytes().pack())
-            .output(output_builder.build())
-            .output_data(Bytes::from(root_hash.as_slice().to_vec()).pack());
-        if self.last_cell.is_some() {
-            transaction_builder = transaction_builder.input(
-                CellInput::new_builder()
-                    .previous_output(self.last_cell.as_ref().unwrap().0.clone())
-                    .build(),
-            );
+        Ok(advances)
+    }
+
+    /// Applies the result of `prepare_advance`, no other modifications shall
+    /// happen to the account in between.
+    pub(crate) fn finish_advance(
+        &mut self,
+        advance: Advance<B::Changes>,
+    ) -> Result<UndoRecord<B::Undo>, Box<dyn StdError>> {
+        match advance {
+            Advance::Pending(hash) => {
+                let pending = self
+                    .pending
+                    .pop_front()
+                    .filter(|p| p.hash == hash)
+                    .ok_or("Pending transactions are altered!")?;
+                self.apply_update(pending.update)
+            }
+            Advance::Update(update) => {
+                let record = self.apply_update(*update)?;
+                self.pending.clear();
+                Ok(record)
+            }
         }
-        Ok(transaction_builder.build().data())
     }
 
-    /// Updates internal SMT state based on provided transaction. Typically, the
-    /// transaction provided here comes from a committed block on chain.
-    pub fn advance(&mut self, transaction: &Transaction) -> Result<(), Box<dyn StdError>> {
+    /// Reverts an update applied by `finish_advance`, updates applied after it
+    /// must be reverted first. Pending transactions are dropped since the state
+    /// they are built upon is gone.
+    pub(crate) fn undo(&mut self, record: UndoRecord<B::Undo>) -> Result<(), Box<dyn StdError>> {
+        self.state.revert(record.undo)?;
+        self.last_cell = record.last_cell;
+        self.pending.clear();
+        Ok(())
+    }
+
+    /// Returns true if the transaction creates, updates or destroys the account cell,
+    /// including the cells created by pending transactions.
+    pub fn touches(&self, transaction: &Transaction) -> bool {
+        let cells = self
+            .last_cell
+            .iter()
+            .chain(
+                self.pending
+                    .iter()
+                    .filter_map(|p| p.update.last_cell.as_ref()),
+            )
+            .map(|(op, _, _)| op);
+        touches_cells(&self.config, &transaction.clone().into_view(), cells)
+    }
+
+    /// Queues a sent but not yet committed transaction, typically one built from
+    /// `generate` and then completed with fees and signatures. Following calls to
+    /// `generate` will chain off the cell created by this transaction. A pending
+    /// transaction consuming the same cell as an already queued one replaces it,
+    /// together with all transactions queued after it.
+    pub fn push_pending(&mut self, transaction: &Transaction) -> Result<(), Box<dyn StdError>> {
         let view = transaction.clone().into_view();
-        let mut outputs: Vec<(usize, (CellOutput, Bytes))> = view
-            .outputs_with_data_iter()
-            .enumerate()
-            .filter(|(_, (o, _))| {
-                o.type_().is_some() && o.type_().to_opt().unwrap() == self.config.type_script
-            })
-            .collect();
-        if outputs.len() > 1 {
+        let inputs: Vec<OutPoint> = view.input_pts_iter().collect();
+        if let Some(index) = self.pending.iter().position(|p| {
+            p.consumed_cell
+                .as_ref()
+                .map(|op| inputs.contains(op))
+                .unwrap_or(false)
+        }) {
+            self.pending.truncate(index);
+        }
+        let consumed_cell = self.tip_last_cell().map(|(op, _, _)| op.clone());
+        let update = prepare_update(
+            &self.config,
+            &self.state,
+            &self.pending_changes(),
+            self.tip_last_cell(),
+            transaction,
+        )?;
+        self.pending.push_back(PendingTransaction {
+            transaction: transaction.clone(),
+            hash: view.hash(),
+            consumed_cell,
+            update,
+        });
+        Ok(())
+    }
+
+    /// Removes a pending transaction that will never be committed, all pending
+    /// transactions chained after it are removed as well. Removed transactions
+    /// are returned in queued order.
+    pub fn drop_pending(&mut self, hash: &Byte32) -> Vec<Transaction> {
+        match self.pending.iter().position(|p| &p.hash == hash) {
+            Some(index) => self
+                .pending
+                .split_off(index)
+                .into_iter()
+                .map(|p| p.transaction)
+                .collect(),
+            None => Vec::new(),
+        }
+    }
+
+    pub fn pending_transactions(&self) -> &VecDeque<PendingTransaction<B::Changes>> {
+        &self.pending
+    }
+
+    fn tip_last_cell(&self) -> Option<&(OutPoint, CellOutput, Bytes)> {
+        match self.pending.back() {
+            Some(pending) => pending.update.last_cell.as_ref(),
+            None => self.last_cell.as_ref(),
+        }
+    }
+
+    fn pending_changes(&self) -> Vec<&B::Changes> {
+        self.pending.iter().map(|p| &p.update.changes).collect()
+    }
+
+    pub(crate) fn apply_update(
+        &mut self,
+        update: AccountUpdate<B::Changes>,
+    ) -> Result<UndoRecord<B::Undo>, Box<dyn StdError>> {
+        let undo = self.state.apply(update.changes)?;
+        let record = UndoRecord {
+            last_cell: self.last_cell.clone(),
+            undo,
+        };
+        self.last_cell = update.last_cell;
+        Ok(record)
+    }
+}
+
+impl<B: SnapshotBackend> CkbSimpleAccount<B> {
+    /// Copies the account, including pending transactions, into an independent
+    /// account that is not affected by later modifications to this one.
+    pub fn snapshot(&self) -> Result<Self, Box<dyn StdError>> {
+        Ok(CkbSimpleAccount {
+            config: self.config.clone(),
+            state: self.state.snapshot()?,
+            last_cell: self.last_cell.clone(),
+            pending: self.pending.clone(),
+        })
+    }
+}
+
+impl<H: Hasher + Default, S: Store<H256> + ClearStore> CkbSimpleAccount<SmtState<S, H>> {
+    /// Iterates over all non-empty leaves of committed state, ordered by key bytes.
+    pub fn iter_leaves(&self) -> Result<impl Iterator<Item = (H256, H256)>, Box<dyn StdError>> {
+        self.scan_prefix(&[])
+    }
+
+    /// Iterates over non-empty leaves of committed state whose keys start with
+    /// `prefix`, ordered by key bytes. Leaves are found by walking the tree from
+    /// current root, so stale leaves left in the store are never visited, and
+    /// subtrees that can't hold a key starting with `prefix` are skipped. The
+    /// tree places leading key bytes at its lowest heights, so pruning works
+    /// best for keys sharing their trailing bytes.
+    pub fn scan_prefix(
+        &self,
+        prefix: &[u8],
+    ) -> Result<impl Iterator<Item = (H256, H256)>, Box<dyn StdError>> {
+        let leaves = collect_prefix_leaves(self.state.store(), self.state.root(), prefix)?;
+        Ok(leaves.into_iter())
+    }
+}
+
+fn is_account_cell<B: StateBackend>(config: &Config, output: &CellOutput, data: &Bytes) -> bool {
+    output.type_().to_opt().as_ref() == Some(&config.type_script) && B::is_account_cell(data)
+}
+
+/// Returns true if the transaction creates an account cell, or consumes one
+/// of `cells`
+fn touches_cells<'a>(
+    config: &Config,
+    view: &TransactionView,
+    cells: impl Iterator<Item = &'a OutPoint>,
+) -> bool {
+    let creates = view
+        .outputs()
+        .into_iter()
+        .any(|o| o.type_().is_some() && o.type_().to_opt().unwrap() == config.type_script);
+    let cells: Vec<&OutPoint> = cells.collect();
+    creates || view.input_pts_iter().any(|op| cells.contains(&&op))
+}
+
+fn generate_transaction<B: StateBackend>(
+    config: &Config,
+    state: &B,
+    pending: &[&B::Changes],
+    last_cell: Option<&(OutPoint, CellOutput, Bytes)>,
+    program: &Bytes,
+) -> Result<Transaction, Box<dyn StdError>> {
+    let layout = state.generate(config, pending, program)?;
+    build_transaction(config, last_cell, layout)
+}
+
+/// Builds a cell with the account type script, capacity is raised to the
+/// occupied capacity when it is not enough to hold `data`.
+fn account_output(
+    config: &Config,
+    lock: Script,
+    capacity: u64,
+    data: &Bytes,
+) -> Result<CellOutput, Box<dyn StdError>> {
+    let output = CellOutput::new_builder()
+        .type_(
+            ScriptOpt::new_builder()
+                .set(Some(config.type_script.clone()))
+                .build(),
+        )
+        .lock(lock)
+        .capacity(capacity.pack())
+        .build();
+    let data_capacity = Capacity::bytes(data.len()).map_err(|_| "Capacity overflow!")?;
+    let occupied = output
+        .occupied_capacity(data_capacity)
+        .map_err(|_| "Capacity overflow!")?
+        .as_u64();
+    if occupied > capacity {
+        Ok(output.as_builder().capacity(occupied.pack()).build())
+    } else {
+        Ok(output)
+    }
+}
+
+/// Assembles the account transaction laid out by a `StateBackend`
+pub(crate) fn build_transaction(
+    config: &Config,
+    last_cell: Option<&(OutPoint, CellOutput, Bytes)>,
+    layout: TransactionLayout,
+) -> Result<Transaction, Box<dyn StdError>> {
+    let data = BytesOpt::new_builder()
+        .set(Some(layout.witness.pack()))
+        .build();
+    let mut witness_builder = WitnessArgs::new_builder();
+    if last_cell.is_none() {
+        witness_builder = witness_builder.output_type(data);
+    } else {
+        witness_builder = witness_builder.input_type(data);
+    }
+    let lock = match (&config.lock_script, last_cell) {
+        (Some(lock), _) => lock.clone(),
+        (None, Some((_, output, _))) => output.lock(),
+        (None, None) => return Err("No valid lock script to use!".into()),
+    };
+    let capacity = match last_cell {
+        Some((_, output, _)) => output.capacity().unpack(),
+        None => config.capacity,
+    };
+    let mut transaction_builder = TransactionBuilder::default()
+        .witness(witness_builder.build().as_bytes().pack())
+        .output(account_output(
+            config,
+            lock.clone(),
+            capacity,
+            &layout.output_data,
+        )?)
+        .output_data(layout.output_data.pack());
+    for cell_dep in layout.cell_deps {
+        transaction_builder = transaction_builder.cell_dep(cell_dep);
+    }
+    for data in layout.extra_outputs {
+        transaction_builder = transaction_builder
+            .output(account_output(config, lock.clone(), 0, &data)?)
+            .output_data(data.pack());
+    }
+    let inputs = last_cell
+        .map(|(out_point, _, _)| out_point.clone())
+        .into_iter()
+        .chain(layout.extra_inputs);
+    for out_point in inputs {
+        transaction_builder =
+            transaction_builder.input(CellInput::new_builder().previous_output(out_point).build());
+    }
+    Ok(transaction_builder.build().data())
+}
+
+/// Verifies that `transaction` is a valid state transition from the state
+/// represented by `state` with `pending` changes and `last_cell`, and computes
+/// the resulting update without touching the state.
+pub(crate) fn prepare_update<B: StateBackend>(
+    config: &Config,
+    state: &B,
+    pending: &[&B::Changes],
+    last_cell: Option<&(OutPoint, CellOutput, Bytes)>,
+    transaction: &Transaction,
+) -> Result<AccountUpdate<B::Changes>, Box<dyn StdError>> {
+    let view = transaction.clone().into_view();
+    let mut outputs: Vec<(usize, (CellOutput, Bytes))> = view
+        .outputs_with_data_iter()
+        .enumerate()
+        .filter(|(_, (o, data))| is_account_cell::<B>(config, o, data))
+        .collect();
+    if outputs.len() > 1 {
+        return Err(Error::InvalidTransaction(
+            view.hash(),
+            "Invalid number of outputs!".to_string(),
+        )
+        .into());
+    }
+    if let Some((last_op, _, _)) = last_cell {
+        if view.input_pts_iter().all(|op| &op != last_op) {
             return Err(Error::InvalidTransaction(
                 view.hash(),
-                "Invalid number of outputs!".to_string(),
+                "Provided transaction does not consume last cell!".to_string(),
             )
             .into());
         }
-        if let Some((last_op, _, _)) = &self.last_cell {
-            if view.input_pts_iter().all(|op| &op != last_op) {
-                return Err(Error::InvalidTransaction(
-                    view.hash(),
-                    "Provided transaction does not consume last cell!".to_string(),
-                )
-                .into());
-            }
-        }
-        if outputs.is_empty() {
-            replace_with_or_abort_and_return(&mut self.tree, |tree| {
-                let root_hash = *tree.root();
-                let mut store = tree.take_store();
-                match store.clear_store() {
-                    Ok(()) => (Ok(()), SparseMerkleTree::new(H256::zero(), store)),
-                    Err(e) => (Err(e), SparseMerkleTree::new(root_hash, store)),
-                }
-            })?;
-            self.last_cell = None;
-            return Ok(());
-        }
-        let (index, (output, output_data)) = outputs.pop().unwrap();
-        let witness = view
-            .witnesses()
-            .get(index)
-            .ok_or_else(|| "Witness is missing!")?;
-        let witness_args = WitnessArgs::from_slice(witness.as_slice())
-            .map_err(|_| "Witness format is invalid!")?;
-        let program = if self.last_cell.is_none() {
-            witness_args.output_type()
-        } else {
-            witness_args.input_type()
-        }
-        .to_opt()
-        .ok_or_else(|| "Witness format is invalid!")?
-        .raw_data();
-        let result = run(&self.config, &self.tree, &program)?;
-        let new_root_hash = result.committed_root_hash(&self.tree)?;
-        if output_data.len() != 32 || output_data != new_root_hash.as_slice() {
-            return Err("Invalid new root hash!".into());
-        }
-        result.commit(&mut self.tree)?;
-        let out_point = OutPoint::new_builder()
-            .tx_hash(view.hash())
-            .index((index as u32).pack())
-            .build();
-        self.last_cell = Some((out_point, output, output_data));
-        Ok(())
     }
+    if outputs.is_empty() {
+        return Ok(AccountUpdate {
+            last_cell: None,
+            changes: state.destroy(),
+        });
+    }
+    let (index, (output, output_data)) = outputs.pop().unwrap();
+    let witness_args = account_witness(&view, index)?;
+    let program = if last_cell.is_none() {
+        witness_args.output_type()
+    } else {
+        witness_args.input_type()
+    }
+    .to_opt()
+    .ok_or_else(|| "Witness format is invalid!")?
+    .raw_data();
+    let changes = state.verify(config, pending, &program, &output_data, &view)?;
+    let out_point = OutPoint::new_builder()
+        .tx_hash(view.hash())
+        .index((index as u32).pack())
+        .build();
+    Ok(AccountUpdate {
+        last_cell: Some((out_point, output, output_data)),
+        changes,
+    })
 }
@@ -0,0 +1,60 @@
+use crate::{
+    backend::{SmtState, StateBackend},
+    chunks::load_bytes,
+    ckb::CkbSimpleAccount,
+    keys::{code_hash, code_key},
+    smt::ClearStore,
+};
+use bytes::Bytes;
+use ckb_types::packed::Transaction;
+use sparse_merkle_tree::{
+    traits::{Hasher, Store},
+    H256,
+};
+use std::error::Error as StdError;
+
+/// Prefix of programs storing code in account state, see `c/code.h`. Programs
+/// with this prefix, or `CALL_MAGIC`, are only recognized by generators and
+/// validators built with `CSAL_ENABLE_CODE_PROGRAMS`.
+pub const DEPLOY_MAGIC: &[u8] = b"CSALDPLY";
+/// Prefix of programs executing stored code by hash, see `c/code.h`
+pub const CALL_MAGIC: &[u8] = b"CSALCALL";
+
+/// Builds a program that stores `code` in account state when executed
+pub fn deploy_program(code: &[u8]) -> Bytes {
+    let mut buffer = Vec::with_capacity(DEPLOY_MAGIC.len() + code.len());
+    buffer.extend_from_slice(DEPLOY_MAGIC);
+    buffer.extend_from_slice(code);
+    buffer.into()
+}
+
+/// Builds a program that executes previously deployed code
+pub fn call_program(code_hash: &H256) -> Bytes {
+    let mut buffer = Vec::with_capacity(CALL_MAGIC.len() + 32);
+    buffer.extend_from_slice(CALL_MAGIC);
+    buffer.extend_from_slice(code_hash.as_slice());
+    buffer.into()
+}
+
+impl<B: StateBackend> CkbSimpleAccount<B> {
+    /// Generates a transaction skeleton storing `code` in account state, returns
+    /// hash of the code together with the transaction. Deployed code can later be
+    /// executed by generating a transaction with `call_program`. See `generate`
+    /// for caveats on the generated transaction.
+    pub fn deploy(&self, code: &[u8]) -> Result<(H256, Transaction), Box<dyn StdError>> {
+        let transaction = self.generate(&deploy_program(code))?;
+        Ok((code_hash(code), transaction))
+    }
+}
+
+impl<H: Hasher + Default, S: Store<H256> + ClearStore> CkbSimpleAccount<SmtState<S, H>> {
+    /// Fetches deployed code from committed state
+    pub fn load_code(&self, code_hash: &H256) -> Result<Option<Bytes>, Box<dyn StdError>> {
+        let code = load_bytes(&self.state, &code_key(code_hash))?;
+        Ok(if code.is_empty() { None } else { Some(code) })
+    }
+
+    pub fn has_code(&self, code_hash: &H256) -> Result<bool, Box<dyn StdError>> {
+        Ok(!self.state.get(&code_key(code_hash))?.is_zero())
+    }
+}
@@ -0,0 +1,155 @@
+use crate::{
+    backend::SmtState,
+    ckb::CkbSimpleAccount,
+    smt::{branch_children, collect_node_leaves, ClearStore},
+};
+use sparse_merkle_tree::{
+    traits::{Hasher, Store},
+    tree::BranchNode,
+    H256,
+};
+use std::error::Error as StdError;
+
+/// Keys changed between two states, each list is sorted by key. Keys holding
+/// zero values are considered absent.
+#[derive(Debug, PartialEq, Clone, Eq, Default)]
+pub struct StateDiff {
+    /// Keys only present in the new state, with their values
+    pub added: Vec<(H256, H256)>,
+    /// Keys only present in the old state, with their old values
+    pub removed: Vec<(H256, H256)>,
+    /// Keys present in both states with different values. Order of items is
+    /// key, old value, new value
+    pub modified: Vec<(H256, H256, H256)>,
+}
+
+impl StateDiff {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.modified.is_empty()
+    }
+
+    /// Records differences between two sorted lists of leaves
+    fn compare_leaves(&mut self, old: Vec<(H256, H256)>, new: Vec<(H256, H256)>) {
+        let mut old = old.into_iter().peekable();
+        let mut new = new.into_iter().peekable();
+        loop {
+            match (old.peek(), new.peek()) {
+                (Some(a), Some(b)) if a.0 == b.0 => {
+                    if a.1 != b.1 {
+                        self.modified.push((a.0, a.1, b.1));
+                    }
+                    old.next();
+                    new.next();
+                }
+                (Some(a), Some(b)) if a.0 < b.0 => self.removed.extend(old.next()),
+                (Some(_), Some(_)) | (None, Some(_)) => self.added.extend(new.next()),
+                (Some(_), None) => self.removed.extend(old.next()),
+                (None, None) => break,
+            }
+        }
+    }
+}
+
+fn load_branch<S: Store<H256>>(
+    store: &S,
+    node: &H256,
+    is_leaf: bool,
+) -> Result<Option<BranchNode>, Box<dyn StdError>> {
+    if is_leaf || node.is_zero() {
+        return Ok(None);
+    }
+    Ok(store.get_branch(node)?)
+}
+
+/// Splits the subtree at `node` into its left and right halves at `height`,
+/// which must be no lower than the fork height of `branch`. Each half comes
+/// with a flag telling if it is a leaf.
+fn split_at(branch: &BranchNode, node: H256, height: u8) -> [(H256, bool); 2] {
+    if branch.fork_height == height {
+        let (left, right) = branch_children(branch);
+        [(left, height == 0), (right, height == 0)]
+    } else if branch.key.get_bit(height) {
+        [(H256::zero(), false), (node, false)]
+    } else {
+        [(node, false), (H256::zero(), false)]
+    }
+}
+
+fn diff_nodes<A: Store<H256>, B: Store<H256>>(
+    old_store: &A,
+    new_store: &B,
+    old: (H256, bool),
+    new: (H256, bool),
+    diff: &mut StateDiff,
+) -> Result<(), Box<dyn StdError>> {
+    if old.0 == new.0 {
+        return Ok(());
+    }
+    let old_branch = load_branch(old_store, &old.0, old.1)?;
+    let new_branch = load_branch(new_store, &new.0, new.1)?;
+    if let (Some(old_branch), Some(new_branch)) = (old_branch, new_branch) {
+        // Subtrees covering the same range of keys are compared half by half
+        let height = old_branch.fork_height.max(new_branch.fork_height);
+        if old_branch.key.parent_path(height) == new_branch.key.parent_path(height) {
+            let old_halves = split_at(&old_branch, old.0, height);
+            let new_halves = split_at(&new_branch, new.0, height);
+            for (old_half, new_half) in old_halves.iter().zip(new_halves.iter()) {
+                diff_nodes(old_store, new_store, *old_half, *new_half, diff)?;
+            }
+            return Ok(());
+        }
+    }
+    let old_leaves = collect_node_leaves(old_store, &old.0, old.1)?;
+    let new_leaves = collect_node_leaves(new_store, &new.0, new.1)?;
+    diff.compare_leaves(old_leaves, new_leaves);
+    Ok(())
+}
+
+/// Computes keys changed from the tree at `old_root` in `old_store` to the
+/// tree at `new_root` in `new_store`. Subtrees with identical hashes are
+/// skipped without being visited.
+pub fn diff_states<A: Store<H256>, B: Store<H256>>(
+    old_store: &A,
+    old_root: &H256,
+    new_store: &B,
+    new_root: &H256,
+) -> Result<StateDiff, Box<dyn StdError>> {
+    let mut diff = StateDiff::default();
+    diff_nodes(
+        old_store,
+        new_store,
+        (*old_root, false),
+        (*new_root, false),
+        &mut diff,
+    )?;
+    diff.added.sort_unstable_by_key(|(k, _)| *k);
+    diff.removed.sort_unstable_by_key(|(k, _)| *k);
+    diff.modified.sort_unstable_by_key(|(k, _, _)| *k);
+    Ok(diff)
+}
+
+/// Same as `diff_states`, for two roots kept in the same store, such as a
+/// store that keeps nodes of previous versions.
+pub fn diff_roots<S: Store<H256>>(
+    store: &S,
+    old_root: &H256,
+    new_root: &H256,
+) -> Result<StateDiff, Box<dyn StdError>> {
+    diff_states(store, old_root, store, new_root)
+}
+
+impl<H: Hasher + Default, S: Store<H256> + ClearStore> CkbSimpleAccount<SmtState<S, H>> {
+    /// Computes keys changed from committed state of this account to the one
+    /// of `other`, pending transactions are not included.
+    pub fn diff<T: Store<H256> + ClearStore>(
+        &self,
+        other: &CkbSimpleAccount<SmtState<T, H>>,
+    ) -> Result<StateDiff, Box<dyn StdError>> {
+        diff_states(
+            self.state.store(),
+            self.state.root(),
+            other.state.store(),
+            other.state.root(),
+        )
+    }
+}
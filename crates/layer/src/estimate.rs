@@ -0,0 +1,280 @@
+use crate::{
+    backend::TransactionLayout, ckb::build_transaction, Config, RunProofResult, RunResult,
+};
+use bytes::Bytes;
+use ckb_types::{
+    packed::{CellOutput, OutPoint, WitnessArgs},
+    prelude::*,
+};
+use sparse_merkle_tree::{
+    traits::{Hasher, Store},
+    SparseMerkleTree, H256,
+};
+use std::error::Error as StdError;
+
+/// Validator cycles spent per unit of proof shape. The defaults are rough
+/// figures, for accurate estimates, run the validator offline against sample
+/// transactions, fit the measured cycles via `fit`, or the `calibrate`
+/// example, and load the resulting table via `from_table`.
+#[derive(Debug, PartialEq, Clone, Eq)]
+pub struct CycleCalibration {
+    /// Fixed cost of loading the script, cell data and starting the VM
+    pub base: u64,
+    /// Loading and parsing the witness
+    pub per_witness_byte: u64,
+    /// Recording a read value and hashing its leaf
+    pub per_read: u64,
+    /// Recording and organizing a write, plus hashing its old and new leaves
+    pub per_write: u64,
+    /// Merging proof nodes, the write old proof is walked twice, once for
+    /// verifying old values and once for computing the new root
+    pub per_proof_byte: u64,
+}
+
+impl Default for CycleCalibration {
+    fn default() -> Self {
+        CycleCalibration {
+            base: 1_500_000,
+            per_witness_byte: 2,
+            per_read: 12_000,
+            per_write: 40_000,
+            per_proof_byte: 400,
+        }
+    }
+}
+
+impl CycleCalibration {
+    /// Parses a table of `<name> <cycles>` lines, where names are the same
+    /// as field names. Empty lines and lines starting with `#` are ignored,
+    /// entries missing from the table keep their default values.
+    pub fn from_table(table: &str) -> Result<Self, Box<dyn StdError>> {
+        let mut calibration = CycleCalibration::default();
+        for line in table.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let mut parts = line.split_whitespace();
+            let (name, cycles) = match (parts.next(), parts.next(), parts.next()) {
+                (Some(name), Some(cycles), None) => (name, cycles.parse::<u64>()?),
+                _ => return Err(format!("Invalid calibration line: {}", line).into()),
+            };
+            let field = match name {
+                "base" => &mut calibration.base,
+                "per_witness_byte" => &mut calibration.per_witness_byte,
+                "per_read" => &mut calibration.per_read,
+                "per_write" => &mut calibration.per_write,
+                "per_proof_byte" => &mut calibration.per_proof_byte,
+                _ => return Err(format!("Unknown calibration entry: {}", name).into()),
+            };
+            *field = cycles;
+        }
+        Ok(calibration)
+    }
+
+    /// Formats the calibration as a table accepted by `from_table`
+    pub fn to_table(&self) -> String {
+        format!(
+            "base {}\nper_witness_byte {}\nper_read {}\nper_write {}\nper_proof_byte {}\n",
+            self.base, self.per_witness_byte, self.per_read, self.per_write, self.per_proof_byte
+        )
+    }
+
+    /// Fits a calibration to validator cycles measured offline via least
+    /// squares. Samples must vary enough to determine every entry, negative
+    /// fits are clamped to zero, fits beyond `u64` are rejected.
+    pub fn fit(samples: &[CalibrationSample]) -> Result<Self, Box<dyn StdError>> {
+        const N: usize = 5;
+        // Normal equations of the fit, with the right hand side appended
+        let mut matrix = [[0f64; N + 1]; N];
+        for sample in samples {
+            let x = sample.features();
+            for i in 0..N {
+                for j in 0..N {
+                    matrix[i][j] += x[i] * x[j];
+                }
+                matrix[i][N] += x[i] * sample.cycles as f64;
+            }
+        }
+        // Pivots are compared against the scale of the samples, so rounding
+        // errors of degenerate samples are not mistaken for a solution
+        let scale = (0..N).map(|i| matrix[i][i]).fold(1.0, f64::max);
+        for column in 0..N {
+            if (column..N).any(|row| !matrix[row][column].is_finite()) {
+                return Err("Calibration samples are out of range!".into());
+            }
+            let pivot = (column..N).fold(column, |pivot, row| {
+                if matrix[row][column].abs() > matrix[pivot][column].abs() {
+                    row
+                } else {
+                    pivot
+                }
+            });
+            if matrix[pivot][column].abs() < scale * 1e-9 {
+                return Err("Calibration samples do not determine all entries!".into());
+            }
+            matrix.swap(column, pivot);
+            let pivot_row = matrix[column];
+            for (row, values) in matrix.iter_mut().enumerate() {
+                if row != column {
+                    let factor = values[column] / pivot_row[column];
+                    for (value, pivot_value) in values.iter_mut().zip(pivot_row.iter()) {
+                        *value -= factor * pivot_value;
+                    }
+                }
+            }
+        }
+        let mut entries = [0u64; N];
+        for (i, entry) in entries.iter_mut().enumerate() {
+            let value = (matrix[i][N] / matrix[i][i]).round().max(0.0);
+            if !value.is_finite() || value >= std::u64::MAX as f64 {
+                return Err("Calibration samples are out of range!".into());
+            }
+            *entry = value as u64;
+        }
+        Ok(CycleCalibration {
+            base: entries[0],
+            per_witness_byte: entries[1],
+            per_read: entries[2],
+            per_write: entries[3],
+            per_proof_byte: entries[4],
+        })
+    }
+
+    /// Estimated cycles of `sample`, saturating at `u64::MAX`
+    fn cycles(&self, sample: &CalibrationSample) -> u64 {
+        let costs = [
+            (self.per_witness_byte, sample.witness_size),
+            (self.per_read, sample.reads),
+            (self.per_write, sample.writes),
+            (self.per_proof_byte, sample.proof_bytes),
+        ];
+        costs.iter().fold(self.base, |cycles, (cost, count)| {
+            cycles.saturating_add(cost.saturating_mul(*count as u64))
+        })
+    }
+}
+
+/// Proof shape of a transaction, together with cycles the validator spent on
+/// it besides running the program, as measured offline
+#[derive(Debug, PartialEq, Clone, Eq, Default)]
+pub struct CalibrationSample {
+    pub witness_size: usize,
+    pub reads: usize,
+    pub writes: usize,
+    /// Read proof plus twice the write old proof, see `per_proof_byte`
+    pub proof_bytes: usize,
+    pub cycles: u64,
+}
+
+impl CalibrationSample {
+    fn features(&self) -> [f64; 5] {
+        [
+            1.0,
+            self.witness_size as f64,
+            self.reads as f64,
+            self.writes as f64,
+            self.proof_bytes as f64,
+        ]
+    }
+}
+
+/// Sizes cover the transaction skeleton built by `CkbSimpleAccount::generate`
+/// only: the account cell input if any, the account cell output, the
+/// validator cell dep and the witness carrying the proof. Signatures in
+/// `WitnessArgs.lock`, inputs paying fees, change outputs and cell deps of
+/// lock scripts are added later by the wallet and are not included.
+#[derive(Debug, PartialEq, Clone, Eq, Default)]
+pub struct Estimate {
+    /// Size of the serialized WitnessArgs carrying the proof, with an empty
+    /// lock field
+    pub witness_size: usize,
+    /// Size of the transaction skeleton counted towards block size limits
+    pub transaction_size: usize,
+    /// Cycles the validator spends besides running the program in the VM
+    pub validator_cycles: u64,
+}
+
+impl RunProofResult {
+    /// Estimates the transaction updating the account cell to run `program`,
+    /// `last_cell` is the current account cell, if any. See `Estimate` for
+    /// what is covered. Fails like `generate` when there is no lock script to
+    /// use.
+    pub fn estimate(
+        &self,
+        config: &Config,
+        program: &Bytes,
+        last_cell: Option<&(OutPoint, CellOutput, Bytes)>,
+        calibration: &CycleCalibration,
+    ) -> Result<Estimate, Box<dyn StdError>> {
+        let (sample, transaction_size) = self.shape(config, program, last_cell)?;
+        Ok(Estimate {
+            witness_size: sample.witness_size,
+            transaction_size,
+            validator_cycles: calibration.cycles(&sample),
+        })
+    }
+
+    /// Pairs the proof shape of the transaction running `program` with
+    /// `cycles` measured by running the validator on it offline, to be used
+    /// with `CycleCalibration::fit`
+    pub fn calibration_sample(
+        &self,
+        config: &Config,
+        program: &Bytes,
+        last_cell: Option<&(OutPoint, CellOutput, Bytes)>,
+        cycles: u64,
+    ) -> Result<CalibrationSample, Box<dyn StdError>> {
+        let (sample, _) = self.shape(config, program, last_cell)?;
+        Ok(CalibrationSample { cycles, ..sample })
+    }
+
+    /// Returns the proof shape without cycles, and the transaction size
+    fn shape(
+        &self,
+        config: &Config,
+        program: &Bytes,
+        last_cell: Option<&(OutPoint, CellOutput, Bytes)>,
+    ) -> Result<(CalibrationSample, usize), Box<dyn StdError>> {
+        let output_data = Bytes::from(H256::zero().as_slice().to_vec());
+        let layout = TransactionLayout {
+            witness: self.serialize(program)?,
+            output_data,
+            cell_deps: vec![config.validator_cell_dep()],
+            ..Default::default()
+        };
+        let transaction = build_transaction(config, last_cell, layout)?;
+        let witness_size = transaction
+            .witnesses()
+            .get(0)
+            .and_then(|witness| WitnessArgs::from_slice(&witness.raw_data()).ok())
+            .ok_or("Witness is missing!")?
+            .as_slice()
+            .len();
+        // Transactions are prefixed with an offset in blocks
+        let transaction_size = transaction.as_slice().len() + 4;
+        let sample = CalibrationSample {
+            witness_size,
+            reads: self.read_values.len(),
+            writes: self.write_values.len(),
+            proof_bytes: self.read_proof.len() + self.write_old_proof.len() * 2,
+            cycles: 0,
+        };
+        Ok((sample, transaction_size))
+    }
+}
+
+impl RunResult {
+    /// Same as `RunProofResult::estimate`, with proofs generated from `tree`
+    pub fn estimate<H: Hasher + Default, S: Store<H256>>(
+        &self,
+        tree: &SparseMerkleTree<H, H256, S>,
+        config: &Config,
+        program: &Bytes,
+        last_cell: Option<&(OutPoint, CellOutput, Bytes)>,
+        calibration: &CycleCalibration,
+    ) -> Result<Estimate, Box<dyn StdError>> {
+        self.generate_proof(tree)?
+            .estimate(config, program, last_cell, calibration)
+    }
+}
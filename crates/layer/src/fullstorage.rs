@@ -0,0 +1,459 @@
+//! Fullstorage mode, following the cell layout of `c/fullstorage.h`.
+//!
+//! Only the off-chain side is provided. The C validator in `c/fullstorage.h`
+//! is still on hold and can't be built: it includes `core.h` and
+//! `validator_utils.h`, which are not part of this tree, the skeleton in
+//! `c/validator.h` only drives SMT validation, and `validate_changes` doesn't
+//! handle the shard splits done by `FullStorageState::apply`. Until it is
+//! brought back, nothing checks these transactions on chain, and
+//! `FullStorageAccount::advance` re-runs the program and compares the
+//! resulting cells instead.
+use crate::{
+    backend::{StateBackend, TransactionLayout},
+    ckb::CkbSimpleAccount,
+    run_default,
+    smt::CkbBlake2bHasher,
+    vm::ValueSource,
+    Config, RunResult,
+};
+use bytes::Bytes;
+use ckb_types::{
+    core::TransactionView,
+    packed::{CellOutput, OutPoint},
+    prelude::*,
+};
+use sparse_merkle_tree::{traits::Hasher, H256};
+use std::collections::{BTreeMap, HashMap};
+use std::error::Error as StdError;
+
+/// Identifier at the start of main cell data, see `c/fullstorage.h`
+pub const MAIN_CELL_IDENTIFIER: u64 = 0x4e49_414d;
+/// Identifier at the start of data cell data, see `c/fullstorage.h`
+pub const DATA_CELL_IDENTIFIER: u64 = 0x4154_4144;
+/// Shards holding more entries than this are split
+pub const DEFAULT_MAX_SHARD_ENTRIES: usize = 128;
+
+const MAIN_CELL_HEADER_BYTES: usize = 16;
+const SHARD_INFO_BYTES: usize = 64;
+const ENTRY_BYTES: usize = 64;
+
+fn data_hash(data: &[u8]) -> H256 {
+    let mut hasher = CkbBlake2bHasher::default();
+    hasher.write_bytes(data);
+    hasher.finish()
+}
+
+fn read_h256(data: &[u8]) -> H256 {
+    let mut buffer = [0u8; 32];
+    buffer.copy_from_slice(&data[0..32]);
+    buffer.into()
+}
+
+fn read_u32(data: &[u8]) -> u32 {
+    let mut buffer = [0u8; 4];
+    buffer.copy_from_slice(&data[0..4]);
+    u32::from_le_bytes(buffer)
+}
+
+fn read_u64(data: &[u8]) -> u64 {
+    let mut buffer = [0u8; 8];
+    buffer.copy_from_slice(&data[0..8]);
+    u64::from_le_bytes(buffer)
+}
+
+/// A range of fullstorage entries kept in a single data cell. Entries are sorted
+/// by key bytes, and no key is smaller than `prefix`.
+#[derive(Debug, PartialEq, Clone, Eq, Default)]
+pub struct Shard {
+    pub prefix: H256,
+    pub entries: Vec<(H256, H256)>,
+}
+
+impl Shard {
+    fn parse(prefix: H256, data: &[u8]) -> Result<Self, Box<dyn StdError>> {
+        if data.len() < 8
+            || read_u64(data) != DATA_CELL_IDENTIFIER
+            || !data[8..].chunks_exact(ENTRY_BYTES).remainder().is_empty()
+        {
+            return Err("Invalid data cell!".into());
+        }
+        let entries: Vec<(H256, H256)> = data[8..]
+            .chunks(ENTRY_BYTES)
+            .map(|entry| (read_h256(&entry[0..32]), read_h256(&entry[32..64])))
+            .collect();
+        let mut last = prefix.as_slice();
+        for (i, (key, _)) in entries.iter().enumerate() {
+            if (i == 0 && key.as_slice() < last) || (i > 0 && key.as_slice() <= last) {
+                return Err("Data cell entries are not in order!".into());
+            }
+            last = key.as_slice();
+        }
+        Ok(Shard { prefix, entries })
+    }
+
+    /// Data of the data cell: identifier | (key | value) * entries
+    pub fn data(&self) -> Bytes {
+        let mut buffer = Vec::with_capacity(8 + self.entries.len() * ENTRY_BYTES);
+        buffer.extend_from_slice(&DATA_CELL_IDENTIFIER.to_le_bytes()[..]);
+        for (key, value) in &self.entries {
+            buffer.extend_from_slice(key.as_slice());
+            buffer.extend_from_slice(value.as_slice());
+        }
+        buffer.into()
+    }
+
+    pub fn data_hash(&self) -> H256 {
+        data_hash(&self.data())
+    }
+
+    fn search(&self, key: &H256) -> Result<usize, usize> {
+        self.entries
+            .binary_search_by(|(k, _)| k.as_slice().cmp(key.as_slice()))
+    }
+}
+
+/// Result of applying changes to fullstorage state
+#[derive(Debug, PartialEq, Clone, Eq)]
+pub struct FullStorageUpdate {
+    pub state: FullStorageState,
+    /// Indices of shards in the old state that are changed
+    pub replaced: Vec<usize>,
+    /// For each shard in the new state, index of the same shard in the old
+    /// state if it is left untouched
+    pub origins: Vec<Option<usize>>,
+}
+
+/// Account state in fullstorage mode, where all entries are kept in sharded data
+/// cells, and a main cell records prefix and data hash of each shard. Unlike SMT
+/// mode, entries written with zero values are kept. Empty state has no shards.
+#[derive(Debug, PartialEq, Clone, Eq)]
+pub struct FullStorageState {
+    pub nonce: u32,
+    pub shards: Vec<Shard>,
+    pub max_shard_entries: usize,
+}
+
+impl Default for FullStorageState {
+    fn default() -> Self {
+        FullStorageState::new(DEFAULT_MAX_SHARD_ENTRIES)
+    }
+}
+
+impl FullStorageState {
+    pub fn new(max_shard_entries: usize) -> Self {
+        FullStorageState {
+            nonce: 0,
+            shards: Vec::new(),
+            max_shard_entries,
+        }
+    }
+
+    /// Rebuilds state from main cell data and data cells, data cells can be
+    /// provided in any order.
+    pub fn from_cells(
+        main_cell_data: &[u8],
+        data_cells: &[Bytes],
+        max_shard_entries: usize,
+    ) -> Result<Self, Box<dyn StdError>> {
+        if main_cell_data.len() < MAIN_CELL_HEADER_BYTES
+            || read_u64(main_cell_data) != MAIN_CELL_IDENTIFIER
+        {
+            return Err("Invalid main cell!".into());
+        }
+        let nonce = read_u32(&main_cell_data[8..]);
+        let count = read_u32(&main_cell_data[12..]) as usize;
+        if main_cell_data.len() != MAIN_CELL_HEADER_BYTES + count * SHARD_INFO_BYTES {
+            return Err("Invalid main cell length!".into());
+        }
+        let hashes: HashMap<H256, &Bytes> = data_cells.iter().map(|d| (data_hash(d), d)).collect();
+        let mut shards: Vec<Shard> = Vec::with_capacity(count);
+        for info in main_cell_data[MAIN_CELL_HEADER_BYTES..].chunks(SHARD_INFO_BYTES) {
+            let prefix = read_h256(&info[0..32]);
+            if let Some(last) = shards.last() {
+                if prefix.as_slice() <= last.prefix.as_slice() {
+                    return Err("Shards are not in order!".into());
+                }
+            }
+            let data = hashes
+                .get(&read_h256(&info[32..64]))
+                .ok_or("Data cell is missing!")?;
+            shards.push(Shard::parse(prefix, data)?);
+        }
+        Ok(FullStorageState {
+            nonce,
+            shards,
+            max_shard_entries,
+        })
+    }
+
+    pub fn get(&self, key: &H256) -> H256 {
+        let shard = match self.shards.get(self.shard_index(key)) {
+            Some(shard) => shard,
+            None => return H256::zero(),
+        };
+        match shard.search(key) {
+            Ok(i) => shard.entries[i].1,
+            Err(_) => H256::zero(),
+        }
+    }
+
+    /// A key belongs to the last shard whose prefix is no larger than the key
+    fn shard_index(&self, key: &H256) -> usize {
+        self.shards
+            .iter()
+            .rposition(|shard| shard.prefix.as_slice() <= key.as_slice())
+            .unwrap_or(0)
+    }
+
+    /// Data of the main cell: identifier | nonce | shard count |
+    /// (prefix | data hash) * shard count
+    pub fn main_cell_data(&self) -> Bytes {
+        let mut buffer =
+            Vec::with_capacity(MAIN_CELL_HEADER_BYTES + self.shards.len() * SHARD_INFO_BYTES);
+        buffer.extend_from_slice(&MAIN_CELL_IDENTIFIER.to_le_bytes()[..]);
+        buffer.extend_from_slice(&self.nonce.to_le_bytes()[..]);
+        buffer.extend_from_slice(&(self.shards.len() as u32).to_le_bytes()[..]);
+        for shard in &self.shards {
+            buffer.extend_from_slice(shard.prefix.as_slice());
+            buffer.extend_from_slice(shard.data_hash().as_slice());
+        }
+        buffer.into()
+    }
+
+    /// Applies changes to a copy of current state. Only shards containing
+    /// changed keys are rebuilt, a rebuilt shard is split into consecutive
+    /// shards when it holds more than `max_shard_entries` entries. The first
+    /// split shard keeps the original prefix, later ones use their first keys.
+    /// Changes to empty state go to new shards starting from zero prefix.
+    pub fn apply(&self, changes: &HashMap<H256, H256>) -> FullStorageUpdate {
+        let mut grouped: BTreeMap<usize, Vec<(&H256, &H256)>> = BTreeMap::new();
+        for (key, value) in changes {
+            grouped
+                .entry(self.shard_index(key))
+                .or_default()
+                .push((key, value));
+        }
+        let max_shard_entries = self.max_shard_entries.max(1);
+        let empty = [Shard::default()];
+        let old_shards = if self.shards.is_empty() && !changes.is_empty() {
+            &empty[..]
+        } else {
+            &self.shards[..]
+        };
+        let mut shards = Vec::with_capacity(old_shards.len());
+        let mut replaced = Vec::new();
+        let mut origins = Vec::with_capacity(old_shards.len());
+        for (i, shard) in old_shards.iter().enumerate() {
+            let shard_changes = match grouped.get(&i) {
+                Some(shard_changes) => shard_changes,
+                None => {
+                    shards.push(shard.clone());
+                    origins.push(Some(i));
+                    continue;
+                }
+            };
+            let mut merged = shard.clone();
+            for (key, value) in shard_changes {
+                match merged.search(key) {
+                    Ok(index) => merged.entries[index].1 = **value,
+                    Err(index) => merged.entries.insert(index, (**key, **value)),
+                }
+            }
+            if i < self.shards.len() {
+                replaced.push(i);
+            }
+            if merged.entries.len() <= max_shard_entries {
+                shards.push(merged);
+                origins.push(None);
+                continue;
+            }
+            for (j, entries) in merged.entries.chunks(max_shard_entries).enumerate() {
+                let prefix = if j == 0 { merged.prefix } else { entries[0].0 };
+                shards.push(Shard {
+                    prefix,
+                    entries: entries.to_vec(),
+                });
+                origins.push(None);
+            }
+        }
+        FullStorageUpdate {
+            state: FullStorageState {
+                nonce: self.nonce.wrapping_add(1),
+                shards,
+                max_shard_entries: self.max_shard_entries,
+            },
+            replaced,
+            origins,
+        }
+    }
+}
+
+impl ValueSource for FullStorageState {
+    fn get_value(&self, key: &H256) -> Result<H256, Box<dyn StdError>> {
+        Ok(self.get(key))
+    }
+}
+
+/// Fullstorage state together with its data cells. As a `StateBackend`, the
+/// program is kept as is in the witness of the main cell, which is the account
+/// cell. Transactions consume changed data cells and create data cells of
+/// changed shards, unchanged data cells are left untouched.
+#[derive(Debug, PartialEq, Clone, Eq, Default)]
+pub struct FullStorage {
+    pub state: FullStorageState,
+    /// Current data cells, in the same order as shards in state
+    pub data_cells: Vec<(OutPoint, CellOutput)>,
+}
+
+/// An account in fullstorage mode
+pub type FullStorageAccount = CkbSimpleAccount<FullStorage>;
+
+impl FullStorage {
+    pub fn new(max_shard_entries: usize) -> Self {
+        FullStorage {
+            state: FullStorageState::new(max_shard_entries),
+            data_cells: Vec::new(),
+        }
+    }
+
+    /// Rebuilds state from main cell data and live data cells, data cells can
+    /// be provided in any order.
+    pub fn from_cells(
+        main_cell_data: &[u8],
+        data_cells: Vec<(OutPoint, CellOutput, Bytes)>,
+        max_shard_entries: usize,
+    ) -> Result<Self, Box<dyn StdError>> {
+        let data: Vec<Bytes> = data_cells.iter().map(|(_, _, d)| d.clone()).collect();
+        let state = FullStorageState::from_cells(main_cell_data, &data, max_shard_entries)?;
+        let mut cells: HashMap<H256, (OutPoint, CellOutput)> = data_cells
+            .into_iter()
+            .map(|(op, output, data)| (data_hash(&data), (op, output)))
+            .collect();
+        // Data cells of listed shards are checked in `FullStorageState::from_cells`
+        let data_cells = state
+            .shards
+            .iter()
+            .filter_map(|shard| cells.remove(&shard.data_hash()))
+            .collect();
+        Ok(FullStorage { state, data_cells })
+    }
+
+    pub fn run(&self, config: &Config, program: &Bytes) -> Result<RunResult, Box<dyn StdError>> {
+        run_default(config, &self.state, program, None)
+    }
+}
+
+impl StateBackend for FullStorage {
+    /// State and data cells after the changes
+    type Changes = FullStorage;
+    /// State and data cells before the changes
+    type Undo = FullStorage;
+
+    fn commitment(&self) -> Bytes {
+        self.state.main_cell_data()
+    }
+
+    fn generate(
+        &self,
+        config: &Config,
+        pending: &[&FullStorage],
+        program: &Bytes,
+    ) -> Result<TransactionLayout, Box<dyn StdError>> {
+        let tip = pending.last().cloned().unwrap_or(self);
+        let result = tip.run(config, program)?;
+        let update = tip.state.apply(&result.write_values);
+        let extra_inputs = update
+            .replaced
+            .iter()
+            .filter_map(|i| tip.data_cells.get(*i))
+            .map(|(out_point, _)| out_point.clone())
+            .collect();
+        // Unchanged shards only need data cells when they are not created yet
+        let extra_outputs = update
+            .state
+            .shards
+            .iter()
+            .zip(update.origins.iter())
+            .filter(|(_, origin)| origin.and_then(|i| tip.data_cells.get(i)).is_none())
+            .map(|(shard, _)| shard.data())
+            .collect();
+        Ok(TransactionLayout {
+            witness: program.clone(),
+            output_data: update.state.main_cell_data(),
+            extra_outputs,
+            extra_inputs,
+            cell_deps: vec![config.validator_cell_dep()],
+        })
+    }
+
+    fn verify(
+        &self,
+        config: &Config,
+        pending: &[&FullStorage],
+        witness: &Bytes,
+        output_data: &Bytes,
+        transaction: &TransactionView,
+    ) -> Result<FullStorage, Box<dyn StdError>> {
+        let tip = pending.last().cloned().unwrap_or(self);
+        let result = tip.run(config, witness)?;
+        let update = tip.state.apply(&result.write_values);
+        if output_data != &update.state.main_cell_data() {
+            return Err("Invalid main cell data!".into());
+        }
+        let inputs: Vec<OutPoint> = transaction.input_pts_iter().collect();
+        for (i, (out_point, _)) in tip.data_cells.iter().enumerate() {
+            let consumed = inputs.contains(out_point);
+            if update.replaced.contains(&i) {
+                if !consumed {
+                    return Err("Changed data cell is not consumed!".into());
+                }
+            } else if consumed {
+                // Unchanged shards keep their data cells, which must stay live
+                return Err("Unchanged data cell is consumed!".into());
+            }
+        }
+        let mut data_cells = Vec::with_capacity(update.state.shards.len());
+        for (shard, origin) in update.state.shards.iter().zip(update.origins.iter()) {
+            if let Some(cell) = origin.and_then(|i| tip.data_cells.get(i)) {
+                data_cells.push(cell.clone());
+                continue;
+            }
+            let hash = shard.data_hash();
+            let (index, output) = transaction
+                .outputs_with_data_iter()
+                .enumerate()
+                .find(|(_, (output, data))| {
+                    output.type_().to_opt().as_ref() == Some(&config.type_script)
+                        && data_hash(data) == hash
+                })
+                .map(|(index, (output, _))| (index, output))
+                .ok_or("Data cell is missing!")?;
+            let out_point = OutPoint::new_builder()
+                .tx_hash(transaction.hash())
+                .index((index as u32).pack())
+                .build();
+            data_cells.push((out_point, output));
+        }
+        Ok(FullStorage {
+            state: update.state,
+            data_cells,
+        })
+    }
+
+    fn is_account_cell(output_data: &[u8]) -> bool {
+        output_data.len() >= MAIN_CELL_HEADER_BYTES && read_u64(output_data) == MAIN_CELL_IDENTIFIER
+    }
+
+    fn destroy(&self) -> FullStorage {
+        FullStorage::new(self.state.max_shard_entries)
+    }
+
+    fn apply(&mut self, changes: FullStorage) -> Result<FullStorage, Box<dyn StdError>> {
+        Ok(std::mem::replace(self, changes))
+    }
+
+    fn revert(&mut self, undo: FullStorage) -> Result<(), Box<dyn StdError>> {
+        *self = undo;
+        Ok(())
+    }
+}
@@ -0,0 +1,96 @@
+//! Key derivation for structured storage on top of flat 32-byte keys. The same
+//! scheme is implemented in `c/keys.h`, so querying code and on-chain VMs derive
+//! identical keys:
+//!
+//! * namespace: blake2b(0x00 | name)
+//! * mapping slot: blake2b(0x01 | base | key)
+//! * array index: blake2b(0x02 | base | index as 64-bit little endian)
+//! * value chunk: blake2b(0x03 | base | index as 64-bit little endian)
+//! * stored code: blake2b(0x04 | code hash), where code hash is blake2b(code)
+//!
+//! where blake2b is the personalized hash used by `CkbBlake2bHasher`. Integer
+//! values are stored in little endian, padded with zeros to 32 bytes.
+use crate::smt::CkbBlake2bHasher;
+use sparse_merkle_tree::{traits::Hasher, H256};
+
+const NAMESPACE_TAG: u8 = 0;
+const MAPPING_TAG: u8 = 1;
+const ARRAY_TAG: u8 = 2;
+const CHUNK_TAG: u8 = 3;
+const CODE_TAG: u8 = 4;
+
+fn derive(tag: u8, parts: &[&[u8]]) -> H256 {
+    let mut hasher = CkbBlake2bHasher::default();
+    hasher.write_bytes(&[tag]);
+    for part in parts {
+        hasher.write_bytes(part);
+    }
+    hasher.finish()
+}
+
+/// Derives the base key of a named storage area, such as a contract field
+pub fn namespace_key(name: &[u8]) -> H256 {
+    derive(NAMESPACE_TAG, &[name])
+}
+
+/// Derives the key holding the value of `key` in the mapping at `base`
+pub fn mapping_key(base: &H256, key: &[u8]) -> H256 {
+    derive(MAPPING_TAG, &[base.as_slice(), key])
+}
+
+/// Derives the key holding element `index` of the array at `base`, the array
+/// length can be kept at `base` itself.
+pub fn array_key(base: &H256, index: u64) -> H256 {
+    derive(ARRAY_TAG, &[base.as_slice(), &index.to_le_bytes()[..]])
+}
+
+/// Derives the key holding chunk `index` of a variable length value stored
+/// at `base`, see `store_bytes`.
+pub fn chunk_key(base: &H256, index: u64) -> H256 {
+    derive(CHUNK_TAG, &[base.as_slice(), &index.to_le_bytes()[..]])
+}
+
+pub fn code_hash(code: &[u8]) -> H256 {
+    let mut hasher = CkbBlake2bHasher::default();
+    hasher.write_bytes(code);
+    hasher.finish()
+}
+
+/// Derives the key where code with `code_hash` is stored, see `store_bytes`
+pub fn code_key(code_hash: &H256) -> H256 {
+    derive(CODE_TAG, &[code_hash.as_slice()])
+}
+
+pub fn u64_to_value(value: u64) -> H256 {
+    let mut buffer = [0u8; 32];
+    buffer[0..8].copy_from_slice(&value.to_le_bytes()[..]);
+    buffer.into()
+}
+
+/// Returns None if the value does not fit in 64 bits
+pub fn value_to_u64(value: &H256) -> Option<u64> {
+    let data = value.as_slice();
+    if data[8..].iter().any(|b| *b != 0) {
+        return None;
+    }
+    let mut buffer = [0u8; 8];
+    buffer.copy_from_slice(&data[0..8]);
+    Some(u64::from_le_bytes(buffer))
+}
+
+pub fn u128_to_value(value: u128) -> H256 {
+    let mut buffer = [0u8; 32];
+    buffer[0..16].copy_from_slice(&value.to_le_bytes()[..]);
+    buffer.into()
+}
+
+/// Returns None if the value does not fit in 128 bits
+pub fn value_to_u128(value: &H256) -> Option<u128> {
+    let data = value.as_slice();
+    if data[16..].iter().any(|b| *b != 0) {
+        return None;
+    }
+    let mut buffer = [0u8; 16];
+    buffer.copy_from_slice(&data[0..16]);
+    Some(u128::from_le_bytes(buffer))
+}
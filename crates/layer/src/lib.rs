@@ -1,25 +1,72 @@
 #[macro_use]
 extern crate derive_more;
 
+mod backend;
+mod chain;
+mod chunks;
 mod ckb;
+mod code;
+mod diff;
+mod estimate;
+mod fullstorage;
+mod keys;
+mod machine;
+mod parallel;
+mod registry;
+mod shared;
 mod smt;
+mod snapshot;
+mod store;
 mod vm;
 
-pub use ckb::CkbSimpleAccount;
-pub use smt::{CkbBlake2bHasher, ClearStore};
+pub use backend::{
+    SmtAccount, SmtChanges, SmtState, SmtUndo, SnapshotBackend, StateBackend, TransactionLayout,
+};
+pub use chain::{Block, ChainFollower, ChainSource, FollowEvent, MockChain};
+pub use chunks::{load_bytes, store_bytes, MAX_VALUE_LENGTH};
+pub use ckb::{CkbSimpleAccount, PendingTransaction, RestoreReport};
+pub use code::{call_program, deploy_program, CALL_MAGIC, DEPLOY_MAGIC};
+pub use diff::{diff_roots, diff_states, StateDiff};
+pub use estimate::{CalibrationSample, CycleCalibration, Estimate};
+pub use fullstorage::{
+    FullStorage, FullStorageAccount, FullStorageState, FullStorageUpdate, Shard,
+    DATA_CELL_IDENTIFIER, DEFAULT_MAX_SHARD_ENTRIES, MAIN_CELL_IDENTIFIER,
+};
+pub use keys::{
+    array_key, chunk_key, code_hash, code_key, mapping_key, namespace_key, u128_to_value,
+    u64_to_value, value_to_u128, value_to_u64,
+};
+#[cfg(feature = "asm")]
+pub use machine::Asm;
+pub use machine::{DefaultGeneratorMachine, GeneratorMachine, Interpreter};
+pub use parallel::run_parallel;
+pub use registry::AccountRegistry;
+pub use shared::{AccountSnapshot, SharedAccount};
+pub use smt::{
+    CkbBlake2bHasher, ClearStore, IterableStore, SnapshotStore, StoreChanges, StoreTransaction,
+};
+pub use snapshot::StateSnapshot;
+pub use store::{KeyValueBackend, KeyValuePairs, MemoryBackend, PrefixedStore};
 
 use crate::{
-    smt::{generate_proof, Proof, WrappedStore},
-    vm::{ExtraSyscalls, TreeSyscalls},
+    smt::{generate_proof, Proof},
+    vm::{CycleSyscalls, ExtraSyscalls, ProgramSyscalls, TreeSyscalls, ValueSource},
 };
 use bytes::Bytes;
-use ckb_types::packed::{Byte32, OutPoint, Script};
+use ckb_types::{
+    core::DepType,
+    packed::{Byte32, CellDep, OutPoint, Script},
+    prelude::*,
+};
 use ckb_vm::{
-    machine::asm::{AsmCoreMachine, AsmMachine},
-    DefaultMachineBuilder, Error as VMError, SupportMachine,
+    instructions::cost_model::instruction_cycles, DefaultMachineBuilder, Error as VMError,
+    SupportMachine, RISCV_MAX_MEMORY,
+};
+use sparse_merkle_tree::{
+    traits::{Hasher, Store},
+    SparseMerkleTree, H256,
 };
-use sparse_merkle_tree::{traits::Store, SparseMerkleTree, H256};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::error::Error as StdError;
 
 #[derive(Debug, PartialEq, Clone, Eq, Display)]
@@ -28,6 +75,16 @@ pub enum Error {
     InvalidResponseCode(i8),
     #[display(fmt = "invalid transaction {:#x}: {}", "_0", "_1")]
     InvalidTransaction(Byte32, String),
+    #[display(fmt = "cannot restore account: {}", "_0")]
+    Restore(RestoreReport),
+    #[display(fmt = "program of {} bytes exceeds the limit of {} bytes", "_0", "_1")]
+    ProgramTooLong(usize, usize),
+    #[display(
+        fmt = "{} bytes of VM memory are required, only {} are available",
+        "_0",
+        "_1"
+    )]
+    InsufficientMemory(usize, usize),
     #[display(fmt = "other error: {}", "_0")]
     Other(String),
 }
@@ -52,19 +109,122 @@ pub struct Config {
     pub lock_script: Option<Script>,
     /// Initial capacity used to create the first cell
     pub capacity: u64,
+    /// Maximum length of programs. When `load_program_via_syscall` is set, this
+    /// is further capped at `MAX_PROGRAM_SIZE`.
+    pub max_program_length: Option<usize>,
+    /// Lets the generator load the program via syscall instead of passing it
+    /// in argv, which is kept on the VM stack and limits program size.
+    pub load_program_via_syscall: bool,
+    /// Runs the generator with the interpreter even when the `asm` feature is
+    /// enabled, such as on hosts where the asm machine is not supported. This
+    /// applies to `run` and accounts, while `run_with_context` and
+    /// `run_with_machine` use the machine picked by their type parameters.
+    pub use_interpreter: bool,
+    /// Cycle limit of the generator, `None` means unlimited. Set this to the
+    /// cycle limit of the validator to reject programs that would run out of
+    /// cycles on chain.
+    pub max_cycles: Option<u64>,
+}
+
+impl Config {
+    /// Cell dep of the validator, required by all account transactions
+    pub fn validator_cell_dep(&self) -> CellDep {
+        CellDep::new_builder()
+            .out_point(self.validator_outpoint.clone())
+            .dep_type(DepType::Code.into())
+            .build()
+    }
+}
+
+/// Size of the buffer programs loaded via syscall are kept in, which is
+/// `CSAL_MAX_PROGRAM_SIZE` in `c/generator.h`
+pub const MAX_PROGRAM_SIZE: usize = 256 * 1024;
+
+/// Stack reserved for the generator besides its arguments
+const GENERATOR_STACK_SIZE: usize = 64 * 1024;
+
+fn read_elf_u16(elf: &[u8], offset: usize) -> Option<u64> {
+    let mut buffer = [0u8; 2];
+    buffer.copy_from_slice(elf.get(offset..offset + 2)?);
+    Some(u16::from_le_bytes(buffer).into())
+}
+
+fn read_elf_u64(elf: &[u8], offset: usize) -> Option<u64> {
+    let mut buffer = [0u8; 8];
+    buffer.copy_from_slice(elf.get(offset..offset + 8)?);
+    Some(u64::from_le_bytes(buffer))
+}
+
+/// Returns the end address of all loadable segments in a 64-bit little endian
+/// ELF, which covers BSS as well.
+fn elf_memory_end(elf: &[u8]) -> Option<u64> {
+    if elf.len() < 64 || elf[0..4] != b"\x7fELF"[..] || elf[4] != 2 || elf[5] != 1 {
+        return None;
+    }
+    let header_offset = read_elf_u64(elf, 32)?;
+    let header_size = read_elf_u16(elf, 54)?;
+    let mut end = 0;
+    for i in 0..read_elf_u16(elf, 56)? {
+        let offset = header_offset.checked_add(i.checked_mul(header_size)?)? as usize;
+        // PT_LOAD
+        if elf.get(offset..offset + 4)? != [1, 0, 0, 0] {
+            continue;
+        }
+        let address = read_elf_u64(elf, offset + 16)?;
+        let size = read_elf_u64(elf, offset + 40)?;
+        end = end.max(address.checked_add(size)?);
+    }
+    Some(end)
+}
+
+/// Checks that `program` can be run by the generator with `config`, where
+/// `args` are the arguments passed to the generator. The generator's memory
+/// and its arguments, which are kept on the stack, must fit in CKB VM's memory
+/// together with `GENERATOR_STACK_SIZE` bytes of stack.
+fn check_program(
+    config: &Config,
+    program: &Bytes,
+    args: &[Bytes],
+) -> Result<(), Box<dyn StdError>> {
+    let mut max_program_length = config
+        .max_program_length
+        .unwrap_or(std::u32::MAX as usize)
+        .min(std::u32::MAX as usize);
+    if config.load_program_via_syscall {
+        max_program_length = max_program_length.min(MAX_PROGRAM_SIZE);
+    }
+    if program.len() > max_program_length {
+        return Err(Error::ProgramTooLong(program.len(), max_program_length).into());
+    }
+    let generator_size =
+        elf_memory_end(&config.generator).ok_or("Generator is not a valid ELF!")? as usize;
+    // Argument strings with terminators, aligned to 16 bytes, followed by
+    // argc, argument pointers and a terminating null pointer
+    let strings_size: usize = args.iter().map(|arg| arg.len() + 1).sum();
+    let args_size = ((strings_size + 15) & !15) + 8 * (args.len() + 2);
+    let required = generator_size
+        .saturating_add(args_size)
+        .saturating_add(GENERATOR_STACK_SIZE);
+    if required > RISCV_MAX_MEMORY {
+        return Err(Error::InsufficientMemory(required, RISCV_MAX_MEMORY).into());
+    }
+    Ok(())
 }
 
 #[derive(Debug, PartialEq, Clone, Eq, Default)]
 pub struct RunResult {
+    /// Values of all keys read from the tree, zero if absent
     pub read_values: HashMap<H256, H256>,
+    /// Values written by the program, deleted keys have zero values
     pub write_values: HashMap<H256, H256>,
 }
 
 #[derive(Debug, PartialEq, Clone, Eq, Default)]
 pub struct RunProofResult {
-    /// Pairs of values in the old tree that is read by the program
+    /// Pairs of values in the old tree that is read by the program, including
+    /// zero values of keys that do not exist
     pub read_values: Vec<(H256, H256)>,
-    /// Proof of read_values
+    /// Proof of read_values, zero values are proven to be absent
     pub read_proof: Bytes,
     /// Tuple of values that is written by the program. Order of items is
     /// key, old value, new value
@@ -89,30 +249,84 @@ impl<Mac: SupportMachine> RunContext<Mac> for DefaultRunContext {
     }
 }
 
-pub fn run_with_context<S: Store<H256>, C: RunContext<Box<AsmCoreMachine>>>(
+pub fn run_with_context<
+    H: Hasher + Default,
+    S: Store<H256>,
+    C: RunContext<<DefaultGeneratorMachine as GeneratorMachine>::Core>,
+>(
+    config: &Config,
+    tree: &SparseMerkleTree<H, H256, S>,
+    program: &Bytes,
+    context: &mut C,
+) -> Result<RunResult, Box<dyn StdError>> {
+    run_with_machine::<DefaultGeneratorMachine, _, _, _>(config, tree, program, context)
+}
+
+/// Same as `run_with_context`, but runs the generator in machine `M`
+pub fn run_with_machine<
+    M: GeneratorMachine,
+    H: Hasher + Default,
+    S: Store<H256>,
+    C: RunContext<M::Core>,
+>(
+    config: &Config,
+    tree: &SparseMerkleTree<H, H256, S>,
+    program: &Bytes,
+    context: &mut C,
+) -> Result<RunResult, Box<dyn StdError>> {
+    run_internal::<M, _, _>(config, tree, program, context, None)
+}
+
+/// Runs the program with `DefaultRunContext` in the machine picked by
+/// `config.use_interpreter`, see `run_internal`.
+pub(crate) fn run_default<V: ValueSource>(
+    config: &Config,
+    tree: &V,
+    program: &Bytes,
+    read_keys: Option<&mut HashSet<H256>>,
+) -> Result<RunResult, Box<dyn StdError>> {
+    let mut context = DefaultRunContext {};
+    if config.use_interpreter {
+        run_internal::<Interpreter, _, _>(config, tree, program, &mut context, read_keys)
+    } else {
+        run_internal::<DefaultGeneratorMachine, _, _>(
+            config,
+            tree,
+            program,
+            &mut context,
+            read_keys,
+        )
+    }
+}
+
+/// Besides running the program, this also records in `read_keys` all keys
+/// looked up in the tree, including those holding empty values.
+pub(crate) fn run_internal<M: GeneratorMachine, V: ValueSource, C: RunContext<M::Core>>(
     config: &Config,
-    tree: &SparseMerkleTree<CkbBlake2bHasher, H256, S>,
+    tree: &V,
     program: &Bytes,
     context: &mut C,
+    read_keys: Option<&mut HashSet<H256>>,
 ) -> Result<RunResult, Box<dyn StdError>> {
+    let program_name = Bytes::from_static(b"generator");
+    let args = if config.load_program_via_syscall {
+        vec![program_name]
+    } else {
+        let program_length_bytes = (program.len() as u32).to_le_bytes()[..].to_vec();
+        let program_length = Bytes::from(program_length_bytes);
+        vec![program_name, program_length, program.clone()]
+    };
+    check_program(config, program, &args)?;
     let mut result = RunResult::default();
     {
-        let core_machine = Box::<AsmCoreMachine>::default();
-        let machine_builder = DefaultMachineBuilder::new(core_machine)
+        let max_cycles = config.max_cycles.unwrap_or(std::u64::MAX);
+        let machine_builder = DefaultMachineBuilder::new(M::core_machine(max_cycles))
+            .instruction_cycle_func(Box::new(instruction_cycles))
             .syscall(Box::new(ExtraSyscalls::new(context)))
-            .syscall(Box::new(TreeSyscalls {
-                tree,
-                result: &mut result,
-            }));
-        let mut machine = AsmMachine::new(machine_builder.build(), None);
-        let program_name = Bytes::from_static(b"generator");
-        let program_length_bytes = (program.len() as u32).to_le_bytes()[..].to_vec();
-        let program_length = Bytes::from(program_length_bytes);
-        machine.load_program(
-            &config.generator,
-            &[program_name, program_length, program.clone()],
-        )?;
-        let code = machine.run()?;
+            .syscall(Box::new(TreeSyscalls::new(tree, &mut result, read_keys)))
+            .syscall(Box::new(ProgramSyscalls { program }))
+            .syscall(Box::new(CycleSyscalls {}));
+        let code = M::execute(machine_builder.build(), &config.generator, &args)?;
         if code != 0 {
             return Err(Error::InvalidResponseCode(code).into());
         }
@@ -120,19 +334,18 @@ pub fn run_with_context<S: Store<H256>, C: RunContext<Box<AsmCoreMachine>>>(
     Ok(result)
 }
 
-pub fn run<S: Store<H256>>(
+pub fn run<H: Hasher + Default, S: Store<H256>>(
     config: &Config,
-    tree: &SparseMerkleTree<CkbBlake2bHasher, H256, S>,
+    tree: &SparseMerkleTree<H, H256, S>,
     program: &Bytes,
 ) -> Result<RunResult, Box<dyn StdError>> {
-    let mut ctx = DefaultRunContext {};
-    run_with_context(config, tree, program, &mut ctx)
+    run_default(config, tree, program, None)
 }
 
 impl RunResult {
-    pub fn generate_proof<S: Store<H256>>(
+    pub fn generate_proof<H: Hasher + Default, S: Store<H256>>(
         &self,
-        tree: &SparseMerkleTree<CkbBlake2bHasher, H256, S>,
+        tree: &SparseMerkleTree<H, H256, S>,
     ) -> Result<RunProofResult, Box<dyn StdError>> {
         let read_values = &self.read_values;
         let write_values = &self.write_values;
@@ -162,13 +375,13 @@ impl RunResult {
 
     // After this method returns successfully, the tree will be reverted to original value,
     // we only mark tree as mutable to make Rust happy.
-    pub fn committed_root_hash<S: Store<H256>>(
+    pub fn committed_root_hash<H: Hasher + Default, S: Store<H256>>(
         &self,
-        tree: &SparseMerkleTree<CkbBlake2bHasher, H256, S>,
+        tree: &SparseMerkleTree<H, H256, S>,
     ) -> Result<H256, Box<dyn StdError>> {
         let root_hash = *tree.root();
-        let temp_store = WrappedStore::new(tree.store());
-        let mut temp_tree: SparseMerkleTree<CkbBlake2bHasher, H256, WrappedStore<S>> =
+        let temp_store = StoreTransaction::new(tree.store());
+        let mut temp_tree: SparseMerkleTree<H, H256, StoreTransaction<S>> =
             SparseMerkleTree::new(root_hash, temp_store);
         for (key, value) in &self.write_values {
             temp_tree.update(*key, *value)?;
@@ -176,9 +389,9 @@ impl RunResult {
         Ok(*temp_tree.root())
     }
 
-    pub fn commit<S: Store<H256>>(
+    pub fn commit<H: Hasher + Default, S: Store<H256>>(
         &self,
-        tree: &mut SparseMerkleTree<CkbBlake2bHasher, H256, S>,
+        tree: &mut SparseMerkleTree<H, H256, S>,
     ) -> Result<(), Box<dyn StdError>> {
         for (key, value) in &self.write_values {
             tree.update(*key, *value)?;
@@ -0,0 +1,72 @@
+use bytes::Bytes;
+#[cfg(feature = "asm")]
+use ckb_vm::machine::asm::{AsmCoreMachine, AsmMachine};
+use ckb_vm::{
+    DefaultCoreMachine, DefaultMachine, Error as VMError, SparseMemory, SupportMachine, WXorXMemory,
+};
+
+/// Kind of VM the generator runs in. `RunContext` implementations that are
+/// generic over the machine work with all of them.
+pub trait GeneratorMachine {
+    type Core: SupportMachine;
+
+    fn core_machine(max_cycles: u64) -> Self::Core;
+
+    /// Loads `program` with `args` into `machine` and runs it till exit,
+    /// returning the exit code.
+    fn execute(
+        machine: DefaultMachine<'_, Self::Core>,
+        program: &Bytes,
+        args: &[Bytes],
+    ) -> Result<i8, VMError>;
+}
+
+/// Plain RISC-V interpreter, available on all platforms
+pub struct Interpreter;
+
+impl GeneratorMachine for Interpreter {
+    type Core = DefaultCoreMachine<u64, WXorXMemory<u64, SparseMemory<u64>>>;
+
+    fn core_machine(max_cycles: u64) -> Self::Core {
+        DefaultCoreMachine::new_with_max_cycles(max_cycles)
+    }
+
+    fn execute(
+        mut machine: DefaultMachine<'_, Self::Core>,
+        program: &Bytes,
+        args: &[Bytes],
+    ) -> Result<i8, VMError> {
+        machine.load_program(program, args)?;
+        machine.run()
+    }
+}
+
+/// Assembly based machine, only available with the `asm` feature on
+/// supported platforms
+#[cfg(feature = "asm")]
+pub struct Asm;
+
+#[cfg(feature = "asm")]
+impl GeneratorMachine for Asm {
+    type Core = Box<AsmCoreMachine>;
+
+    fn core_machine(max_cycles: u64) -> Self::Core {
+        AsmCoreMachine::new_with_max_cycles(max_cycles)
+    }
+
+    fn execute(
+        machine: DefaultMachine<'_, Self::Core>,
+        program: &Bytes,
+        args: &[Bytes],
+    ) -> Result<i8, VMError> {
+        let mut machine = AsmMachine::new(machine, None);
+        machine.load_program(program, args)?;
+        machine.run()
+    }
+}
+
+/// Machine used by `run` and `run_with_context`
+#[cfg(feature = "asm")]
+pub type DefaultGeneratorMachine = Asm;
+#[cfg(not(feature = "asm"))]
+pub type DefaultGeneratorMachine = Interpreter;
@@ -0,0 +1,82 @@
+use crate::{run, run_default, smt::StoreTransaction, Config, RunResult};
+use bytes::Bytes;
+use crossbeam_utils::thread;
+use sparse_merkle_tree::{
+    traits::{Hasher, Store},
+    SparseMerkleTree, H256,
+};
+use std::collections::HashSet;
+use std::error::Error as StdError;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Result of running a program in a worker thread, with all keys it read
+type Outcome = (Result<RunResult, String>, HashSet<H256>);
+
+/// Runs a batch of programs on `threads` threads, returning exactly the same
+/// results as running them one after another, where each program sees the
+/// writes of all programs before it.
+///
+/// All programs first run concurrently against the same `tree`. Results are
+/// then checked in order: a program that read a key written by an earlier
+/// program is executed again on top of all preceding writes, whether its first
+/// run succeeded or not. A program failing without such a conflict fails the
+/// whole batch, as it would when run sequentially. The returned results can be
+/// committed to `tree` in order.
+pub fn run_parallel<H: Hasher + Default + Sync, S: Store<H256> + Sync>(
+    config: &Config,
+    tree: &SparseMerkleTree<H, H256, S>,
+    programs: &[Bytes],
+    threads: usize,
+) -> Result<Vec<RunResult>, Box<dyn StdError>> {
+    let next = AtomicUsize::new(0);
+    let mut outcomes: Vec<Option<Outcome>> = vec![None; programs.len()];
+    thread::scope(|scope| {
+        let workers: Vec<_> = (0..threads.max(1).min(programs.len()))
+            .map(|_| {
+                scope.spawn(|_| {
+                    let mut outcomes = Vec::new();
+                    loop {
+                        let index = next.fetch_add(1, Ordering::SeqCst);
+                        if index >= programs.len() {
+                            break;
+                        }
+                        // Keys read before a failure are kept as well, since
+                        // the failure might be caused by a conflicting read.
+                        let mut read_keys = HashSet::default();
+                        let result =
+                            run_default(config, tree, &programs[index], Some(&mut read_keys))
+                                .map_err(|e| e.to_string());
+                        outcomes.push((index, (result, read_keys)));
+                    }
+                    outcomes
+                })
+            })
+            .collect();
+        for worker in workers {
+            // Programs from a panicked worker are left without outcomes
+            if let Ok(worker_outcomes) = worker.join() {
+                for (index, outcome) in worker_outcomes {
+                    outcomes[index] = Some(outcome);
+                }
+            }
+        }
+    })
+    .map_err(|_| "Worker thread panicked!")?;
+
+    let mut written_keys: HashSet<H256> = HashSet::default();
+    let mut overlay: SparseMerkleTree<H, H256, StoreTransaction<S>> =
+        SparseMerkleTree::new(*tree.root(), StoreTransaction::new(tree.store()));
+    let mut results = Vec::with_capacity(programs.len());
+    for (program, outcome) in programs.iter().zip(outcomes) {
+        let (result, read_keys) = outcome.ok_or("Worker thread panicked!")?;
+        let result = if read_keys.is_disjoint(&written_keys) {
+            result?
+        } else {
+            run(config, &overlay, program)?
+        };
+        result.commit(&mut overlay)?;
+        written_keys.extend(result.write_values.keys());
+        results.push(result);
+    }
+    Ok(results)
+}
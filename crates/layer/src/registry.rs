@@ -0,0 +1,208 @@
+use crate::{
+    backend::{SmtState, StateBackend},
+    ckb::CkbSimpleAccount,
+    smt::ClearStore,
+    store::{KeyValueBackend, PrefixedStore},
+    Config,
+};
+use ckb_types::{
+    packed::{Script, Transaction},
+    prelude::*,
+};
+use sparse_merkle_tree::{traits::Hasher, SparseMerkleTree, H256};
+use std::collections::HashMap;
+use std::error::Error as StdError;
+use std::sync::{Arc, RwLock};
+
+type AdvanceResult = Result<(), Box<dyn StdError>>;
+type StateBuilder<B> = Box<dyn Fn(&Script) -> B + Send + Sync>;
+
+/// A collection of accounts keyed by their type scripts, typically many
+/// accounts sharing the same validator but with different type script args.
+/// Incoming transactions are routed to the accounts they touch: an account is
+/// touched when its type script appears in the outputs, or when its last cell,
+/// or a cell created by one of its pending transactions, is consumed in the
+/// inputs.
+///
+/// Accounts can only be modified through the registry, so the index of their
+/// cells is kept up to date.
+pub struct AccountRegistry<B: StateBackend> {
+    accounts: HashMap<Vec<u8>, CkbSimpleAccount<B>>,
+    /// Account cells, including the ones of pending transactions, mapped to
+    /// type scripts of their accounts
+    cells: HashMap<Vec<u8>, Vec<u8>>,
+    new_state: Option<StateBuilder<B>>,
+}
+
+impl<B: StateBackend> Default for AccountRegistry<B> {
+    fn default() -> Self {
+        AccountRegistry {
+            accounts: HashMap::default(),
+            cells: HashMap::default(),
+            new_state: None,
+        }
+    }
+}
+
+impl<K, H> AccountRegistry<SmtState<PrefixedStore<K>, H>>
+where
+    K: KeyValueBackend + Send + Sync + 'static,
+    H: Hasher + Default,
+{
+    /// Creates a registry keeping the trees of all its accounts in `backend`,
+    /// each account uses the namespace derived from its type script, see
+    /// `PrefixedStore::for_script`.
+    pub fn with_shared_store(backend: Arc<RwLock<K>>) -> Self {
+        let new_state: StateBuilder<_> = Box::new(move |type_script: &Script| {
+            SparseMerkleTree::new(
+                H256::zero(),
+                PrefixedStore::for_script(Arc::clone(&backend), type_script),
+            )
+        });
+        AccountRegistry {
+            new_state: Some(new_state),
+            ..AccountRegistry::default()
+        }
+    }
+
+    /// Removes an account together with its data in the shared store
+    pub fn purge(&mut self, type_script: &Script) -> Result<bool, Box<dyn StdError>> {
+        match self.remove(type_script) {
+            Some(account) => {
+                account.state.take_store().clear_store()?;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+}
+
+impl<B: StateBackend> AccountRegistry<B> {
+    pub fn new() -> Self {
+        AccountRegistry::default()
+    }
+
+    /// Adds an empty account with state built by the registry, such as one
+    /// created via `with_shared_store`.
+    pub fn create(&mut self, config: Config) -> Result<(), Box<dyn StdError>> {
+        if self.contains(&config.type_script) {
+            return Err("Account already exists!".into());
+        }
+        let state = match &self.new_state {
+            Some(new_state) => new_state(&config.type_script),
+            None => return Err("Registry has no store to create accounts in!".into()),
+        };
+        self.insert(CkbSimpleAccount::empty_with_state(config, state));
+        Ok(())
+    }
+
+    /// Adds an account, replacing and returning the existing account with the
+    /// same type script if any.
+    pub fn insert(&mut self, account: CkbSimpleAccount<B>) -> Option<CkbSimpleAccount<B>> {
+        let key = account.config.type_script.as_slice().to_vec();
+        let old_account = self.remove_by_key(&key);
+        self.accounts.insert(key.clone(), account);
+        self.index(&key);
+        old_account
+    }
+
+    /// Removes an account. Notice the account's store is left untouched, the
+    /// caller is responsible for cleaning it up when needed, for accounts backed
+    /// by `PrefixedStore`, `purge` only deletes the account's namespace.
+    pub fn remove(&mut self, type_script: &Script) -> Option<CkbSimpleAccount<B>> {
+        self.remove_by_key(type_script.as_slice())
+    }
+
+    pub fn get(&self, type_script: &Script) -> Option<&CkbSimpleAccount<B>> {
+        self.accounts.get(type_script.as_slice())
+    }
+
+    /// Runs `f` with mutable access to an account, such as for queueing pending
+    /// transactions, the index of account cells is refreshed afterwards. `None`
+    /// is returned when the account doesn't exist.
+    pub fn update<F, R>(&mut self, type_script: &Script, f: F) -> Option<R>
+    where
+        F: FnOnce(&mut CkbSimpleAccount<B>) -> R,
+    {
+        let key = type_script.as_slice().to_vec();
+        let result = f(self.accounts.get_mut(&key)?);
+        self.index(&key);
+        Some(result)
+    }
+
+    pub fn contains(&self, type_script: &Script) -> bool {
+        self.accounts.contains_key(type_script.as_slice())
+    }
+
+    pub fn len(&self) -> usize {
+        self.accounts.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.accounts.is_empty()
+    }
+
+    pub fn accounts(&self) -> impl Iterator<Item = &CkbSimpleAccount<B>> {
+        self.accounts.values()
+    }
+
+    /// Returns type scripts of all accounts touched by the transaction
+    pub fn route(&self, transaction: &Transaction) -> Vec<Script> {
+        let view = transaction.clone().into_view();
+        let mut keys: Vec<&Vec<u8>> = Vec::new();
+        for output in view.outputs().into_iter() {
+            if let Some(type_script) = output.type_().to_opt() {
+                if let Some((key, _)) = self.accounts.get_key_value(type_script.as_slice()) {
+                    keys.push(key);
+                }
+            }
+        }
+        for op in view.input_pts_iter() {
+            if let Some(key) = self.cells.get(op.as_slice()) {
+                keys.push(key);
+            }
+        }
+        keys.sort();
+        keys.dedup();
+        keys.into_iter()
+            .map(|key| self.accounts[key].config.type_script.clone())
+            .collect()
+    }
+
+    /// Advances all accounts touched by the transaction. Accounts are independent
+    /// from each other, a failure in one account doesn't prevent others from
+    /// advancing, so the result of each touched account is returned.
+    pub fn advance(&mut self, transaction: &Transaction) -> Vec<(Script, AdvanceResult)> {
+        self.route(transaction)
+            .into_iter()
+            .map(|type_script| {
+                let result = self
+                    .update(&type_script, |account| account.advance(transaction))
+                    .unwrap();
+                (type_script, result)
+            })
+            .collect()
+    }
+
+    /// Re-indexes the cells of an account
+    fn index(&mut self, key: &[u8]) {
+        self.cells.retain(|_, k| k.as_slice() != key);
+        if let Some(account) = self.accounts.get(key) {
+            let cells = account.last_cell.iter().chain(
+                account
+                    .pending_transactions()
+                    .iter()
+                    .filter_map(|p| p.last_cell()),
+            );
+            for (op, _, _) in cells {
+                self.cells.insert(op.as_slice().to_vec(), key.to_vec());
+            }
+        }
+    }
+
+    fn remove_by_key(&mut self, key: &[u8]) -> Option<CkbSimpleAccount<B>> {
+        let account = self.accounts.remove(key)?;
+        self.index(key);
+        Some(account)
+    }
+}
@@ -0,0 +1,103 @@
+use crate::{
+    backend::{SnapshotBackend, StateBackend},
+    ckb::CkbSimpleAccount,
+};
+use ckb_types::packed::{Byte32, Transaction};
+use std::error::Error as StdError;
+use std::ops::Deref;
+use std::sync::{Arc, Mutex, RwLock, RwLockReadGuard};
+
+/// A thread safe handle to a `CkbSimpleAccount`, which can be cloned and shared
+/// between threads. Any number of readers can take snapshots and run queries
+/// against them, while writes are serialized. Snapshots are independent copies
+/// of the account, so they never hold up writes no matter how long they live.
+/// The expensive part of `advance`, namely running and verifying the program,
+/// only needs read access, so it runs alongside readers; the account is only
+/// locked exclusively for the short moment staged changes are written to the
+/// store.
+pub struct SharedAccount<B: StateBackend> {
+    account: Arc<RwLock<CkbSimpleAccount<B>>>,
+    writer: Arc<Mutex<()>>,
+}
+
+impl<B: StateBackend> Clone for SharedAccount<B> {
+    fn clone(&self) -> Self {
+        SharedAccount {
+            account: Arc::clone(&self.account),
+            writer: Arc::clone(&self.writer),
+        }
+    }
+}
+
+/// Read only view of an account, taken via `CkbSimpleAccount::snapshot`. The
+/// state, including the root hash, stays unchanged while the account moves on.
+pub struct AccountSnapshot<B: StateBackend> {
+    account: CkbSimpleAccount<B>,
+}
+
+impl<B: StateBackend> Deref for AccountSnapshot<B> {
+    type Target = CkbSimpleAccount<B>;
+
+    fn deref(&self) -> &CkbSimpleAccount<B> {
+        &self.account
+    }
+}
+
+impl<B: StateBackend> SharedAccount<B> {
+    pub fn new(account: CkbSimpleAccount<B>) -> Self {
+        SharedAccount {
+            account: Arc::new(RwLock::new(account)),
+            writer: Arc::new(Mutex::new(())),
+        }
+    }
+
+    fn read(&self) -> Result<RwLockReadGuard<'_, CkbSimpleAccount<B>>, Box<dyn StdError>> {
+        Ok(self
+            .account
+            .read()
+            .map_err(|_| "Account lock is poisoned!")?)
+    }
+
+    /// See `CkbSimpleAccount::advance`
+    pub fn advance(&self, transaction: &Transaction) -> Result<(), Box<dyn StdError>> {
+        let _writer = self.writer.lock().map_err(|_| "Writer lock is poisoned!")?;
+        let advance = self.read()?.prepare_advance(transaction)?;
+        let mut account = self
+            .account
+            .write()
+            .map_err(|_| "Account lock is poisoned!")?;
+        account.finish_advance(advance).map(|_| ())
+    }
+
+    /// See `CkbSimpleAccount::push_pending`
+    pub fn push_pending(&self, transaction: &Transaction) -> Result<(), Box<dyn StdError>> {
+        self.write(|account| account.push_pending(transaction))?
+    }
+
+    /// See `CkbSimpleAccount::drop_pending`
+    pub fn drop_pending(&self, hash: &Byte32) -> Result<Vec<Transaction>, Box<dyn StdError>> {
+        self.write(|account| account.drop_pending(hash))
+    }
+
+    /// Runs `f` with exclusive access to the account, serialized with all
+    /// other writes.
+    pub fn write<F, R>(&self, f: F) -> Result<R, Box<dyn StdError>>
+    where
+        F: FnOnce(&mut CkbSimpleAccount<B>) -> R,
+    {
+        let _writer = self.writer.lock().map_err(|_| "Writer lock is poisoned!")?;
+        let mut account = self
+            .account
+            .write()
+            .map_err(|_| "Account lock is poisoned!")?;
+        Ok(f(&mut account))
+    }
+}
+
+impl<B: SnapshotBackend> SharedAccount<B> {
+    /// Copies current account, the account is only locked while being copied
+    pub fn snapshot(&self) -> Result<AccountSnapshot<B>, Box<dyn StdError>> {
+        let account = self.read()?.snapshot()?;
+        Ok(AccountSnapshot { account })
+    }
+}
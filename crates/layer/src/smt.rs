@@ -1,6 +1,7 @@
 use blake2b_rs::{Blake2b, Blake2bBuilder};
 use bytes::Bytes;
 use sparse_merkle_tree::{
+    default_store::DefaultStore,
     error::Error as SMTError,
     traits::{Hasher, Store},
     tree::{BranchNode, LeafNode},
@@ -13,6 +14,38 @@ pub trait ClearStore {
     fn clear_store(&mut self) -> Result<(), Box<dyn StdError>>;
 }
 
+impl ClearStore for DefaultStore<H256> {
+    fn clear_store(&mut self) -> Result<(), Box<dyn StdError>> {
+        *self = DefaultStore::default();
+        Ok(())
+    }
+}
+
+/// Stores that can enumerate the leaves they hold. Returned leaves are in no
+/// particular order, and might include stale leaves no longer reachable from
+/// the current root.
+pub trait IterableStore {
+    fn leaf_nodes(&self) -> Result<Vec<LeafNode<H256>>, Box<dyn StdError>>;
+}
+
+impl IterableStore for DefaultStore<H256> {
+    fn leaf_nodes(&self) -> Result<Vec<LeafNode<H256>>, Box<dyn StdError>> {
+        Ok(self.leaves_map().values().cloned().collect())
+    }
+}
+
+/// Stores that can be copied into an independent store with the same content,
+/// modifications to either one are not visible in the other.
+pub trait SnapshotStore: Sized {
+    fn snapshot_store(&self) -> Result<Self, Box<dyn StdError>>;
+}
+
+impl SnapshotStore for DefaultStore<H256> {
+    fn snapshot_store(&self) -> Result<Self, Box<dyn StdError>> {
+        Ok(self.clone())
+    }
+}
+
 pub struct CkbBlake2bHasher(Blake2b);
 
 impl Default for CkbBlake2bHasher {
@@ -24,6 +57,12 @@ impl Default for CkbBlake2bHasher {
     }
 }
 
+impl CkbBlake2bHasher {
+    pub fn write_bytes(&mut self, data: &[u8]) {
+        self.0.update(data);
+    }
+}
+
 impl Hasher for CkbBlake2bHasher {
     fn write_h256(&mut self, h: &H256) {
         self.0.update(h.as_slice());
@@ -35,13 +74,117 @@ impl Hasher for CkbBlake2bHasher {
     }
 }
 
+/// Returns children of a branch node, in left, right order
+pub(crate) fn branch_children(branch: &BranchNode) -> (H256, H256) {
+    if branch.key.get_bit(branch.fork_height) {
+        (branch.sibling, branch.node)
+    } else {
+        (branch.node, branch.sibling)
+    }
+}
+
+/// Collects all non-empty leaves of the tree rooted at `root`, sorted by key.
+/// This walks the tree the same way `SparseMerkleTree::get` does: a node is
+/// a branch if it can be found as one in the store, otherwise it is a leaf,
+/// children of a branch at height 0 are always leaves.
+pub(crate) fn collect_leaves<S: Store<H256>>(
+    store: &S,
+    root: &H256,
+) -> Result<Vec<(H256, H256)>, Box<dyn StdError>> {
+    collect_node_leaves(store, root, false)
+}
+
+/// Same as `collect_leaves`, but starts from any node of the tree, `is_leaf`
+/// tells if `node` is known to be a leaf, as children of height 0 branches.
+pub(crate) fn collect_node_leaves<S: Store<H256>>(
+    store: &S,
+    node: &H256,
+    is_leaf: bool,
+) -> Result<Vec<(H256, H256)>, Box<dyn StdError>> {
+    let mut leaves = Vec::new();
+    let mut stack = vec![(*node, is_leaf)];
+    while let Some((node, is_leaf)) = stack.pop() {
+        if node.is_zero() {
+            continue;
+        }
+        if !is_leaf {
+            if let Some(branch) = store.get_branch(&node)? {
+                let (left, right) = branch_children(&branch);
+                stack.push((left, branch.fork_height == 0));
+                stack.push((right, branch.fork_height == 0));
+                continue;
+            }
+        }
+        let leaf = store.get_leaf(&node)?.ok_or("Leaf is missing!")?;
+        if !leaf.value.is_zero() {
+            leaves.push((leaf.key, leaf.value));
+        }
+    }
+    leaves.sort_unstable_by_key(|(k, _)| *k);
+    Ok(leaves)
+}
+
+/// Collects non-empty leaves of the tree rooted at `root` whose keys start
+/// with `prefix`, sorted by key bytes. Key bits are laid out in the tree from
+/// the bottom up, so a prefix fixes the lowest heights. Keys below a branch
+/// all share the bits above its fork height, a branch is only entered when
+/// those bits agree with `prefix`, and below the prefix bits only the
+/// matching child is followed.
+pub(crate) fn collect_prefix_leaves<S: Store<H256>>(
+    store: &S,
+    root: &H256,
+    prefix: &[u8],
+) -> Result<Vec<(H256, H256)>, Box<dyn StdError>> {
+    let prefix_bytes = prefix.len().min(32);
+    let prefix_bits = prefix_bytes * 8;
+    let mut padded = [0u8; 32];
+    padded[..prefix_bytes].copy_from_slice(&prefix[..prefix_bytes]);
+    let prefix_key: H256 = padded.into();
+    let agrees = |key: &H256, from: usize| {
+        (from..prefix_bits).all(|i| key.get_bit(i as u8) == prefix_key.get_bit(i as u8))
+    };
+
+    let mut leaves = Vec::new();
+    let mut stack = vec![(*root, false)];
+    while let Some((node, is_leaf)) = stack.pop() {
+        if node.is_zero() {
+            continue;
+        }
+        if !is_leaf {
+            if let Some(branch) = store.get_branch(&node)? {
+                let height = branch.fork_height as usize;
+                if !agrees(&branch.key, height + 1) {
+                    continue;
+                }
+                let (left, right) = branch_children(&branch);
+                let is_leaf = height == 0;
+                let free = height >= prefix_bits;
+                let go_right = prefix_key.get_bit(branch.fork_height);
+                if free || !go_right {
+                    stack.push((left, is_leaf));
+                }
+                if free || go_right {
+                    stack.push((right, is_leaf));
+                }
+                continue;
+            }
+        }
+        let leaf = store.get_leaf(&node)?.ok_or("Leaf is missing!")?;
+        if !leaf.value.is_zero() && leaf.key.as_slice().starts_with(prefix) {
+            leaves.push((leaf.key, leaf.value));
+        }
+    }
+    leaves.sort_unstable_by(|a, b| a.0.as_slice().cmp(b.0.as_slice()));
+    Ok(leaves)
+}
+
 pub(crate) struct Proof {
     pub(crate) pairs: Vec<(H256, H256)>,
     pub(crate) proof: Bytes,
 }
 
-pub(crate) fn generate_proof<S: Store<H256>>(
-    tree: &SparseMerkleTree<CkbBlake2bHasher, H256, S>,
+pub(crate) fn generate_proof<H: Hasher + Default, S: Store<H256>>(
+    tree: &SparseMerkleTree<H, H256, S>,
     values: &HashMap<H256, H256>,
 ) -> Result<Proof, Box<dyn StdError>> {
     let mut pairs: Vec<(H256, H256)> = values.iter().map(|(k, v)| (*k, *v)).collect();
@@ -55,63 +198,149 @@ pub(crate) fn generate_proof<S: Store<H256>>(
     Ok(Proof { pairs, proof })
 }
 
-pub(crate) struct WrappedStore<'a, S: Store<H256>> {
+/// A copy-on-write overlay on top of an existing store. All modifications
+/// are kept in memory, the underlying store is never touched until the
+/// transaction is committed via `commit_into`, dropping the transaction (or
+/// calling `discard`) simply throws all staged modifications away.
+pub struct StoreTransaction<'a, S: Store<H256>> {
     store: &'a S,
+    changes: StoreChanges,
+}
+
+/// Modifications staged in a `StoreTransaction`, detached from the store
+/// they were built upon.
+#[derive(Debug, Clone, Default)]
+pub struct StoreChanges {
     branches_map: HashMap<H256, BranchNode>,
     leaves_map: HashMap<H256, LeafNode<H256>>,
     deleted_branches: HashSet<H256>,
     deleted_leaves: HashSet<H256>,
 }
 
-impl<'a, S: Store<H256>> WrappedStore<'a, S> {
+impl StoreChanges {
+    pub fn is_empty(&self) -> bool {
+        self.branches_map.is_empty()
+            && self.leaves_map.is_empty()
+            && self.deleted_branches.is_empty()
+            && self.deleted_leaves.is_empty()
+    }
+
+    /// Stacks `other` on top of current changes, as if modifications in
+    /// `other` were performed after the ones already staged here.
+    pub fn merge(&mut self, other: StoreChanges) {
+        for node in other.deleted_branches {
+            self.branches_map.remove(&node);
+            self.deleted_branches.insert(node);
+        }
+        for leaf_hash in other.deleted_leaves {
+            self.leaves_map.remove(&leaf_hash);
+            self.deleted_leaves.insert(leaf_hash);
+        }
+        for (node, branch) in other.branches_map {
+            self.deleted_branches.remove(&node);
+            self.branches_map.insert(node, branch);
+        }
+        for (leaf_hash, leaf) in other.leaves_map {
+            self.deleted_leaves.remove(&leaf_hash);
+            self.leaves_map.insert(leaf_hash, leaf);
+        }
+    }
+
+    /// Applies all staged modifications to `store`.
+    pub fn commit_into<T: Store<H256>>(self, store: &mut T) -> Result<(), SMTError> {
+        for node in &self.deleted_branches {
+            store.remove_branch(node)?;
+        }
+        for leaf_hash in &self.deleted_leaves {
+            store.remove_leaf(leaf_hash)?;
+        }
+        for (node, branch) in self.branches_map {
+            store.insert_branch(node, branch)?;
+        }
+        for (leaf_hash, leaf) in self.leaves_map {
+            store.insert_leaf(leaf_hash, leaf)?;
+        }
+        Ok(())
+    }
+}
+
+impl<'a, S: Store<H256>> StoreTransaction<'a, S> {
     pub fn new(store: &'a S) -> Self {
-        WrappedStore {
+        StoreTransaction {
             store,
-            branches_map: HashMap::default(),
-            leaves_map: HashMap::default(),
-            deleted_branches: HashSet::default(),
-            deleted_leaves: HashSet::default(),
+            changes: StoreChanges::default(),
         }
     }
+
+    /// Resumes a transaction from previously staged modifications, which
+    /// must have been built upon the same content as `store`.
+    pub fn with_changes(store: &'a S, changes: StoreChanges) -> Self {
+        StoreTransaction { store, changes }
+    }
+
+    pub fn changes(&self) -> &StoreChanges {
+        &self.changes
+    }
+
+    /// Detaches staged modifications from the underlying store. This is
+    /// needed to commit the changes back to the very store the transaction
+    /// is built upon, since the transaction keeps it borrowed:
+    ///
+    /// ```ignore
+    /// let changes = transaction.into_changes();
+    /// changes.commit_into(&mut store)?;
+    /// ```
+    pub fn into_changes(self) -> StoreChanges {
+        self.changes
+    }
+
+    /// Applies all staged modifications to `store`, which is typically a
+    /// different store sharing the same content as the underlying one. Use
+    /// `into_changes` to commit to the underlying store itself.
+    pub fn commit_into<T: Store<H256>>(self, store: &mut T) -> Result<(), SMTError> {
+        self.changes.commit_into(store)
+    }
+
+    pub fn discard(self) {}
 }
 
-impl<'a, S: Store<H256>> Store<H256> for WrappedStore<'a, S> {
+impl<'a, S: Store<H256>> Store<H256> for StoreTransaction<'a, S> {
     fn get_branch(&self, node: &H256) -> Result<Option<BranchNode>, SMTError> {
-        if self.deleted_branches.contains(&node) {
+        if self.changes.deleted_branches.contains(node) {
             return Ok(None);
         }
-        match self.branches_map.get(node) {
+        match self.changes.branches_map.get(node) {
             Some(value) => Ok(Some(value.clone())),
             None => self.store.get_branch(node),
         }
     }
     fn get_leaf(&self, leaf_hash: &H256) -> Result<Option<LeafNode<H256>>, SMTError> {
-        if self.deleted_leaves.contains(&leaf_hash) {
+        if self.changes.deleted_leaves.contains(leaf_hash) {
             return Ok(None);
         }
-        match self.leaves_map.get(leaf_hash) {
+        match self.changes.leaves_map.get(leaf_hash) {
             Some(value) => Ok(Some(value.clone())),
             None => self.store.get_leaf(leaf_hash),
         }
     }
     fn insert_branch(&mut self, node: H256, branch: BranchNode) -> Result<(), SMTError> {
-        self.deleted_branches.remove(&node);
-        self.branches_map.insert(node, branch);
+        self.changes.deleted_branches.remove(&node);
+        self.changes.branches_map.insert(node, branch);
         Ok(())
     }
     fn insert_leaf(&mut self, leaf_hash: H256, leaf: LeafNode<H256>) -> Result<(), SMTError> {
-        self.deleted_leaves.remove(&leaf_hash);
-        self.leaves_map.insert(leaf_hash, leaf);
+        self.changes.deleted_leaves.remove(&leaf_hash);
+        self.changes.leaves_map.insert(leaf_hash, leaf);
         Ok(())
     }
     fn remove_branch(&mut self, node: &H256) -> Result<(), SMTError> {
-        self.deleted_branches.insert(*node);
-        self.branches_map.remove(node);
+        self.changes.deleted_branches.insert(*node);
+        self.changes.branches_map.remove(node);
         Ok(())
     }
     fn remove_leaf(&mut self, leaf_hash: &H256) -> Result<(), SMTError> {
-        self.deleted_leaves.insert(*leaf_hash);
-        self.leaves_map.remove(leaf_hash);
+        self.changes.deleted_leaves.insert(*leaf_hash);
+        self.changes.leaves_map.remove(leaf_hash);
         Ok(())
     }
 }
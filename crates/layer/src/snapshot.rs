@@ -0,0 +1,219 @@
+use crate::{
+    backend::SmtState,
+    ckb::CkbSimpleAccount,
+    smt::{collect_leaves, ClearStore, IterableStore},
+    Config,
+};
+use bytes::Bytes;
+use ckb_types::{
+    packed::{CellOutput, OutPoint},
+    prelude::*,
+};
+use sparse_merkle_tree::{
+    traits::{Hasher, Store},
+    SparseMerkleTree, H256,
+};
+use std::error::Error as StdError;
+
+const SNAPSHOT_MAGIC: &[u8] = b"CSALSNAP";
+const SNAPSHOT_VERSION: u32 = 1;
+
+/// Full committed state of an account: all leaves of the tree, together with
+/// the root hash and the last cell. Serialized format is:
+///
+/// magic | version | root hash | last cell flag | [out point | cell output |
+/// cell data] | leaf count | (key | value) * leaf count
+///
+/// where all integers are 32-bit unsigned little endian integers, out point,
+/// cell output and cell data are each prefixed with their lengths.
+#[derive(Debug, PartialEq, Clone, Eq, Default)]
+pub struct StateSnapshot {
+    pub root_hash: H256,
+    pub last_cell: Option<(OutPoint, CellOutput, Bytes)>,
+    /// Key value pairs of all non-empty leaves, sorted by key
+    pub leaves: Vec<(H256, H256)>,
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, size: usize) -> Result<&'a [u8], Box<dyn StdError>> {
+        if self.data.len() - self.offset < size {
+            return Err("Snapshot is truncated!".into());
+        }
+        let result = &self.data[self.offset..self.offset + size];
+        self.offset += size;
+        Ok(result)
+    }
+
+    fn uint32(&mut self) -> Result<u32, Box<dyn StdError>> {
+        let mut buffer = [0u8; 4];
+        buffer.copy_from_slice(self.bytes(4)?);
+        Ok(u32::from_le_bytes(buffer))
+    }
+
+    fn h256(&mut self) -> Result<H256, Box<dyn StdError>> {
+        let mut buffer = [0u8; 32];
+        buffer.copy_from_slice(self.bytes(32)?);
+        Ok(buffer.into())
+    }
+
+    fn sized_bytes(&mut self) -> Result<&'a [u8], Box<dyn StdError>> {
+        let size = self.uint32()? as usize;
+        self.bytes(size)
+    }
+}
+
+fn write_sized_bytes(buffer: &mut Vec<u8>, data: &[u8]) -> Result<(), Box<dyn StdError>> {
+    if data.len() > std::u32::MAX as usize {
+        return Err("Data is too long!".into());
+    }
+    buffer.extend_from_slice(&(data.len() as u32).to_le_bytes()[..]);
+    buffer.extend_from_slice(data);
+    Ok(())
+}
+
+impl StateSnapshot {
+    pub fn serialize(&self) -> Result<Bytes, Box<dyn StdError>> {
+        let mut buffer = Vec::new();
+        buffer.extend_from_slice(SNAPSHOT_MAGIC);
+        buffer.extend_from_slice(&SNAPSHOT_VERSION.to_le_bytes()[..]);
+        buffer.extend_from_slice(self.root_hash.as_slice());
+        match &self.last_cell {
+            Some((out_point, output, data)) => {
+                buffer.extend_from_slice(&1u32.to_le_bytes()[..]);
+                write_sized_bytes(&mut buffer, out_point.as_slice())?;
+                write_sized_bytes(&mut buffer, output.as_slice())?;
+                write_sized_bytes(&mut buffer, data)?;
+            }
+            None => buffer.extend_from_slice(&0u32.to_le_bytes()[..]),
+        }
+        if self.leaves.len() > std::u32::MAX as usize {
+            return Err("Too many leaves!".into());
+        }
+        buffer.extend_from_slice(&(self.leaves.len() as u32).to_le_bytes()[..]);
+        for (key, value) in &self.leaves {
+            buffer.extend_from_slice(key.as_slice());
+            buffer.extend_from_slice(value.as_slice());
+        }
+        Ok(buffer.into())
+    }
+
+    pub fn deserialize(data: &[u8]) -> Result<Self, Box<dyn StdError>> {
+        let mut reader = Reader { data, offset: 0 };
+        if reader.bytes(SNAPSHOT_MAGIC.len())? != SNAPSHOT_MAGIC {
+            return Err("Invalid snapshot magic!".into());
+        }
+        if reader.uint32()? != SNAPSHOT_VERSION {
+            return Err("Unsupported snapshot version!".into());
+        }
+        let root_hash = reader.h256()?;
+        let last_cell = match reader.uint32()? {
+            0 => None,
+            1 => {
+                let out_point = OutPoint::from_slice(reader.sized_bytes()?)
+                    .map_err(|_| "Invalid out point in snapshot!")?;
+                let output = CellOutput::from_slice(reader.sized_bytes()?)
+                    .map_err(|_| "Invalid cell output in snapshot!")?;
+                let data = Bytes::from(reader.sized_bytes()?.to_vec());
+                Some((out_point, output, data))
+            }
+            _ => return Err("Invalid last cell flag in snapshot!".into()),
+        };
+        let count = reader.uint32()? as usize;
+        // Count comes from untrusted data, check it against what's left before
+        // allocating anything.
+        if count.checked_mul(64) != Some(data.len() - reader.offset) {
+            return Err("Invalid leaf count in snapshot!".into());
+        }
+        let mut leaves = Vec::with_capacity(count);
+        for _ in 0..count {
+            let key = reader.h256()?;
+            let value = reader.h256()?;
+            leaves.push((key, value));
+        }
+        Ok(StateSnapshot {
+            root_hash,
+            last_cell,
+            leaves,
+        })
+    }
+}
+
+impl<H: Hasher + Default, S: Store<H256> + ClearStore> CkbSimpleAccount<SmtState<S, H>> {
+    /// Exports committed state of the account, pending transactions are not
+    /// included.
+    pub fn export_snapshot(&self) -> Result<StateSnapshot, Box<dyn StdError>> {
+        Ok(StateSnapshot {
+            root_hash: *self.state.root(),
+            last_cell: self.last_cell.clone(),
+            leaves: collect_leaves(self.state.store(), self.state.root())?,
+        })
+    }
+
+    /// Rebuilds an account from a snapshot using `store`, which must be empty.
+    /// The rebuilt root hash is checked against the one recorded in the snapshot,
+    /// as well as the last cell's type script and data.
+    pub fn import_snapshot_with_store(
+        config: Config,
+        store: S,
+        snapshot: &StateSnapshot,
+    ) -> Result<Self, Box<dyn StdError>>
+    where
+        S: IterableStore,
+    {
+        if !store.leaf_nodes()?.is_empty() {
+            return Err("Store to import snapshot into is not empty!".into());
+        }
+        let mut tree = SparseMerkleTree::new(H256::zero(), store);
+        for (key, value) in &snapshot.leaves {
+            tree.update(*key, *value)?;
+        }
+        if tree.root() != &snapshot.root_hash {
+            return Err("Rebuilt root hash does not match snapshot!".into());
+        }
+        verify_last_cell(&config, &snapshot.root_hash, snapshot.last_cell.as_ref())?;
+        Ok(match &snapshot.last_cell {
+            Some(last_cell) => CkbSimpleAccount::new(config, tree, last_cell.clone()),
+            None => CkbSimpleAccount::empty_with_state(config, tree),
+        })
+    }
+}
+
+impl<H: Hasher + Default, S: Store<H256> + ClearStore + IterableStore + Default>
+    CkbSimpleAccount<SmtState<S, H>>
+{
+    pub fn import_snapshot(
+        config: Config,
+        snapshot: &StateSnapshot,
+    ) -> Result<Self, Box<dyn StdError>> {
+        Self::import_snapshot_with_store(config, S::default(), snapshot)
+    }
+}
+
+/// Checks that `last_cell` is an account cell holding `root_hash`
+pub(crate) fn verify_last_cell(
+    config: &Config,
+    root_hash: &H256,
+    last_cell: Option<&(OutPoint, CellOutput, Bytes)>,
+) -> Result<(), Box<dyn StdError>> {
+    match last_cell {
+        Some((_, output, data)) => {
+            if output.type_().to_opt() != Some(config.type_script.clone()) {
+                return Err("Last cell has invalid type script!".into());
+            }
+            if data.len() != 32 || data != root_hash.as_slice() {
+                return Err("Last cell data does not match root hash!".into());
+            }
+        }
+        None => {
+            if !root_hash.is_zero() {
+                return Err("Non-empty state requires a last cell!".into());
+            }
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,253 @@
+use crate::smt::{ClearStore, IterableStore, SnapshotStore};
+use ckb_types::{packed::Script, prelude::*};
+use sparse_merkle_tree::{
+    error::Error as SMTError,
+    traits::Store,
+    tree::{BranchNode, LeafNode},
+    H256,
+};
+use std::collections::BTreeMap;
+use std::error::Error as StdError;
+use std::sync::{Arc, RwLock};
+
+pub type KeyValuePairs = Vec<(Vec<u8>, Vec<u8>)>;
+
+/// A byte oriented key value store, such as an embedded database, that can be
+/// shared by many accounts via `PrefixedStore`.
+pub trait KeyValueBackend {
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, SMTError>;
+    fn put(&mut self, key: &[u8], value: &[u8]) -> Result<(), SMTError>;
+    fn delete(&mut self, key: &[u8]) -> Result<(), SMTError>;
+    /// Deletes all keys starting with `prefix`
+    fn delete_prefix(&mut self, prefix: &[u8]) -> Result<(), SMTError>;
+    /// Returns all key value pairs whose keys start with `prefix`
+    fn scan_prefix(&self, prefix: &[u8]) -> Result<KeyValuePairs, SMTError>;
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct MemoryBackend {
+    map: BTreeMap<Vec<u8>, Vec<u8>>,
+}
+
+impl MemoryBackend {
+    pub fn new() -> Self {
+        MemoryBackend::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.map.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+}
+
+impl KeyValueBackend for MemoryBackend {
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, SMTError> {
+        Ok(self.map.get(key).cloned())
+    }
+
+    fn put(&mut self, key: &[u8], value: &[u8]) -> Result<(), SMTError> {
+        self.map.insert(key.to_vec(), value.to_vec());
+        Ok(())
+    }
+
+    fn delete(&mut self, key: &[u8]) -> Result<(), SMTError> {
+        self.map.remove(key);
+        Ok(())
+    }
+
+    fn delete_prefix(&mut self, prefix: &[u8]) -> Result<(), SMTError> {
+        let keys: Vec<Vec<u8>> = self
+            .map
+            .range(prefix.to_vec()..)
+            .take_while(|(k, _)| k.starts_with(prefix))
+            .map(|(k, _)| k.clone())
+            .collect();
+        for key in keys {
+            self.map.remove(&key);
+        }
+        Ok(())
+    }
+
+    fn scan_prefix(&self, prefix: &[u8]) -> Result<KeyValuePairs, SMTError> {
+        Ok(self
+            .map
+            .range(prefix.to_vec()..)
+            .take_while(|(k, _)| k.starts_with(prefix))
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect())
+    }
+}
+
+const BRANCH_TAG: u8 = b'b';
+const LEAF_TAG: u8 = b'l';
+
+/// A tree store keeping nodes in a shared backend, under a 32-byte namespace
+/// owned by a single account. Keys are laid out as:
+///
+/// namespace | tag | node hash
+///
+/// where tag distinguishes branches from leaves. `clear_store` only deletes
+/// keys in current namespace, leaving other accounts intact.
+pub struct PrefixedStore<B: KeyValueBackend> {
+    backend: Arc<RwLock<B>>,
+    namespace: [u8; 32],
+}
+
+impl<B: KeyValueBackend> PrefixedStore<B> {
+    pub fn new(backend: Arc<RwLock<B>>, namespace: [u8; 32]) -> Self {
+        PrefixedStore { backend, namespace }
+    }
+
+    /// Uses the hash of an account's type script as namespace
+    pub fn for_script(backend: Arc<RwLock<B>>, type_script: &Script) -> Self {
+        let mut namespace = [0u8; 32];
+        namespace.copy_from_slice(type_script.calc_script_hash().as_slice());
+        PrefixedStore::new(backend, namespace)
+    }
+
+    pub fn namespace(&self) -> &[u8; 32] {
+        &self.namespace
+    }
+
+    pub fn backend(&self) -> &Arc<RwLock<B>> {
+        &self.backend
+    }
+
+    fn key(&self, tag: u8, hash: &H256) -> Vec<u8> {
+        let mut key = Vec::with_capacity(65);
+        key.extend_from_slice(&self.namespace);
+        key.push(tag);
+        key.extend_from_slice(hash.as_slice());
+        key
+    }
+
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, SMTError> {
+        self.backend
+            .read()
+            .map_err(|_| SMTError::Store("Backend lock is poisoned!".to_string()))?
+            .get(key)
+    }
+
+    fn with_backend<F>(&self, f: F) -> Result<(), SMTError>
+    where
+        F: FnOnce(&mut B) -> Result<(), SMTError>,
+    {
+        let mut backend = self
+            .backend
+            .write()
+            .map_err(|_| SMTError::Store("Backend lock is poisoned!".to_string()))?;
+        f(&mut backend)
+    }
+}
+
+fn read_h256(data: &[u8]) -> H256 {
+    let mut buffer = [0u8; 32];
+    buffer.copy_from_slice(data);
+    buffer.into()
+}
+
+fn read_leaf(data: &[u8]) -> Result<LeafNode<H256>, SMTError> {
+    if data.len() != 64 {
+        return Err(SMTError::Store("Invalid leaf data!".to_string()));
+    }
+    Ok(LeafNode {
+        key: read_h256(&data[0..32]),
+        value: read_h256(&data[32..64]),
+    })
+}
+
+impl<B: KeyValueBackend> Store<H256> for PrefixedStore<B> {
+    fn get_branch(&self, node: &H256) -> Result<Option<BranchNode>, SMTError> {
+        match self.get(&self.key(BRANCH_TAG, node))? {
+            Some(data) => {
+                if data.len() != 97 {
+                    return Err(SMTError::Store("Invalid branch data!".to_string()));
+                }
+                Ok(Some(BranchNode {
+                    fork_height: data[0],
+                    key: read_h256(&data[1..33]),
+                    node: read_h256(&data[33..65]),
+                    sibling: read_h256(&data[65..97]),
+                }))
+            }
+            None => Ok(None),
+        }
+    }
+    fn get_leaf(&self, leaf_hash: &H256) -> Result<Option<LeafNode<H256>>, SMTError> {
+        match self.get(&self.key(LEAF_TAG, leaf_hash))? {
+            Some(data) => Ok(Some(read_leaf(&data)?)),
+            None => Ok(None),
+        }
+    }
+    fn insert_branch(&mut self, node: H256, branch: BranchNode) -> Result<(), SMTError> {
+        let mut data = Vec::with_capacity(97);
+        data.push(branch.fork_height);
+        data.extend_from_slice(branch.key.as_slice());
+        data.extend_from_slice(branch.node.as_slice());
+        data.extend_from_slice(branch.sibling.as_slice());
+        let key = self.key(BRANCH_TAG, &node);
+        self.with_backend(|backend| backend.put(&key, &data))
+    }
+    fn insert_leaf(&mut self, leaf_hash: H256, leaf: LeafNode<H256>) -> Result<(), SMTError> {
+        let mut data = Vec::with_capacity(64);
+        data.extend_from_slice(leaf.key.as_slice());
+        data.extend_from_slice(leaf.value.as_slice());
+        let key = self.key(LEAF_TAG, &leaf_hash);
+        self.with_backend(|backend| backend.put(&key, &data))
+    }
+    fn remove_branch(&mut self, node: &H256) -> Result<(), SMTError> {
+        let key = self.key(BRANCH_TAG, node);
+        self.with_backend(|backend| backend.delete(&key))
+    }
+    fn remove_leaf(&mut self, leaf_hash: &H256) -> Result<(), SMTError> {
+        let key = self.key(LEAF_TAG, leaf_hash);
+        self.with_backend(|backend| backend.delete(&key))
+    }
+}
+
+impl<B: KeyValueBackend> ClearStore for PrefixedStore<B> {
+    fn clear_store(&mut self) -> Result<(), Box<dyn StdError>> {
+        let namespace = self.namespace;
+        self.with_backend(|backend| backend.delete_prefix(&namespace))?;
+        Ok(())
+    }
+}
+
+impl<B: KeyValueBackend> IterableStore for PrefixedStore<B> {
+    fn leaf_nodes(&self) -> Result<Vec<LeafNode<H256>>, Box<dyn StdError>> {
+        let mut prefix = self.namespace.to_vec();
+        prefix.push(LEAF_TAG);
+        let pairs = self
+            .backend
+            .read()
+            .map_err(|_| SMTError::Store("Backend lock is poisoned!".to_string()))?
+            .scan_prefix(&prefix)?;
+        let mut leaves = Vec::with_capacity(pairs.len());
+        for (_, data) in pairs {
+            leaves.push(read_leaf(&data)?);
+        }
+        Ok(leaves)
+    }
+}
+
+impl<B: KeyValueBackend + Default> SnapshotStore for PrefixedStore<B> {
+    /// Copies current namespace into a new backend
+    fn snapshot_store(&self) -> Result<Self, Box<dyn StdError>> {
+        let pairs = self
+            .backend
+            .read()
+            .map_err(|_| SMTError::Store("Backend lock is poisoned!".to_string()))?
+            .scan_prefix(&self.namespace)?;
+        let mut backend = B::default();
+        for (key, value) in pairs {
+            backend.put(&key, &value)?;
+        }
+        Ok(PrefixedStore::new(
+            Arc::new(RwLock::new(backend)),
+            self.namespace,
+        ))
+    }
+}
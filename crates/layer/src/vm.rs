@@ -1,17 +1,167 @@
-use crate::{RunContext, RunResult};
+use crate::{
+    chunks::MAX_VALUE_LENGTH,
+    keys::{chunk_key, code_key, value_to_u64},
+    RunContext, RunResult,
+};
+use bytes::Bytes;
 use ckb_vm::{
-    registers::{A0, A1, A7},
+    registers::{A0, A1, A2, A3, A7},
     Error as VMError, Memory, Register, SupportMachine, Syscalls,
 };
 use sparse_merkle_tree::{
     traits::{Hasher, Store},
     SparseMerkleTree, H256,
 };
+use std::collections::{HashMap, HashSet};
+use std::error::Error as StdError;
 use std::marker::PhantomData;
 
-pub(crate) struct TreeSyscalls<'a, H: Hasher + Default, S: Store<H256>> {
-    pub(crate) tree: &'a SparseMerkleTree<H, H256, S>,
+/// Same as CSAL_ERROR_INSUFFICIENT_CAPACITY in C headers
+const ERROR_INSUFFICIENT_CAPACITY: i64 = -20;
+/// Same as CSAL_ERROR_VALUE_OVERFLOW in C headers
+const ERROR_VALUE_OVERFLOW: i64 = -40;
+/// Same as CSAL_ERROR_CODE_NOT_FOUND in C headers
+const ERROR_CODE_NOT_FOUND: i64 = -41;
+/// Same as CSAL_ERROR_CODE_TOO_LARGE in C headers
+const ERROR_CODE_TOO_LARGE: i64 = -42;
+/// Same as CSAL_ERROR_INVALID_SAVEPOINT in C headers
+const ERROR_INVALID_SAVEPOINT: i64 = -46;
+/// Same as CSAL_ERROR_VALUE_TOO_LONG in C headers
+const ERROR_VALUE_TOO_LONG: i64 = -47;
+/// Maximum depth of nested savepoints, same as CSAL_MAX_SAVEPOINTS in C headers
+const MAX_SAVEPOINTS: usize = 64;
+
+/// State that programs can read values from
+pub(crate) trait ValueSource {
+    fn get_value(&self, key: &H256) -> Result<H256, Box<dyn StdError>>;
+}
+
+impl<H: Hasher + Default, S: Store<H256>> ValueSource for SparseMerkleTree<H, H256, S> {
+    fn get_value(&self, key: &H256) -> Result<H256, Box<dyn StdError>> {
+        Ok(self.get(key)?)
+    }
+}
+
+pub(crate) struct TreeSyscalls<'a, V: ValueSource> {
+    pub(crate) tree: &'a V,
     pub(crate) result: &'a mut RunResult,
+    pub(crate) read_keys: Option<&'a mut HashSet<H256>>,
+    /// IDs of active savepoints together with values overwritten since each
+    /// of them, IDs follow the order savepoints are created in like
+    /// `c/validator.h`, so stale savepoints are rejected the same way
+    pub(crate) savepoints: Vec<(u64, HashMap<H256, Option<H256>>)>,
+    pub(crate) next_savepoint_id: u64,
+}
+
+impl<'a, V: ValueSource> TreeSyscalls<'a, V> {
+    pub(crate) fn new(
+        tree: &'a V,
+        result: &'a mut RunResult,
+        read_keys: Option<&'a mut HashSet<H256>>,
+    ) -> Self {
+        TreeSyscalls {
+            tree,
+            result,
+            read_keys,
+            savepoints: Vec::new(),
+            next_savepoint_id: 0,
+        }
+    }
+
+    /// Reads a value written by the program so far, or from the tree, in
+    /// which case the read is recorded for the proof
+    fn fetch(&mut self, key: H256) -> Result<H256, VMError> {
+        if let Some(value) = self.result.write_values.get(&key) {
+            return Ok(*value);
+        }
+        let value = self.tree.get_value(&key).map_err(|_| VMError::Unexpected)?;
+        if let Some(read_keys) = &mut self.read_keys {
+            read_keys.insert(key);
+        }
+        // Zero values are kept as well, so the read proof also proves that
+        // those keys are absent
+        self.result.read_values.insert(key, value);
+        Ok(value)
+    }
+
+    fn write(&mut self, key: H256, value: H256) {
+        let old_value = self.result.write_values.insert(key, value);
+        if let Some((_, savepoint)) = self.savepoints.last_mut() {
+            savepoint.entry(key).or_insert(old_value);
+        }
+    }
+
+    /// Creates a savepoint, returning its ID or an error code
+    fn savepoint(&mut self) -> Result<u64, i64> {
+        if self.savepoints.len() >= MAX_SAVEPOINTS {
+            return Err(ERROR_INSUFFICIENT_CAPACITY);
+        }
+        let id = self.next_savepoint_id;
+        self.next_savepoint_id += 1;
+        self.savepoints.push((id, HashMap::default()));
+        Ok(id)
+    }
+
+    /// Loads at most `capacity` bytes of code stored under `code_hash`, taking
+    /// the same steps as `_csal_load_code` in `c/code.h`, so the same values
+    /// are read. Returns the code, or an error code.
+    fn load_code(
+        &mut self,
+        code_hash: &H256,
+        capacity: u64,
+    ) -> Result<Result<Vec<u8>, i64>, VMError> {
+        let key = code_key(code_hash);
+        let length = match value_to_u64(&self.fetch(key)?) {
+            Some(length) if length > MAX_VALUE_LENGTH => return Ok(Err(ERROR_VALUE_TOO_LONG)),
+            Some(length) => length,
+            None => return Ok(Err(ERROR_VALUE_OVERFLOW)),
+        };
+        let loaded = length.min(capacity);
+        let mut code = Vec::with_capacity(loaded as usize);
+        for i in 0..(loaded + 31) >> 5 {
+            code.extend_from_slice(self.fetch(chunk_key(&key, i))?.as_slice());
+        }
+        code.truncate(loaded as usize);
+        if length == 0 {
+            return Ok(Err(ERROR_CODE_NOT_FOUND));
+        }
+        if length > capacity {
+            return Ok(Err(ERROR_CODE_TOO_LARGE));
+        }
+        Ok(Ok(code))
+    }
+
+    /// Returns the depth of active savepoint `id`
+    fn savepoint_depth(&self, id: u64) -> Option<usize> {
+        self.savepoints.iter().rposition(|(i, _)| *i == id)
+    }
+
+    /// Discards all writes since the savepoint at `depth`, which stays active
+    fn rollback(&mut self, depth: usize) {
+        let id = self.savepoints[depth].0;
+        for (_, savepoint) in self.savepoints.drain(depth..).rev() {
+            for (key, old_value) in savepoint {
+                match old_value {
+                    Some(value) => self.result.write_values.insert(key, value),
+                    None => self.result.write_values.remove(&key),
+                };
+            }
+        }
+        self.savepoints.push((id, HashMap::default()));
+    }
+
+    /// Drops the savepoint at `depth` while keeping the writes, they now
+    /// belong to the enclosing savepoint if any
+    fn release(&mut self, depth: usize) {
+        let released: Vec<_> = self.savepoints.drain(depth..).collect();
+        if let Some((_, parent)) = self.savepoints.last_mut() {
+            for (_, savepoint) in released {
+                for (key, old_value) in savepoint {
+                    parent.entry(key).or_insert(old_value);
+                }
+            }
+        }
+    }
 }
 
 fn load_h256<Mac: SupportMachine>(machine: &mut Mac, address: u64) -> Result<H256, VMError> {
@@ -33,9 +183,7 @@ fn store_data<Mac: SupportMachine>(
     machine.memory_mut().store_bytes(address, data.as_slice())
 }
 
-impl<'a, H: Hasher + Default, S: Store<H256>, Mac: SupportMachine> Syscalls<Mac>
-    for TreeSyscalls<'a, H, S>
-{
+impl<'a, V: ValueSource, Mac: SupportMachine> Syscalls<Mac> for TreeSyscalls<'a, V> {
     fn initialize(&mut self, _machine: &mut Mac) -> Result<(), VMError> {
         Ok(())
     }
@@ -49,7 +197,7 @@ impl<'a, H: Hasher + Default, S: Store<H256>, Mac: SupportMachine> Syscalls<Mac>
                 let key = load_h256(machine, key_address)?;
                 let value_address = machine.registers()[A1].to_u64();
                 let value = load_h256(machine, value_address)?;
-                self.result.write_values.insert(key, value);
+                self.write(key, value);
                 machine.set_register(A0, Mac::REG::from_u64(0));
                 Ok(true)
             }
@@ -58,17 +206,80 @@ impl<'a, H: Hasher + Default, S: Store<H256>, Mac: SupportMachine> Syscalls<Mac>
                 let key_address = machine.registers()[A0].to_u64();
                 let key = load_h256(machine, key_address)?;
                 let value_address = machine.registers()[A1].to_u64();
-                let value = match self.result.write_values.get(&key) {
-                    Some(value) => *value,
-                    None => {
-                        let tree_value = self.tree.get(&key).map_err(|_| VMError::Unexpected)?;
-                        if tree_value != H256::default() {
-                            self.result.read_values.insert(key, tree_value);
+                let value = self.fetch(key)?;
+                store_data(machine, value_address, &value)?;
+                machine.set_register(A0, Mac::REG::from_u64(0));
+                Ok(true)
+            }
+            // delete, the leaf is removed by writing a zero value
+            3079 => {
+                let key_address = machine.registers()[A0].to_u64();
+                let key = load_h256(machine, key_address)?;
+                self.write(key, H256::zero());
+                machine.set_register(A0, Mac::REG::from_u64(0));
+                Ok(true)
+            }
+            // savepoint, ID is written to A0's address
+            3076 => {
+                let id_address = Mac::REG::from_u64(machine.registers()[A0].to_u64());
+                let ret = match self.savepoint() {
+                    Ok(id) => {
+                        machine
+                            .memory_mut()
+                            .store64(&id_address, &Mac::REG::from_u64(id))?;
+                        0
+                    }
+                    Err(ret) => ret,
+                };
+                machine.set_register(A0, Mac::REG::from_u64(ret as u64));
+                Ok(true)
+            }
+            // call, loads code stored under the hash at A0's address into the
+            // buffer at A1, whose size is kept at A2's address, then creates a
+            // savepoint for the call with its ID written to A3's address. Code
+            // length is written back to A2's address.
+            3081 => {
+                let hash_address = machine.registers()[A0].to_u64();
+                let code_hash = load_h256(machine, hash_address)?;
+                let buffer_address = machine.registers()[A1].to_u64();
+                let size_address = Mac::REG::from_u64(machine.registers()[A2].to_u64());
+                let id_address = Mac::REG::from_u64(machine.registers()[A3].to_u64());
+                let capacity = machine.memory_mut().load64(&size_address)?.to_u64();
+                let ret = match self.load_code(&code_hash, capacity)? {
+                    Ok(code) => match self.savepoint() {
+                        Ok(id) => {
+                            machine.memory_mut().store_bytes(buffer_address, &code)?;
+                            machine
+                                .memory_mut()
+                                .store64(&size_address, &Mac::REG::from_u64(code.len() as u64))?;
+                            machine
+                                .memory_mut()
+                                .store64(&id_address, &Mac::REG::from_u64(id))?;
+                            0
                         }
-                        tree_value
+                        Err(ret) => ret,
+                    },
+                    Err(ret) => ret,
+                };
+                machine.set_register(A0, Mac::REG::from_u64(ret as u64));
+                Ok(true)
+            }
+            // rollback and release, A0 holds savepoint ID
+            3077 | 3078 => {
+                let id = machine.registers()[A0].to_u64();
+                let depth = match self.savepoint_depth(id) {
+                    Some(depth) => depth,
+                    None => {
+                        machine
+                            .set_register(A0, Mac::REG::from_u64(ERROR_INVALID_SAVEPOINT as u64));
+                        return Ok(true);
                     }
                 };
-                store_data(machine, value_address, &value)?;
+                if code == 3077 {
+                    self.rollback(depth);
+                } else {
+                    self.release(depth);
+                }
                 machine.set_register(A0, Mac::REG::from_u64(0));
                 Ok(true)
             }
@@ -77,6 +288,60 @@ impl<'a, H: Hasher + Default, S: Store<H256>, Mac: SupportMachine> Syscalls<Mac>
     }
 }
 
+/// Serves the program being run to generators that load it via syscall
+/// instead of argv. Loading works like partial loading in CKB syscalls: A0
+/// holds the buffer address, A1 the address of buffer size and A2 the offset.
+/// Full length of data after offset is written back to A1's address.
+pub(crate) struct ProgramSyscalls<'a> {
+    pub(crate) program: &'a Bytes,
+}
+
+impl<'a, Mac: SupportMachine> Syscalls<Mac> for ProgramSyscalls<'a> {
+    fn initialize(&mut self, _machine: &mut Mac) -> Result<(), VMError> {
+        Ok(())
+    }
+
+    fn ecall(&mut self, machine: &mut Mac) -> Result<bool, VMError> {
+        if machine.registers()[A7].to_u64() != 3075 {
+            return Ok(false);
+        }
+        let address = machine.registers()[A0].to_u64();
+        let size_address = Mac::REG::from_u64(machine.registers()[A1].to_u64());
+        let offset = machine.registers()[A2].to_u64() as usize;
+        if offset > self.program.len() {
+            return Err(VMError::OutOfBound);
+        }
+        let size = machine.memory_mut().load64(&size_address)?.to_u64() as usize;
+        let data = &self.program[offset..];
+        let loaded = size.min(data.len());
+        machine.memory_mut().store_bytes(address, &data[..loaded])?;
+        machine
+            .memory_mut()
+            .store64(&size_address, &Mac::REG::from_u64(data.len() as u64))?;
+        machine.set_register(A0, Mac::REG::from_u64(0));
+        Ok(true)
+    }
+}
+
+/// Provides syscall 2042 used by gas metering in `c/gas.h`, which reports
+/// cycles consumed so far.
+pub(crate) struct CycleSyscalls {}
+
+impl<Mac: SupportMachine> Syscalls<Mac> for CycleSyscalls {
+    fn initialize(&mut self, _machine: &mut Mac) -> Result<(), VMError> {
+        Ok(())
+    }
+
+    fn ecall(&mut self, machine: &mut Mac) -> Result<bool, VMError> {
+        if machine.registers()[A7].to_u64() != 2042 {
+            return Ok(false);
+        }
+        let cycles = machine.cycles();
+        machine.set_register(A0, Mac::REG::from_u64(cycles));
+        Ok(true)
+    }
+}
+
 pub(crate) struct ExtraSyscalls<'a, Mac, C> {
     pub(crate) context: &'a mut C,
     _mac: PhantomData<Mac>,
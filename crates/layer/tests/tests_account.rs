@@ -0,0 +1,838 @@
+use bytes::Bytes;
+use ckb_simple_account_layer::{
+    call_program, code_hash, code_key, deploy_program, store_bytes, AccountRegistry, ChainFollower,
+    CkbBlake2bHasher, Config, Error, FollowEvent, FullStorage, FullStorageAccount, KeyValueBackend,
+    MemoryBackend, MockChain, PrefixedStore, RestoreReport, SharedAccount, SmtAccount, SmtState,
+    StateBackend, StateSnapshot, DEPLOY_MAGIC,
+};
+use ckb_types::{
+    core::TransactionBuilder,
+    packed::{
+        BytesOpt, CellInput, CellOutput, OutPoint, Script, ScriptOpt, Transaction, WitnessArgs,
+    },
+    prelude::*,
+};
+use sparse_merkle_tree::{default_store::DefaultStore, SparseMerkleTree, H256};
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+use std::thread;
+
+fn read_file(name: &str) -> Bytes {
+    let mut file =
+        File::open(Path::new(env!("CARGO_MANIFEST_DIR")).join(format!("testdata/{}", name)))
+            .unwrap();
+    let mut buffer = Vec::new();
+    file.read_to_end(&mut buffer).unwrap();
+    Bytes::from(buffer)
+}
+
+fn build_dummy_config() -> Config {
+    Config {
+        validator: read_file("dummy_smt_validator"),
+        generator: read_file("dummy_smt_generator"),
+        lock_script: Some(Script::default()),
+        ..Default::default()
+    }
+}
+
+/// Program for the dummy generator writing `value` to `key`
+fn write_program(key: u8, value: u8) -> Bytes {
+    let mut program = vec![b'W'];
+    program.extend_from_slice(&[key; 32]);
+    program.extend_from_slice(&[value; 32]);
+    program.into()
+}
+
+fn build_account_transaction(config: &Config, input: Option<OutPoint>, root: u8) -> Transaction {
+    let data = BytesOpt::new_builder()
+        .set(Some(Bytes::from_static(b"program").pack()))
+        .build();
+    let witness = if input.is_some() {
+        WitnessArgs::new_builder().input_type(data).build()
+    } else {
+        WitnessArgs::new_builder().output_type(data).build()
+    };
+    let mut builder = TransactionBuilder::default()
+        .output(
+            CellOutput::new_builder()
+                .type_(
+                    ScriptOpt::new_builder()
+                        .set(Some(config.type_script.clone()))
+                        .build(),
+                )
+                .build(),
+        )
+        .output_data(Bytes::from(vec![root; 32]).pack())
+        .witness(witness.as_bytes().pack());
+    if let Some(input) = input {
+        builder = builder.input(CellInput::new_builder().previous_output(input).build());
+    }
+    builder.build().data()
+}
+
+fn account_cell(transaction: &Transaction) -> OutPoint {
+    OutPoint::new_builder()
+        .tx_hash(transaction.clone().into_view().hash())
+        .index(0u32.pack())
+        .build()
+}
+
+fn restore_report(error: Box<dyn std::error::Error>) -> RestoreReport {
+    match error.downcast_ref::<Error>() {
+        Some(Error::Restore(report)) => report.clone(),
+        _ => panic!("unexpected error: {}", error),
+    }
+}
+
+/// Transaction consuming the account cell without creating a new one
+fn build_destroy_transaction(input: OutPoint) -> Transaction {
+    TransactionBuilder::default()
+        .input(CellInput::new_builder().previous_output(input).build())
+        .build()
+        .data()
+}
+
+#[test]
+pub fn test_restore_detects_forks() {
+    let config = Config::default();
+    let genesis = build_account_transaction(&config, None, 0);
+    let first = build_account_transaction(&config, Some(account_cell(&genesis)), 1);
+    let second = build_account_transaction(&config, Some(account_cell(&genesis)), 2);
+    let third = build_account_transaction(&config, Some(account_cell(&first)), 3);
+    let unlinked = build_account_transaction(&config, Some(OutPoint::default()), 4);
+
+    let result = SmtAccount::<DefaultStore<H256>>::restore_from_transactions(
+        config,
+        &[
+            second.clone(),
+            first.clone(),
+            unlinked.clone(),
+            genesis.clone(),
+            third.clone(),
+            first.clone(),
+        ],
+        false,
+    );
+    let report = restore_report(result.err().unwrap());
+    assert_eq!(report.genesis, Some(genesis.into_view().hash()));
+    assert_eq!(report.duplicates, vec![first.clone().into_view().hash()]);
+    assert_eq!(report.forks.len(), 1);
+    assert_eq!(report.forks[0].1.len(), 2);
+    // Each transaction is reported in a single category
+    assert_eq!(report.missing_links, vec![unlinked.into_view().hash()]);
+    assert_eq!(report.orphaned, vec![third.into_view().hash()]);
+    assert!(report.conflicting_geneses.is_empty());
+}
+
+#[test]
+pub fn test_restore_recreated_account() {
+    let config = build_dummy_config();
+    let mut account = SmtAccount::<DefaultStore<H256>>::empty(config.clone());
+    let old_genesis = account.generate(&write_program(1, 1)).unwrap();
+    account.advance(&old_genesis).unwrap();
+    let update = account.generate(&write_program(2, 2)).unwrap();
+    account.advance(&update).unwrap();
+    let checkpoint = account.export_snapshot().unwrap();
+    let destroy = build_destroy_transaction(account_cell(&update));
+    account.advance(&destroy).unwrap();
+    assert!(account.last_cell.is_none());
+    let new_genesis = account.generate(&write_program(3, 3)).unwrap();
+    account.advance(&new_genesis).unwrap();
+
+    // The current account comes from the creation that is never destroyed,
+    // no matter in which order transactions are provided
+    let transactions = [new_genesis.clone(), destroy, update, old_genesis.clone()];
+    let restored = SmtAccount::<DefaultStore<H256>>::restore_from_transactions(
+        config.clone(),
+        &transactions,
+        true,
+    )
+    .unwrap();
+    assert_eq!(restored.last_cell, account.last_cell);
+    assert_eq!(restored.state.root(), account.state.root());
+    assert_eq!(restored.state.get(&[1; 32].into()).unwrap(), H256::zero());
+    assert_eq!(restored.state.get(&[3; 32].into()).unwrap(), [3; 32].into());
+
+    // Re-creations after the checkpoint are replayed as well
+    let restored = SmtAccount::<DefaultStore<H256>>::restore_from_checkpoint(
+        config.clone(),
+        &checkpoint,
+        &transactions,
+        true,
+    )
+    .unwrap();
+    assert_eq!(restored.last_cell, account.last_cell);
+    assert_eq!(restored.state.root(), account.state.root());
+
+    // Two accounts that are never destroyed cannot be ordered
+    let result = SmtAccount::<DefaultStore<H256>>::restore_from_transactions(
+        config,
+        &[old_genesis.clone(), new_genesis.clone()],
+        false,
+    );
+    let report = restore_report(result.err().unwrap());
+    assert_eq!(report.genesis, None);
+    assert_eq!(
+        report.conflicting_geneses,
+        vec![
+            old_genesis.into_view().hash(),
+            new_genesis.into_view().hash()
+        ]
+    );
+    assert!(report.orphaned.is_empty());
+}
+
+#[test]
+pub fn test_follower_handles_reorg() {
+    let mut chain = MockChain::new();
+    chain.push_block(vec![]);
+    let old_hash = chain.push_block(vec![]);
+    let account = SmtAccount::<DefaultStore<H256>>::empty(Config::default());
+    let mut follower = ChainFollower::new(chain, account, 0);
+    let events = follower.sync().unwrap();
+    assert_eq!(events.len(), 2);
+    assert_eq!(follower.tip(), Some((1, &old_hash)));
+
+    follower.source_mut().rollback_to(0);
+    let new_hash = follower.source_mut().push_block(vec![]);
+    let next_hash = follower.source_mut().push_block(vec![]);
+    assert_ne!(old_hash, new_hash);
+    let events = follower.sync().unwrap();
+    assert_eq!(
+        events,
+        vec![
+            FollowEvent::RolledBack(1, old_hash),
+            FollowEvent::Applied(1, new_hash, 0),
+            FollowEvent::Applied(2, next_hash.clone(), 0),
+        ]
+    );
+    assert_eq!(follower.tip(), Some((2, &next_hash)));
+}
+
+#[test]
+pub fn test_snapshot_roundtrip() {
+    let config = Config::default();
+    let mut tree: SparseMerkleTree<CkbBlake2bHasher, H256, DefaultStore<H256>> =
+        SparseMerkleTree::default();
+    for i in 1u8..=5 {
+        tree.update([i; 32].into(), [i + 10; 32].into()).unwrap();
+    }
+    let root = *tree.root();
+    let output = CellOutput::new_builder()
+        .type_(
+            ScriptOpt::new_builder()
+                .set(Some(config.type_script.clone()))
+                .build(),
+        )
+        .build();
+    let last_cell = (
+        OutPoint::default(),
+        output,
+        Bytes::from(root.as_slice().to_vec()),
+    );
+    let account = SmtAccount::new(config.clone(), tree, last_cell);
+
+    let snapshot = account.export_snapshot().unwrap();
+    assert_eq!(snapshot.root_hash, root);
+    assert_eq!(snapshot.leaves.len(), 5);
+    let data = snapshot.serialize().unwrap();
+    let restored_snapshot = StateSnapshot::deserialize(&data).unwrap();
+    assert_eq!(restored_snapshot, snapshot);
+    assert!(StateSnapshot::deserialize(&data[..data.len() - 1]).is_err());
+    let mut trailing = data.to_vec();
+    trailing.push(0);
+    assert!(StateSnapshot::deserialize(&trailing).is_err());
+    // A forged leaf count is rejected before anything is allocated for it
+    let mut forged = data.to_vec();
+    let count_offset = data.len() - 5 * 64 - 4;
+    forged[count_offset..count_offset + 4].copy_from_slice(&std::u32::MAX.to_le_bytes()[..]);
+    assert_eq!(
+        StateSnapshot::deserialize(&forged).unwrap_err().to_string(),
+        "Invalid leaf count in snapshot!"
+    );
+
+    let restored =
+        SmtAccount::<DefaultStore<H256>>::import_snapshot(config.clone(), &restored_snapshot)
+            .unwrap();
+    assert_eq!(restored.export_snapshot().unwrap(), snapshot);
+    // Leftover nodes in the store would be mixed into the imported tree
+    let used_store = account.state.take_store();
+    assert!(
+        SmtAccount::<DefaultStore<H256>>::import_snapshot_with_store(
+            config.clone(),
+            used_store,
+            &snapshot
+        )
+        .is_err()
+    );
+
+    let mut tampered = snapshot;
+    tampered.leaves[0].1 = [0xFF; 32].into();
+    assert!(SmtAccount::<DefaultStore<H256>>::import_snapshot(config, &tampered).is_err());
+}
+
+fn checkpoint_error(
+    config: &Config,
+    checkpoint: &StateSnapshot,
+    transactions: &[Transaction],
+) -> String {
+    SmtAccount::<DefaultStore<H256>>::restore_from_checkpoint(
+        config.clone(),
+        checkpoint,
+        transactions,
+        false,
+    )
+    .err()
+    .unwrap()
+    .to_string()
+}
+
+#[test]
+pub fn test_restore_from_checkpoint() {
+    let config = Config::default();
+    let genesis = build_account_transaction(&config, None, 0);
+    let genesis_view = genesis.clone().into_view();
+    let checkpoint = StateSnapshot {
+        root_hash: H256::zero(),
+        last_cell: Some((
+            account_cell(&genesis),
+            genesis_view.output(0).unwrap(),
+            genesis_view.outputs_data().get(0).unwrap().raw_data(),
+        )),
+        leaves: vec![],
+    };
+    let account = SmtAccount::<DefaultStore<H256>>::restore_from_checkpoint(
+        config.clone(),
+        &checkpoint,
+        std::slice::from_ref(&genesis),
+        true,
+    )
+    .unwrap();
+    assert_eq!(account.last_cell, checkpoint.last_cell);
+
+    // Each check is exercised by a checkpoint passing all the checks before it
+    let created = std::slice::from_ref(&genesis);
+    let mut no_cell = checkpoint.clone();
+    no_cell.last_cell = None;
+    assert_eq!(
+        checkpoint_error(&config, &no_cell, created),
+        "Checkpoint does not have a last cell!"
+    );
+    assert_eq!(
+        checkpoint_error(&config, &checkpoint, &[]),
+        "Transaction creating the checkpoint cell is missing!"
+    );
+    let mut tampered = checkpoint.clone();
+    if let Some((_, _, data)) = tampered.last_cell.as_mut() {
+        *data = Bytes::from(vec![1; 32]);
+    }
+    assert_eq!(
+        checkpoint_error(&config, &tampered, created),
+        "Checkpoint cell does not match its transaction!"
+    );
+    let unlinked = build_account_transaction(&config, Some(OutPoint::default()), 1);
+    let result = SmtAccount::<DefaultStore<H256>>::restore_from_checkpoint(
+        config.clone(),
+        &checkpoint,
+        &[genesis.clone(), unlinked.clone()],
+        true,
+    );
+    let report = restore_report(result.err().unwrap());
+    assert_eq!(report.before_checkpoint, vec![genesis_view.hash()]);
+    assert_eq!(report.missing_links, vec![unlinked.into_view().hash()]);
+    let mut wrong_root = checkpoint.clone();
+    wrong_root.root_hash = [1; 32].into();
+    assert_eq!(
+        checkpoint_error(&config, &wrong_root, created),
+        "Rebuilt root hash does not match snapshot!"
+    );
+    let other_config = Config {
+        type_script: Script::new_builder()
+            .args(Bytes::from(vec![1]).pack())
+            .build(),
+        ..config.clone()
+    };
+    assert_eq!(
+        checkpoint_error(&other_config, &checkpoint, created),
+        "Last cell has invalid type script!"
+    );
+    let mut tree: SparseMerkleTree<CkbBlake2bHasher, H256, DefaultStore<H256>> =
+        SparseMerkleTree::default();
+    tree.update([1; 32].into(), [1; 32].into()).unwrap();
+    let mut other_state = checkpoint;
+    other_state.root_hash = *tree.root();
+    other_state.leaves = vec![([1; 32].into(), [1; 32].into())];
+    assert_eq!(
+        checkpoint_error(&config, &other_state, created),
+        "Last cell data does not match root hash!"
+    );
+}
+
+#[test]
+pub fn test_scan_leaves_by_prefix() {
+    let mut account = SmtAccount::<DefaultStore<H256>>::empty(Config::default());
+    let mut keys = Vec::new();
+    for (first, second) in &[(2u8, 1u8), (1, 2), (1, 1), (3, 0)] {
+        let mut key = [0u8; 32];
+        key[0] = *first;
+        key[1] = *second;
+        account.state.update(key.into(), [7; 32].into()).unwrap();
+        keys.push(H256::from(key));
+    }
+    // Overwritten and deleted values are not listed
+    account.state.update(keys[0], [8; 32].into()).unwrap();
+    account.state.update(keys[3], H256::zero()).unwrap();
+
+    let all: Vec<(H256, H256)> = account.iter_leaves().unwrap().collect();
+    assert_eq!(
+        all,
+        vec![
+            (keys[2], [7; 32].into()),
+            (keys[1], [7; 32].into()),
+            (keys[0], [8; 32].into()),
+        ]
+    );
+    let scanned: Vec<H256> = account.scan_prefix(&[1]).unwrap().map(|(k, _)| k).collect();
+    assert_eq!(scanned, vec![keys[2], keys[1]]);
+    assert_eq!(account.scan_prefix(&[3]).unwrap().count(), 0);
+}
+
+#[test]
+pub fn test_load_stored_code() {
+    let mut account = SmtAccount::<DefaultStore<H256>>::empty(Config::default());
+    let code = Bytes::from(vec![0x42; 70]);
+    let hash = code_hash(&code);
+    assert!(!account.has_code(&hash).unwrap());
+    assert_eq!(account.load_code(&hash).unwrap(), None);
+
+    // Same layout as deploy programs executed by c/code.h
+    store_bytes(&mut account.state, &code_key(&hash), &code).unwrap();
+    assert!(account.has_code(&hash).unwrap());
+    assert_eq!(account.load_code(&hash).unwrap(), Some(code.clone()));
+
+    let program = deploy_program(&code);
+    assert_eq!(&program[0..8], DEPLOY_MAGIC);
+    assert_eq!(&program[8..], &code[..]);
+    assert_eq!(&call_program(&hash)[8..], hash.as_slice());
+}
+
+#[test]
+pub fn test_deploy_and_call_code() {
+    let mut account = SmtAccount::<DefaultStore<H256>>::empty(build_dummy_config());
+    let code = write_program(5, 6);
+    let (hash, transaction) = account.deploy(&code).unwrap();
+    assert_eq!(hash, code_hash(&code));
+    account.advance(&transaction).unwrap();
+    assert_eq!(account.load_code(&hash).unwrap(), Some(code.clone()));
+    // Deploying does not execute the code
+    assert_eq!(account.state.get(&[5; 32].into()).unwrap(), H256::zero());
+    let root = *account.state.root();
+
+    // Deploying the same code again leaves state untouched
+    let (_, transaction) = account.deploy(&code).unwrap();
+    account.advance(&transaction).unwrap();
+    assert_eq!(*account.state.root(), root);
+
+    let transaction = account.generate(&call_program(&hash)).unwrap();
+    account.advance(&transaction).unwrap();
+    assert_eq!(account.state.get(&[5; 32].into()).unwrap(), [6; 32].into());
+
+    // Calling code that was never deployed fails
+    let missing = code_hash(&write_program(7, 7));
+    let error = account.generate(&call_program(&missing)).err().unwrap();
+    assert_eq!(
+        error.downcast_ref::<Error>(),
+        Some(&Error::InvalidResponseCode(-41))
+    );
+}
+
+#[test]
+pub fn test_fullstorage_account() {
+    let config = build_dummy_config();
+    // Creating an account without any data only has the main cell
+    let account = FullStorageAccount::empty(config.clone());
+    let transaction = account.generate(&Bytes::new()).unwrap();
+    assert_eq!(transaction.into_view().outputs().len(), 1);
+
+    let mut account = FullStorageAccount::empty_with_state(config.clone(), FullStorage::new(2));
+    let mut program = Vec::new();
+    for i in 1u8..=5 {
+        program.extend_from_slice(&write_program(i, i + 10));
+    }
+    let creation = account.generate(&program.into()).unwrap();
+    let view = creation.clone().into_view();
+    assert_eq!(view.inputs().len(), 0);
+    // Main cell, followed by 3 data cells of split shards
+    assert_eq!(view.outputs().len(), 4);
+    account.advance(&creation).unwrap();
+    assert_eq!(account.state.data_cells.len(), 3);
+    assert_eq!(account.state.state.get(&[5; 32].into()), [15; 32].into());
+    assert_eq!(
+        account.last_cell.as_ref().unwrap().0,
+        account_cell(&creation)
+    );
+
+    // Updates only consume and recreate the changed data cell
+    let update = account.generate(&write_program(4, 40)).unwrap();
+    let view = update.clone().into_view();
+    let inputs: Vec<OutPoint> = view.input_pts_iter().collect();
+    assert_eq!(
+        inputs,
+        vec![
+            account_cell(&creation),
+            account.state.data_cells[1].0.clone()
+        ]
+    );
+    assert_eq!(view.outputs().len(), 2);
+    let untouched = account.state.data_cells[0].clone();
+    let old_state = account.state.clone();
+    let old_cell = account.last_cell.clone().unwrap();
+    account.push_pending(&update).unwrap();
+    // Pending transactions chain off new data cells as well
+    let next = account.generate(&write_program(4, 41)).unwrap();
+    let inputs: Vec<OutPoint> = next.into_view().input_pts_iter().collect();
+    let data_cell = account_cell(&update)
+        .as_builder()
+        .index(1u32.pack())
+        .build();
+    assert_eq!(inputs, vec![account_cell(&update), data_cell]);
+    account.advance(&update).unwrap();
+    assert_eq!(account.state.data_cells[0], untouched);
+    assert_eq!(account.state.state.get(&[4; 32].into()), [40; 32].into());
+
+    let cell = |view: &ckb_types::core::TransactionView, index: usize| {
+        let (output, data) = view.outputs_with_data_iter().nth(index).unwrap();
+        let out_point = OutPoint::new_builder()
+            .tx_hash(view.hash())
+            .index((index as u32).pack())
+            .build();
+        (out_point, output, data)
+    };
+    let creation_view = creation.into_view();
+    let state = FullStorage::from_cells(
+        &cell(&view, 0).2,
+        vec![
+            cell(&creation_view, 3),
+            cell(&view, 1),
+            cell(&creation_view, 1),
+        ],
+        2,
+    )
+    .unwrap();
+    assert_eq!(state, account.state);
+
+    // Transactions leaving changed data cells alone are rejected
+    let mut account = FullStorageAccount::new(config, old_state, old_cell.clone());
+    let skipped = view
+        .as_advanced_builder()
+        .set_inputs(vec![CellInput::new_builder()
+            .previous_output(old_cell.0)
+            .build()])
+        .build()
+        .data();
+    assert!(account.advance(&skipped).is_err());
+    // So are transactions consuming unchanged data cells
+    let consumed = view
+        .as_advanced_builder()
+        .input(
+            CellInput::new_builder()
+                .previous_output(untouched.0)
+                .build(),
+        )
+        .build()
+        .data();
+    assert_eq!(
+        account.advance(&consumed).unwrap_err().to_string(),
+        "Unchanged data cell is consumed!"
+    );
+    assert!(account.advance(&update).is_ok());
+}
+
+#[test]
+pub fn test_restore_fullstorage_account() {
+    let config = build_dummy_config();
+    let mut account = FullStorageAccount::empty(config.clone());
+    let creation = account.generate(&write_program(1, 1)).unwrap();
+    account.advance(&creation).unwrap();
+    let update = account.generate(&write_program(2, 2)).unwrap();
+    account.advance(&update).unwrap();
+
+    // Data cells carry the account type script, only the main cell is
+    // followed when chaining transactions
+    let restored =
+        FullStorageAccount::restore_from_transactions(config, &[update, creation], true).unwrap();
+    assert_eq!(restored.state, account.state);
+    assert_eq!(restored.last_cell, account.last_cell);
+}
+
+#[test]
+pub fn test_generate_with_interpreter() {
+    let account = SmtAccount::<DefaultStore<H256>>::empty(build_dummy_config());
+    let interpreted = SmtAccount::<DefaultStore<H256>>::empty(Config {
+        use_interpreter: true,
+        ..build_dummy_config()
+    });
+    let program = write_program(1, 2);
+    assert_eq!(
+        account.generate(&program).unwrap().as_slice(),
+        interpreted.generate(&program).unwrap().as_slice()
+    );
+}
+
+#[test]
+pub fn test_pending_queue() {
+    let mut account = SmtAccount::<DefaultStore<H256>>::empty(build_dummy_config());
+    let genesis = account.generate(&write_program(1, 1)).unwrap();
+    account.advance(&genesis).unwrap();
+    assert_eq!(
+        account.last_cell.as_ref().unwrap().0,
+        account_cell(&genesis)
+    );
+
+    // Transactions chain off the cells of pending ones
+    let first = account.generate(&write_program(2, 2)).unwrap();
+    account.push_pending(&first).unwrap();
+    let second = account.generate(&write_program(3, 3)).unwrap();
+    account.push_pending(&second).unwrap();
+    assert_eq!(account.pending_transactions().len(), 2);
+    let inputs: Vec<OutPoint> = second.clone().into_view().input_pts_iter().collect();
+    assert_eq!(inputs, vec![account_cell(&first)]);
+    // Committed state is untouched by pending transactions
+    assert_eq!(account.state.get(&[2; 32].into()).unwrap(), H256::zero());
+
+    // Dropping a transaction drops the ones chained after it
+    let dropped = account.drop_pending(&first.clone().into_view().hash());
+    assert_eq!(dropped, vec![first.clone(), second.clone()]);
+    assert!(account.pending_transactions().is_empty());
+    account.push_pending(&first).unwrap();
+    account.push_pending(&second).unwrap();
+
+    // Committing the first pending transaction pops it from the queue
+    account.advance(&first).unwrap();
+    assert_eq!(account.pending_transactions().len(), 1);
+    assert_eq!(account.last_cell.as_ref().unwrap().0, account_cell(&first));
+    assert_eq!(account.state.get(&[2; 32].into()).unwrap(), [2; 32].into());
+
+    // A competing transaction consuming the same cell as `second`
+    let dropped = account.drop_pending(&second.clone().into_view().hash());
+    assert_eq!(dropped, vec![second.clone()]);
+    let competing = account.generate(&write_program(4, 4)).unwrap();
+    account.push_pending(&second).unwrap();
+    account.advance(&competing).unwrap();
+    assert!(account.pending_transactions().is_empty());
+    assert_eq!(
+        account.last_cell.as_ref().unwrap().0,
+        account_cell(&competing)
+    );
+    assert_eq!(account.state.get(&[3; 32].into()).unwrap(), H256::zero());
+    assert_eq!(account.state.get(&[4; 32].into()).unwrap(), [4; 32].into());
+}
+
+#[test]
+pub fn test_shared_account_snapshots() {
+    let shared = SharedAccount::new(SmtAccount::<DefaultStore<H256>>::empty(build_dummy_config()));
+    let genesis = shared
+        .snapshot()
+        .unwrap()
+        .generate(&write_program(1, 1))
+        .unwrap();
+
+    // A live snapshot doesn't block the writer, nor does it see its changes
+    let old = shared.snapshot().unwrap();
+    let writer = shared.clone();
+    let committed = genesis.clone();
+    let advanced = thread::spawn(move || writer.advance(&committed).is_ok());
+    assert!(advanced.join().unwrap());
+    assert!(old.last_cell.is_none());
+    assert_eq!(old.state.get(&[1; 32].into()).unwrap(), H256::zero());
+    let current = shared.snapshot().unwrap();
+    assert_eq!(
+        current.last_cell.as_ref().unwrap().0,
+        account_cell(&genesis)
+    );
+    assert_eq!(current.state.get(&[1; 32].into()).unwrap(), [1; 32].into());
+
+    let pending = current.generate(&write_program(2, 2)).unwrap();
+    shared.push_pending(&pending).unwrap();
+    assert_eq!(shared.snapshot().unwrap().pending_transactions().len(), 1);
+    assert!(current.pending_transactions().is_empty());
+    let dropped = shared
+        .drop_pending(&pending.clone().into_view().hash())
+        .unwrap();
+    assert_eq!(dropped, vec![pending]);
+    assert_eq!(
+        shared
+            .write(|account| account.pending_transactions().len())
+            .unwrap(),
+        0
+    );
+}
+
+#[test]
+pub fn test_revert_destroyed_state() {
+    let mut tree: SparseMerkleTree<CkbBlake2bHasher, H256, DefaultStore<H256>> =
+        SparseMerkleTree::default();
+    for i in 1u8..=3 {
+        tree.update([i; 32].into(), [i + 10; 32].into()).unwrap();
+    }
+    let root = *tree.root();
+    let changes = tree.destroy();
+    let undo = tree.apply(changes).unwrap();
+    assert_eq!(tree.root(), &H256::zero());
+    assert_eq!(tree.get(&[1; 32].into()).unwrap(), H256::zero());
+
+    tree.revert(undo).unwrap();
+    assert_eq!(tree.root(), &root);
+    for i in 1u8..=3 {
+        assert_eq!(tree.get(&[i; 32].into()).unwrap(), [i + 10; 32].into());
+    }
+}
+
+#[test]
+pub fn test_follower_reverts_account_transactions() {
+    let config = build_dummy_config();
+    let mut account = SmtAccount::<DefaultStore<H256>>::empty(config.clone());
+    let genesis = account.generate(&write_program(1, 1)).unwrap();
+    account.advance(&genesis).unwrap();
+    let genesis_root = *account.state.root();
+    let update = account.generate(&write_program(2, 2)).unwrap();
+    let competing = account.generate(&write_program(3, 3)).unwrap();
+    account.advance(&update).unwrap();
+    // Only touches the account via the cell created earlier in the same block
+    let destroy = build_destroy_transaction(account_cell(&update));
+
+    let mut chain = MockChain::new();
+    chain.push_block(vec![genesis.clone()]);
+    let old_hash = chain.push_block(vec![update, destroy]);
+    let empty = SmtAccount::<DefaultStore<H256>>::empty(config);
+    assert!(
+        ChainFollower::new(chain.clone(), empty.snapshot().unwrap(), 0)
+            .max_reorg_depth(0)
+            .is_err()
+    );
+    let mut follower = ChainFollower::new(chain, empty, 0)
+        .max_reorg_depth(10)
+        .unwrap();
+    let events = follower.sync().unwrap();
+    assert_eq!(events[1], FollowEvent::Applied(1, old_hash.clone(), 2));
+    assert!(follower.account().last_cell.is_none());
+    assert_eq!(follower.account().state.root(), &H256::zero());
+
+    // Rolling back reverts the destruction and the update before it
+    follower.source_mut().rollback_to(0);
+    let new_hash = follower.source_mut().push_block(vec![competing.clone()]);
+    let events = follower.sync().unwrap();
+    assert_eq!(
+        events,
+        vec![
+            FollowEvent::RolledBack(1, old_hash),
+            FollowEvent::Applied(1, new_hash.clone(), 1),
+        ]
+    );
+    let account = follower.account();
+    assert_eq!(
+        account.last_cell.as_ref().unwrap().0,
+        account_cell(&competing)
+    );
+    assert_eq!(account.state.get(&[1; 32].into()).unwrap(), [1; 32].into());
+    assert_eq!(account.state.get(&[2; 32].into()).unwrap(), H256::zero());
+    assert_eq!(account.state.get(&[3; 32].into()).unwrap(), [3; 32].into());
+
+    // A failing transaction leaves the whole block unapplied
+    follower.source_mut().rollback_to(0);
+    follower
+        .source_mut()
+        .push_block(vec![competing.clone(), genesis]);
+    assert_eq!(
+        follower.step().unwrap(),
+        Some(FollowEvent::RolledBack(1, new_hash))
+    );
+    assert!(follower.step().is_err());
+    assert_eq!(follower.account().state.root(), &genesis_root);
+    assert_eq!(follower.tip().map(|(number, _)| number), Some(0));
+}
+
+#[test]
+pub fn test_registry_routing() {
+    let backend = Arc::new(RwLock::new(MemoryBackend::new()));
+    let mut registry = AccountRegistry::<SmtState<PrefixedStore<MemoryBackend>>>::with_shared_store(
+        Arc::clone(&backend),
+    );
+    let configs: Vec<Config> = (1u8..=2)
+        .map(|i| Config {
+            type_script: Script::new_builder()
+                .args(Bytes::from(vec![i]).pack())
+                .build(),
+            ..build_dummy_config()
+        })
+        .collect();
+    let (a, b) = (&configs[0].type_script, &configs[1].type_script);
+    for config in &configs {
+        registry.create(config.clone()).unwrap();
+    }
+    assert!(registry.create(configs[0].clone()).is_err());
+    assert_eq!(registry.len(), 2);
+
+    let genesis = registry
+        .get(a)
+        .unwrap()
+        .generate(&write_program(1, 1))
+        .unwrap();
+    assert_eq!(registry.route(&genesis), vec![a.clone()]);
+    let results = registry.advance(&genesis);
+    assert_eq!(results.len(), 1);
+    assert!(results[0].1.is_ok());
+    let other = registry
+        .get(b)
+        .unwrap()
+        .generate(&write_program(1, 2))
+        .unwrap();
+    assert!(registry.advance(&other)[0].1.is_ok());
+    // Both accounts keep their trees in the same backend
+    assert_eq!(
+        registry.get(a).unwrap().state.get(&[1; 32].into()).unwrap(),
+        [1; 32].into()
+    );
+    assert_eq!(
+        registry.get(b).unwrap().state.get(&[1; 32].into()).unwrap(),
+        [2; 32].into()
+    );
+
+    // Cells of pending transactions are routed as well
+    let pending = registry
+        .get(a)
+        .unwrap()
+        .generate(&write_program(2, 2))
+        .unwrap();
+    registry
+        .update(a, |account| account.push_pending(&pending))
+        .unwrap()
+        .unwrap();
+    let destroy = build_destroy_transaction(account_cell(&pending));
+    assert_eq!(registry.route(&destroy), vec![a.clone()]);
+    registry.update(a, |account| {
+        account.drop_pending(&pending.clone().into_view().hash())
+    });
+    assert!(registry.route(&destroy).is_empty());
+    assert_eq!(
+        registry.route(&build_destroy_transaction(account_cell(&other))),
+        vec![b.clone()]
+    );
+
+    // Purging an account only deletes its own namespace
+    let namespace = PrefixedStore::<MemoryBackend>::for_script(Arc::clone(&backend), b)
+        .namespace()
+        .to_vec();
+    assert!(registry.purge(b).unwrap());
+    assert!(!registry.purge(b).unwrap());
+    assert!(backend
+        .read()
+        .unwrap()
+        .scan_prefix(&namespace)
+        .unwrap()
+        .is_empty());
+    assert!(!backend.read().unwrap().is_empty());
+    assert!(registry.route(&other).is_empty());
+}
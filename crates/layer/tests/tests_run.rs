@@ -1,10 +1,31 @@
 use bytes::Bytes;
-use ckb_simple_account_layer::{run, CkbBlake2bHasher, Config};
+use ckb_simple_account_layer::{
+    array_key, call_program, chunk_key, code_hash, deploy_program, diff_states, load_bytes,
+    mapping_key, namespace_key, run, run_parallel, run_with_machine, store_bytes, u128_to_value,
+    u64_to_value, value_to_u128, value_to_u64, CalibrationSample, CkbBlake2bHasher, ClearStore,
+    Config, CycleCalibration, DefaultRunContext, Error, FullStorageState, Interpreter,
+    MemoryBackend, PrefixedStore, RunProofResult, RunResult, SmtAccount, StateDiff,
+    StoreTransaction, MAX_PROGRAM_SIZE, MAX_VALUE_LENGTH,
+};
+use ckb_types::{
+    core::TransactionBuilder,
+    packed::{BytesOpt, CellOutput, OutPoint, Script, ScriptOpt, WitnessArgs},
+    prelude::*,
+};
 use hex::decode_to_slice;
-use sparse_merkle_tree::{default_store::DefaultStore, SparseMerkleTree, H256};
+use sparse_merkle_tree::{
+    default_store::DefaultStore,
+    error::Error as SMTError,
+    traits::Store,
+    tree::{BranchNode, LeafNode},
+    CompiledMerkleProof, SparseMerkleTree, H256,
+};
+use std::cell::Cell;
+use std::collections::HashMap;
 use std::fs::File;
 use std::io::Read;
 use std::path::Path;
+use std::sync::{Arc, RwLock};
 
 fn read_file(name: &str) -> Bytes {
     let mut file =
@@ -23,6 +44,13 @@ fn build_dummy_config() -> Config {
     }
 }
 
+/// Appends an operation of the dummy generator, such as `b'R'` or `b'W'`
+fn push_op(program: &mut Vec<u8>, op: u8, key: u8, value: u8) {
+    program.push(op);
+    program.extend_from_slice(&[key; 32]);
+    program.extend_from_slice(&[value; 32]);
+}
+
 fn hex_to_h256(s: &str) -> H256 {
     let mut buffer = [0u8; 32];
     decode_to_slice(s, &mut buffer[..]).unwrap();
@@ -104,3 +132,951 @@ pub fn test_run_read_written_value() {
     let config = build_dummy_config();
     run(&config, &tree, &program).unwrap();
 }
+
+#[test]
+pub fn test_run_nested_calls() {
+    let mut tree: SparseMerkleTree<CkbBlake2bHasher, H256, DefaultStore<H256>> =
+        SparseMerkleTree::default();
+    let config = build_dummy_config();
+    let mut code = Vec::new();
+    push_op(&mut code, b'W', 5, 6);
+    let mut failing_code = Vec::new();
+    push_op(&mut failing_code, b'W', 7, 7);
+    push_op(&mut failing_code, b'R', 5, 9);
+    for code in &[&code, &failing_code] {
+        let result = run(&config, &tree, &deploy_program(code)).unwrap();
+        result.commit(&mut tree).unwrap();
+    }
+
+    let result = run(&config, &tree, &call_program(&code_hash(&code))).unwrap();
+    let mut expected = HashMap::new();
+    expected.insert(H256::from([5u8; 32]), H256::from([6u8; 32]));
+    assert_eq!(result.write_values, expected);
+
+    // A failing callee halts the caller with its error, same as in c/tests
+    let mut program = Vec::new();
+    for hash in &[code_hash(&code), code_hash(&failing_code)] {
+        program.push(b'C');
+        program.extend_from_slice(hash.as_slice());
+        program.extend_from_slice(&[0u8; 32]);
+    }
+    push_op(&mut program, b'W', 9, 9);
+    let error = run(&config, &tree, &program.into()).err().unwrap();
+    assert_eq!(
+        error.downcast_ref::<Error>(),
+        Some(&Error::InvalidResponseCode(-101))
+    );
+
+    let mut program = vec![b'C'];
+    program.extend_from_slice(&[0u8; 64]);
+    let error = run(&config, &tree, &program.into()).err().unwrap();
+    assert_eq!(
+        error.downcast_ref::<Error>(),
+        Some(&Error::InvalidResponseCode(-41))
+    );
+}
+
+#[test]
+pub fn test_run_delete() {
+    let mut tree: SparseMerkleTree<CkbBlake2bHasher, H256, DefaultStore<H256>> =
+        SparseMerkleTree::default();
+    tree.update([1u8; 32].into(), [2u8; 32].into()).unwrap();
+    tree.update([3u8; 32].into(), [4u8; 32].into()).unwrap();
+    let root = *tree.root();
+    let config = build_dummy_config();
+
+    let mut program = Vec::new();
+    push_op(&mut program, b'R', 1, 2);
+    push_op(&mut program, b'D', 1, 0);
+    push_op(&mut program, b'R', 1, 0);
+    // Deleting an absent key is a no-op on the tree
+    push_op(&mut program, b'D', 5, 0);
+    let result = run(&config, &tree, &program.into()).unwrap();
+    let mut expected = HashMap::new();
+    expected.insert(H256::from([1u8; 32]), H256::zero());
+    expected.insert(H256::from([5u8; 32]), H256::zero());
+    assert_eq!(result.write_values, expected);
+
+    let proof = result.generate_proof(&tree).unwrap();
+    let new_root = result.committed_root_hash(&tree).unwrap();
+    assert_ne!(new_root, root);
+    result.commit(&mut tree).unwrap();
+    assert_eq!(*tree.root(), new_root);
+    assert_eq!(tree.get(&[1u8; 32].into()).unwrap(), H256::zero());
+    let mut write_values = proof.write_values;
+    write_values.sort();
+    assert_eq!(
+        write_values,
+        vec![
+            ([1u8; 32].into(), [2u8; 32].into(), H256::zero()),
+            ([5u8; 32].into(), H256::zero(), H256::zero()),
+        ]
+    );
+
+    // Same tree as never inserting the deleted key
+    let mut other: SparseMerkleTree<CkbBlake2bHasher, H256, DefaultStore<H256>> =
+        SparseMerkleTree::default();
+    other.update([3u8; 32].into(), [4u8; 32].into()).unwrap();
+    assert_eq!(*tree.root(), *other.root());
+}
+
+#[test]
+pub fn test_run_with_max_cycles() {
+    let tree: SparseMerkleTree<CkbBlake2bHasher, H256, DefaultStore<H256>> =
+        SparseMerkleTree::default();
+    let mut program = Vec::new();
+    push_op(&mut program, b'W', 1, 2);
+    push_op(&mut program, b'R', 1, 2);
+    let program: Bytes = program.into();
+
+    let config = Config {
+        max_cycles: Some(100_000_000),
+        ..build_dummy_config()
+    };
+    run(&config, &tree, &program).unwrap();
+    let config = Config {
+        max_cycles: Some(1_000),
+        ..config
+    };
+    assert!(run(&config, &tree, &program).is_err());
+}
+
+/// Same sequences as `change.savepoint_sequences` in `c/tests/main.c`, which
+/// runs them through the validator implementation
+#[test]
+pub fn test_savepoints() {
+    let tree: SparseMerkleTree<CkbBlake2bHasher, H256, DefaultStore<H256>> =
+        SparseMerkleTree::default();
+    let config = build_dummy_config();
+
+    // Savepoints created after the one rolled back to are dropped for good
+    let mut program = Vec::new();
+    push_op(&mut program, b'S', 0, 0);
+    push_op(&mut program, b'W', 1, 1);
+    push_op(&mut program, b'S', 1, 0);
+    push_op(&mut program, b'B', 0, 0);
+    push_op(&mut program, b'W', 1, 2);
+    for op in b"BK" {
+        let mut program = program.clone();
+        push_op(&mut program, *op, 1, 0);
+        let error = run(&config, &tree, &program.into()).err().unwrap();
+        assert_eq!(
+            error.downcast_ref::<Error>(),
+            Some(&Error::InvalidResponseCode(-46))
+        );
+    }
+
+    let mut program = Vec::new();
+    push_op(&mut program, b'S', 0, 0);
+    push_op(&mut program, b'W', 1, 1);
+    push_op(&mut program, b'S', 1, 0);
+    push_op(&mut program, b'W', 1, 2);
+    push_op(&mut program, b'B', 1, 0);
+    push_op(&mut program, b'R', 1, 1);
+    push_op(&mut program, b'B', 0, 0);
+    push_op(&mut program, b'R', 1, 0);
+    push_op(&mut program, b'B', 0, 0);
+    push_op(&mut program, b'S', 2, 0);
+    push_op(&mut program, b'W', 1, 3);
+    push_op(&mut program, b'K', 2, 0);
+    push_op(&mut program, b'R', 1, 3);
+    push_op(&mut program, b'K', 0, 0);
+    push_op(&mut program, b'R', 1, 3);
+    let result = run(&config, &tree, &program.into()).unwrap();
+    let mut expected = HashMap::new();
+    expected.insert(H256::from([1u8; 32]), H256::from([3u8; 32]));
+    assert_eq!(result.write_values, expected);
+
+    let mut program = Vec::new();
+    for _ in 0..=64 {
+        push_op(&mut program, b'S', 0, 0);
+    }
+    let error = run(&config, &tree, &program.into()).err().unwrap();
+    assert_eq!(
+        error.downcast_ref::<Error>(),
+        Some(&Error::InvalidResponseCode(-20))
+    );
+}
+
+#[test]
+pub fn test_read_proof_covers_absent_keys() {
+    let mut tree: SparseMerkleTree<CkbBlake2bHasher, H256, DefaultStore<H256>> =
+        SparseMerkleTree::default();
+    let key = hex_to_h256("e8c0265680a02b680b6cbc880348f062b825b28e237da7169aded4bcac0a04e5");
+    let value = hex_to_h256("2ca41595841e46ce8e74ad749e5c3f1d17202150f99c3d8631233ebdd19b19eb");
+    tree.update(key, value).unwrap();
+    let absent_key =
+        hex_to_h256("381dc5391dab099da5e28acd1ad859a051cf18ace804d037f12819c6fbc0e18b");
+
+    let mut result = RunResult::default();
+    result.read_values.insert(key, value);
+    result.read_values.insert(absent_key, H256::zero());
+    let proof = result.generate_proof(&tree).unwrap();
+    assert_eq!(2, proof.read_values.len());
+    let compiled = CompiledMerkleProof(proof.read_proof.to_vec());
+    assert!(compiled
+        .verify::<CkbBlake2bHasher>(tree.root(), proof.read_values.clone())
+        .unwrap());
+    let forged = proof.read_values.iter().map(|(k, _)| (*k, value)).collect();
+    assert!(!compiled
+        .verify::<CkbBlake2bHasher>(tree.root(), forged)
+        .unwrap_or(false));
+}
+
+#[test]
+pub fn test_run_with_interpreter() {
+    let mut tree: SparseMerkleTree<CkbBlake2bHasher, H256, DefaultStore<H256>> =
+        SparseMerkleTree::default();
+    tree.update(
+        hex_to_h256("e8c0265680a02b680b6cbc880348f062b825b28e237da7169aded4bcac0a04e5"),
+        hex_to_h256("2ca41595841e46ce8e74ad749e5c3f1d17202150f99c3d8631233ebdd19b19eb"),
+    )
+    .unwrap();
+
+    let mut program = Vec::new();
+    program.push(0x52); // R
+    program.extend_from_slice(
+        hex_to_h256("e8c0265680a02b680b6cbc880348f062b825b28e237da7169aded4bcac0a04e5").as_slice(),
+    );
+    program.extend_from_slice(
+        hex_to_h256("2ca41595841e46ce8e74ad749e5c3f1d17202150f99c3d8631233ebdd19b19eb").as_slice(),
+    );
+    program.push(0x57); // W
+    program.extend_from_slice(
+        hex_to_h256("a9bb945be71f0bd2757d33d2465b6387383da42f321072e47472f0c9c7428a8a").as_slice(),
+    );
+    program.extend_from_slice(
+        hex_to_h256("a939a47335f777eac4c40fbc0970e25f832a24e1d55adc45a7b76d63fe364e82").as_slice(),
+    );
+    let program: Bytes = program.into();
+
+    let config = build_dummy_config();
+    let result = run_with_machine::<Interpreter, _, _, _>(
+        &config,
+        &tree,
+        &program,
+        &mut DefaultRunContext {},
+    )
+    .unwrap();
+    assert_eq!(result, run(&config, &tree, &program).unwrap());
+
+    // Interpreter can also be picked at runtime
+    let config = Config {
+        use_interpreter: true,
+        ..config
+    };
+    assert_eq!(result, run(&config, &tree, &program).unwrap());
+}
+
+#[test]
+pub fn test_run_load_program_via_syscall() {
+    let tree: SparseMerkleTree<CkbBlake2bHasher, H256, DefaultStore<H256>> =
+        SparseMerkleTree::default();
+    let mut program = Vec::new();
+    push_op(&mut program, b'W', 1, 2);
+    push_op(&mut program, b'R', 1, 2);
+    push_op(&mut program, b'R', 3, 0);
+    let program: Bytes = program.into();
+
+    let config = build_dummy_config();
+    let result = run(&config, &tree, &program).unwrap();
+    let config = Config {
+        load_program_via_syscall: true,
+        ..config
+    };
+    assert_eq!(run(&config, &tree, &program).unwrap(), result);
+
+    // Larger programs are loaded in full as well
+    let mut program = Vec::new();
+    for i in 0..2000 {
+        push_op(&mut program, b'W', (i % 256) as u8, 1);
+    }
+    let result = run(&config, &tree, &program.into()).unwrap();
+    assert_eq!(result.write_values.len(), 256);
+}
+
+#[test]
+pub fn test_program_size_checks() {
+    let tree: SparseMerkleTree<CkbBlake2bHasher, H256, DefaultStore<H256>> =
+        SparseMerkleTree::default();
+    let program = Bytes::from(vec![0x52; 100]);
+
+    let config = Config {
+        max_program_length: Some(64),
+        ..Default::default()
+    };
+    let error = run(&config, &tree, &program).err().unwrap();
+    assert_eq!(
+        error.downcast_ref::<Error>(),
+        Some(&Error::ProgramTooLong(100, 64))
+    );
+
+    // Programs loaded via syscall are capped at the generator's buffer size
+    let config = Config {
+        load_program_via_syscall: true,
+        max_program_length: Some(MAX_PROGRAM_SIZE * 2),
+        ..build_dummy_config()
+    };
+    let program = Bytes::from(vec![0x52; MAX_PROGRAM_SIZE + 1]);
+    let error = run(&config, &tree, &program).err().unwrap();
+    assert_eq!(
+        error.downcast_ref::<Error>(),
+        Some(&Error::ProgramTooLong(
+            MAX_PROGRAM_SIZE + 1,
+            MAX_PROGRAM_SIZE
+        ))
+    );
+
+    // A generator with a single loadable segment of 0x10000 bytes at
+    // 0x3e0000, BSS included, leaves 0x10000 bytes for arguments and stack
+    let mut generator = vec![0u8; 120];
+    generator[0..6].copy_from_slice(b"\x7fELF\x02\x01");
+    generator[32] = 64; // program header offset
+    generator[54] = 56; // program header size
+    generator[56] = 1; // program header count
+    generator[64] = 1; // PT_LOAD
+    generator[80..88].copy_from_slice(&0x3e_0000u64.to_le_bytes());
+    generator[104..112].copy_from_slice(&0x1_0000u64.to_le_bytes());
+    let config = Config {
+        generator: Bytes::from(generator),
+        ..Default::default()
+    };
+    // Arguments: "generator", length and program with terminators take 116
+    // bytes, aligned to 128, then 40 bytes of argc and pointers, followed by
+    // 64 KiB of stack
+    let program = Bytes::from(vec![0x52; 100]);
+    let error = run(&config, &tree, &program).err().unwrap();
+    assert_eq!(
+        error.downcast_ref::<Error>(),
+        Some(&Error::InsufficientMemory(0x40_0000 + 128 + 40, 0x40_0000))
+    );
+
+    let config = Config::default();
+    let error = run(&config, &tree, &program).err().unwrap();
+    assert_eq!(error.to_string(), "Generator is not a valid ELF!");
+}
+
+#[test]
+pub fn test_store_transaction() {
+    let mut tree: SparseMerkleTree<CkbBlake2bHasher, H256, DefaultStore<H256>> =
+        SparseMerkleTree::default();
+    let key = hex_to_h256("e8c0265680a02b680b6cbc880348f062b825b28e237da7169aded4bcac0a04e5");
+    let value = hex_to_h256("2ca41595841e46ce8e74ad749e5c3f1d17202150f99c3d8631233ebdd19b19eb");
+    tree.update(key, value).unwrap();
+    let old_root_hash = *tree.root();
+
+    let new_key = hex_to_h256("a9bb945be71f0bd2757d33d2465b6387383da42f321072e47472f0c9c7428a8a");
+    let new_value = hex_to_h256("a939a47335f777eac4c40fbc0970e25f832a24e1d55adc45a7b76d63fe364e82");
+    let mut staged_tree: SparseMerkleTree<CkbBlake2bHasher, H256, StoreTransaction<_>> =
+        SparseMerkleTree::new(old_root_hash, StoreTransaction::new(tree.store()));
+    staged_tree.update(new_key, new_value).unwrap();
+    staged_tree.update(key, H256::default()).unwrap();
+    let new_root_hash = *staged_tree.root();
+    assert_eq!(new_value, staged_tree.get(&new_key).unwrap());
+    assert_eq!(H256::default(), staged_tree.get(&key).unwrap());
+    assert_eq!(value, tree.get(&key).unwrap());
+    assert_eq!(H256::default(), tree.get(&new_key).unwrap());
+
+    let changes = staged_tree.take_store().into_changes();
+    changes.commit_into(tree.store_mut()).unwrap();
+    let tree: SparseMerkleTree<CkbBlake2bHasher, H256, DefaultStore<H256>> =
+        SparseMerkleTree::new(new_root_hash, tree.take_store());
+    assert_eq!(new_value, tree.get(&new_key).unwrap());
+    assert_eq!(H256::default(), tree.get(&key).unwrap());
+}
+
+#[test]
+pub fn test_prefixed_store_clears_own_namespace() {
+    let backend = Arc::new(RwLock::new(MemoryBackend::new()));
+    let mut store_a = PrefixedStore::new(Arc::clone(&backend), [1u8; 32]);
+    let mut store_b = PrefixedStore::new(Arc::clone(&backend), [2u8; 32]);
+    let leaf_hash = hex_to_h256("e8c0265680a02b680b6cbc880348f062b825b28e237da7169aded4bcac0a04e5");
+    let leaf = LeafNode {
+        key: hex_to_h256("a9bb945be71f0bd2757d33d2465b6387383da42f321072e47472f0c9c7428a8a"),
+        value: hex_to_h256("a939a47335f777eac4c40fbc0970e25f832a24e1d55adc45a7b76d63fe364e82"),
+    };
+    store_a.insert_leaf(leaf_hash, leaf.clone()).unwrap();
+    store_b.insert_leaf(leaf_hash, leaf.clone()).unwrap();
+    assert_eq!(2, backend.read().unwrap().len());
+
+    store_a.clear_store().unwrap();
+    assert_eq!(None, store_a.get_leaf(&leaf_hash).unwrap());
+    assert_eq!(Some(leaf), store_b.get_leaf(&leaf_hash).unwrap());
+    assert_eq!(1, backend.read().unwrap().len());
+}
+
+#[test]
+pub fn test_key_derivation() {
+    // Same vectors are checked against c/keys.h in C tests
+    let balances = namespace_key(b"balances");
+    assert_eq!(
+        balances,
+        hex_to_h256("9c226f8a2726eb95030ea8c06835ae4042ee9375058d30e1236cec98930992c9")
+    );
+    assert_eq!(
+        mapping_key(&balances, &[0x11; 20]),
+        hex_to_h256("d55ee9dce37b5283520e857947c77dbe2244c46a98d8fdd573a94a52b9f20a99")
+    );
+    assert_eq!(
+        array_key(&balances, 5),
+        hex_to_h256("f136d0d735aaf2006d1b66b4bd9475d18d18b2076f82d4388d396dc5f76b0a3d")
+    );
+
+    let value = u128_to_value(0x1112_1314_1516_1718_0102_0304_0506_0708);
+    assert_eq!(value.as_slice()[0], 0x08);
+    assert_eq!(value.as_slice()[8], 0x18);
+    assert_eq!(
+        value_to_u128(&value),
+        Some(0x1112_1314_1516_1718_0102_0304_0506_0708)
+    );
+    assert_eq!(value_to_u64(&value), None);
+}
+
+#[test]
+pub fn test_chunked_values() {
+    let mut tree: SparseMerkleTree<CkbBlake2bHasher, H256, DefaultStore<H256>> =
+        SparseMerkleTree::default();
+    let key = namespace_key(b"code");
+    let long_value: Vec<u8> = (0..100u8).collect();
+    store_bytes(&mut tree, &key, &long_value).unwrap();
+    assert_eq!(load_bytes(&tree, &key).unwrap(), Bytes::from(long_value));
+    assert_eq!(value_to_u64(&tree.get(&key).unwrap()), Some(100));
+
+    store_bytes(&mut tree, &key, b"short").unwrap();
+    assert_eq!(
+        load_bytes(&tree, &key).unwrap(),
+        Bytes::from_static(b"short")
+    );
+    for i in 1..4 {
+        assert_eq!(tree.get(&chunk_key(&key, i)).unwrap(), H256::zero());
+    }
+    assert!(load_bytes(&tree, &namespace_key(b"missing"))
+        .unwrap()
+        .is_empty());
+
+    // Lengths past the limit are rejected, including ones forged in state
+    let too_long = vec![0u8; MAX_VALUE_LENGTH as usize + 1];
+    assert_eq!(
+        store_bytes(&mut tree, &key, &too_long)
+            .unwrap_err()
+            .to_string(),
+        "Value is too long!"
+    );
+    tree.update(key, u64_to_value(std::u64::MAX)).unwrap();
+    assert_eq!(
+        load_bytes(&tree, &key).unwrap_err().to_string(),
+        "Value is too long!"
+    );
+}
+
+#[test]
+pub fn test_fullstorage_shards() {
+    // Empty state lists no shards, matching a main cell created without data
+    // cells in c/fullstorage.h
+    let state = FullStorageState::new(2);
+    assert!(state.shards.is_empty());
+    assert_eq!(state.get(&[1; 32].into()), H256::zero());
+    let empty = state.apply(&HashMap::default());
+    assert!(empty.state.shards.is_empty());
+    assert!(empty.replaced.is_empty());
+    assert_eq!(&empty.state.main_cell_data()[12..], &[0u8; 4][..]);
+    assert_eq!(
+        FullStorageState::from_cells(&empty.state.main_cell_data(), &[], 2).unwrap(),
+        empty.state
+    );
+
+    // Creating state from empty splits shards right away
+    let mut changes = HashMap::default();
+    for i in 1u8..=5 {
+        changes.insert(H256::from([i; 32]), H256::from([i + 10; 32]));
+    }
+    let update = state.apply(&changes);
+    assert!(update.replaced.is_empty());
+    assert_eq!(update.origins, vec![None, None, None]);
+    let shards = &update.state.shards;
+    assert_eq!(shards.len(), 3);
+    assert_eq!(shards[0].prefix, H256::zero());
+    assert_eq!(shards[1].prefix, H256::from([3; 32]));
+    assert_eq!(shards[2].prefix, H256::from([5; 32]));
+    assert_eq!(update.state.get(&[4; 32].into()), [14; 32].into());
+    assert_eq!(update.state.get(&[6; 32].into()), H256::zero());
+
+    // Only the shard holding changed key is rebuilt
+    let mut changes = HashMap::default();
+    changes.insert(H256::from([4; 32]), H256::from([40; 32]));
+    let next = update.state.apply(&changes);
+    assert_eq!(next.replaced, vec![1]);
+    assert_eq!(next.origins, vec![Some(0), None, Some(2)]);
+    assert_eq!(next.state.shards[0], update.state.shards[0]);
+    assert_eq!(next.state.get(&[4; 32].into()), [40; 32].into());
+
+    let data_cells: Vec<Bytes> = next.state.shards.iter().rev().map(|s| s.data()).collect();
+    let restored =
+        FullStorageState::from_cells(&next.state.main_cell_data(), &data_cells, 2).unwrap();
+    assert_eq!(restored, next.state);
+    assert!(
+        FullStorageState::from_cells(&next.state.main_cell_data(), &data_cells[1..], 2).is_err()
+    );
+
+    // A shard in the middle can be split into more than 2 shards
+    let key = |i: u8| {
+        let mut key = [4; 32];
+        key[31] = i;
+        H256::from(key)
+    };
+    let mut changes = HashMap::default();
+    for i in 0u8..4 {
+        changes.insert(key(i), H256::from([i; 32]));
+    }
+    let split = next.state.apply(&changes);
+    assert_eq!(split.replaced, vec![1]);
+    assert_eq!(split.origins, vec![Some(0), None, None, None, Some(2)]);
+    let prefixes: Vec<H256> = split.state.shards.iter().map(|s| s.prefix).collect();
+    assert_eq!(
+        prefixes,
+        vec![H256::zero(), [3; 32].into(), key(1), key(3), [5; 32].into()]
+    );
+    for shard in &split.state.shards[1..4] {
+        assert_eq!(shard.entries.len(), 2);
+    }
+    assert_eq!(split.state.get(&key(2)), [2; 32].into());
+    assert_eq!(split.state.get(&[4; 32].into()), [40; 32].into());
+}
+
+#[test]
+pub fn test_cycle_calibration_table() {
+    let calibration =
+        CycleCalibration::from_table("# measured offline\nbase 1000\n\nper_read 20\n").unwrap();
+    assert_eq!(calibration.base, 1000);
+    assert_eq!(calibration.per_read, 20);
+    assert_eq!(calibration.per_write, CycleCalibration::default().per_write);
+    assert!(CycleCalibration::from_table("per_leaf 20").is_err());
+    assert!(CycleCalibration::from_table("base").is_err());
+    assert!(CycleCalibration::from_table("base -1").is_err());
+}
+
+#[test]
+pub fn test_cycle_calibration_fit() {
+    let calibration = CycleCalibration {
+        base: 1_000_000,
+        per_witness_byte: 3,
+        per_read: 10_000,
+        per_write: 35_000,
+        per_proof_byte: 450,
+    };
+    let samples: Vec<CalibrationSample> = (0..20)
+        .map(|i| {
+            let reads = i % 4;
+            let writes = i % 3;
+            let proof_bytes = 100 + 17 * i + (i * i) % 7;
+            let witness_size = 300 + 64 * reads + 32 * writes + proof_bytes + (i % 5) * 8;
+            let cycles = 1_000_000
+                + 3 * witness_size as u64
+                + 10_000 * reads as u64
+                + 35_000 * writes as u64
+                + 450 * proof_bytes as u64;
+            CalibrationSample {
+                witness_size,
+                reads,
+                writes,
+                proof_bytes,
+                cycles,
+            }
+        })
+        .collect();
+    let fitted = CycleCalibration::fit(&samples).unwrap();
+    assert_eq!(fitted, calibration);
+    assert_eq!(
+        CycleCalibration::from_table(&fitted.to_table()).unwrap(),
+        calibration
+    );
+
+    // Reads always match writes here, so their costs can't be told apart
+    let degenerate: Vec<CalibrationSample> = samples
+        .into_iter()
+        .map(|sample| CalibrationSample {
+            reads: sample.writes,
+            ..sample
+        })
+        .collect();
+    assert!(CycleCalibration::fit(&degenerate).is_err());
+
+    // Entries too large for u64 are rejected rather than truncated
+    let huge: Vec<CalibrationSample> = (0..5)
+        .map(|i| CalibrationSample {
+            witness_size: (i == 1) as usize,
+            reads: (i == 2) as usize,
+            writes: (i == 3) as usize,
+            proof_bytes: (i == 4) as usize,
+            cycles: std::u64::MAX,
+        })
+        .collect();
+    assert_eq!(
+        CycleCalibration::fit(&huge).unwrap_err().to_string(),
+        "Calibration samples are out of range!"
+    );
+}
+
+#[test]
+pub fn test_estimate() {
+    let proof = RunProofResult {
+        read_values: vec![([1; 32].into(), [2; 32].into())],
+        read_proof: Bytes::from(vec![7; 10]),
+        write_values: vec![([3; 32].into(), H256::zero(), [4; 32].into())],
+        write_old_proof: Bytes::from(vec![8; 20]),
+    };
+    let config = Config {
+        lock_script: Some(Script::default()),
+        ..Default::default()
+    };
+    let program = Bytes::from_static(b"program");
+    let calibration = CycleCalibration {
+        base: 1000,
+        per_witness_byte: 2,
+        per_read: 30,
+        per_write: 400,
+        per_proof_byte: 5,
+    };
+    let estimate = proof
+        .estimate(&config, &program, None, &calibration)
+        .unwrap();
+
+    // Proof content: program with its length (11), read values (68), read
+    // proof (14), old values of writes (36) and write old proof (24)
+    let content = proof.serialize(&program).unwrap();
+    assert_eq!(content.len(), 153);
+    // WitnessArgs header (16), plus output_type holding the content (157)
+    assert_eq!(estimate.witness_size, 173);
+    // Transaction header (12), raw transaction (263) and witnesses (185),
+    // plus 4 bytes of offset in blocks. The raw transaction consists of its
+    // header (28), version (4), the validator cell dep (41), header deps (4),
+    // inputs (4), the account cell output (138) and its data (44).
+    assert_eq!(estimate.transaction_size, 464);
+    // 1000 + 2 * 173 + 30 + 400 + 5 * (10 + 2 * 20)
+    assert_eq!(estimate.validator_cycles, 2026);
+
+    // Same sizes as the transaction built by hand
+    let witness = WitnessArgs::new_builder()
+        .output_type(BytesOpt::new_builder().set(Some(content.pack())).build())
+        .build();
+    let output = CellOutput::new_builder()
+        .lock(Script::default())
+        .type_(
+            ScriptOpt::new_builder()
+                .set(Some(Script::default()))
+                .build(),
+        )
+        .build();
+    let transaction = TransactionBuilder::default()
+        .cell_dep(config.validator_cell_dep())
+        .output(output.clone())
+        .output_data(Bytes::from(vec![0; 32]).pack())
+        .witness(witness.as_bytes().pack())
+        .build();
+    assert_eq!(witness.as_slice().len(), estimate.witness_size);
+    assert_eq!(
+        transaction.data().as_slice().len() + 4,
+        estimate.transaction_size
+    );
+
+    // Updating an existing cell adds its input (44), and moves the content
+    // to input_type
+    let last_cell = (OutPoint::default(), output, Bytes::from(vec![0; 32]));
+    let update = proof
+        .estimate(&config, &program, Some(&last_cell), &calibration)
+        .unwrap();
+    assert_eq!(update.witness_size, 173);
+    assert_eq!(update.transaction_size, 508);
+    assert_eq!(update.validator_cycles, 2026);
+    // Huge calibrations saturate instead of overflowing
+    let huge = CycleCalibration {
+        per_proof_byte: std::u64::MAX / 2,
+        ..calibration.clone()
+    };
+    let saturated = proof.estimate(&config, &program, None, &huge).unwrap();
+    assert_eq!(saturated.validator_cycles, std::u64::MAX);
+
+    // Without a lock script, the first cell can't be created
+    let config = Config::default();
+    let error = proof
+        .estimate(&config, &program, None, &calibration)
+        .err()
+        .unwrap();
+    assert_eq!(error.to_string(), "No valid lock script to use!");
+    let mut tree: SparseMerkleTree<CkbBlake2bHasher, H256, DefaultStore<H256>> =
+        SparseMerkleTree::default();
+    tree.update(H256::from([1; 32]), H256::from([2; 32]))
+        .unwrap();
+    let mut result = RunResult::default();
+    result
+        .read_values
+        .insert(H256::from([1; 32]), H256::from([2; 32]));
+    assert!(result
+        .estimate(&tree, &config, &program, None, &calibration)
+        .is_err());
+    // The existing cell's lock script is used otherwise
+    assert!(proof
+        .estimate(&config, &program, Some(&last_cell), &calibration)
+        .is_ok());
+}
+
+#[test]
+pub fn test_diff_states() {
+    let mut old_tree: SparseMerkleTree<CkbBlake2bHasher, H256, DefaultStore<H256>> =
+        SparseMerkleTree::default();
+    for i in 1..=3u8 {
+        old_tree
+            .update(H256::from([i; 32]), H256::from([i + 10; 32]))
+            .unwrap();
+    }
+    let mut new_tree: SparseMerkleTree<CkbBlake2bHasher, H256, DefaultStore<H256>> =
+        SparseMerkleTree::new(*old_tree.root(), old_tree.store().clone());
+    let diff = diff_states(
+        old_tree.store(),
+        old_tree.root(),
+        new_tree.store(),
+        new_tree.root(),
+    )
+    .unwrap();
+    assert!(diff.is_empty());
+
+    new_tree
+        .update(H256::from([2; 32]), H256::from([20; 32]))
+        .unwrap();
+    new_tree.update(H256::from([3; 32]), H256::zero()).unwrap();
+    new_tree
+        .update(H256::from([4; 32]), H256::from([14; 32]))
+        .unwrap();
+    let diff = diff_states(
+        old_tree.store(),
+        old_tree.root(),
+        new_tree.store(),
+        new_tree.root(),
+    )
+    .unwrap();
+    assert_eq!(diff.added, vec![([4; 32].into(), [14; 32].into())]);
+    assert_eq!(diff.removed, vec![([3; 32].into(), [13; 32].into())]);
+    assert_eq!(
+        diff.modified,
+        vec![([2; 32].into(), [12; 32].into(), [20; 32].into())]
+    );
+
+    let reversed = diff_states(
+        new_tree.store(),
+        new_tree.root(),
+        old_tree.store(),
+        old_tree.root(),
+    )
+    .unwrap();
+    assert_eq!(reversed.added, diff.removed);
+    assert_eq!(reversed.removed, diff.added);
+}
+
+/// Counts branch reads, to check how much of the trees is visited
+#[derive(Default)]
+struct CountingStore {
+    inner: DefaultStore<H256>,
+    branch_reads: Cell<usize>,
+}
+
+impl CountingStore {
+    fn new(inner: &DefaultStore<H256>) -> Self {
+        CountingStore {
+            inner: inner.clone(),
+            branch_reads: Cell::new(0),
+        }
+    }
+}
+
+impl Store<H256> for CountingStore {
+    fn get_branch(&self, node: &H256) -> Result<Option<BranchNode>, SMTError> {
+        self.branch_reads.set(self.branch_reads.get() + 1);
+        self.inner.get_branch(node)
+    }
+    fn get_leaf(&self, leaf_hash: &H256) -> Result<Option<LeafNode<H256>>, SMTError> {
+        self.inner.get_leaf(leaf_hash)
+    }
+    fn insert_branch(&mut self, node: H256, branch: BranchNode) -> Result<(), SMTError> {
+        self.inner.insert_branch(node, branch)
+    }
+    fn insert_leaf(&mut self, leaf_hash: H256, leaf: LeafNode<H256>) -> Result<(), SMTError> {
+        self.inner.insert_leaf(leaf_hash, leaf)
+    }
+    fn remove_branch(&mut self, node: &H256) -> Result<(), SMTError> {
+        self.inner.remove_branch(node)
+    }
+    fn remove_leaf(&mut self, leaf_hash: &H256) -> Result<(), SMTError> {
+        self.inner.remove_leaf(leaf_hash)
+    }
+}
+
+impl ClearStore for CountingStore {
+    fn clear_store(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        self.inner.clear_store()
+    }
+}
+
+/// Diffs two trees, returning the diff and the number of branch reads
+fn counted_diff(
+    old_tree: &SparseMerkleTree<CkbBlake2bHasher, H256, DefaultStore<H256>>,
+    new_tree: &SparseMerkleTree<CkbBlake2bHasher, H256, DefaultStore<H256>>,
+) -> (StateDiff, usize) {
+    let old_store = CountingStore::new(old_tree.store());
+    let new_store = CountingStore::new(new_tree.store());
+    let diff = diff_states(&old_store, old_tree.root(), &new_store, new_tree.root()).unwrap();
+    let reads = old_store.branch_reads.get() + new_store.branch_reads.get();
+    (diff, reads)
+}
+
+#[test]
+pub fn test_diff_states_visits_changed_paths() {
+    // Keys differ in every byte, so the 256 leaves form a tree of 255
+    // branches with 8 levels
+    let mut old_tree: SparseMerkleTree<CkbBlake2bHasher, H256, DefaultStore<H256>> =
+        SparseMerkleTree::default();
+    for i in 0..=255u8 {
+        old_tree.update([i; 32].into(), [1; 32].into()).unwrap();
+    }
+    let empty_tree: SparseMerkleTree<CkbBlake2bHasher, H256, DefaultStore<H256>> =
+        SparseMerkleTree::default();
+    let (diff, full_reads) = counted_diff(&old_tree, &empty_tree);
+    assert_eq!(diff.removed.len(), 256);
+    assert!(full_reads >= 255);
+
+    let (diff, reads) = counted_diff(&old_tree, &old_tree);
+    assert!(diff.is_empty());
+    assert_eq!(reads, 0);
+
+    // Each changed key costs a walk down both trees, at most a branch and a
+    // missed lookup for its leaf per level
+    for changed in &[1usize, 4, 16] {
+        let mut new_tree: SparseMerkleTree<CkbBlake2bHasher, H256, DefaultStore<H256>> =
+            SparseMerkleTree::new(*old_tree.root(), old_tree.store().clone());
+        for i in 0..*changed {
+            let key = (i * 256 / changed) as u8;
+            new_tree.update([key; 32].into(), [2; 32].into()).unwrap();
+        }
+        let (diff, reads) = counted_diff(&old_tree, &new_tree);
+        assert_eq!(diff.modified.len(), *changed);
+        assert!(
+            reads <= changed * 2 * 2 * 9,
+            "{} reads for {} changed keys",
+            reads,
+            changed
+        );
+    }
+}
+
+#[test]
+pub fn test_diff_states_single_leaf_and_empty_roots() {
+    let tree_with = |leaves: &[(u8, u8)]| {
+        let mut tree: SparseMerkleTree<CkbBlake2bHasher, H256, DefaultStore<H256>> =
+            SparseMerkleTree::default();
+        for (key, value) in leaves {
+            tree.update([*key; 32].into(), [*value; 32].into()).unwrap();
+        }
+        tree
+    };
+    let diff =
+        |old: &[(u8, u8)], new: &[(u8, u8)]| counted_diff(&tree_with(old), &tree_with(new)).0;
+
+    assert!(diff(&[], &[]).is_empty());
+    assert!(diff(&[(1, 1)], &[(1, 1)]).is_empty());
+    assert_eq!(
+        diff(&[], &[(1, 1)]).added,
+        vec![([1; 32].into(), [1; 32].into())]
+    );
+    assert_eq!(
+        diff(&[(1, 1)], &[]).removed,
+        vec![([1; 32].into(), [1; 32].into())]
+    );
+    assert_eq!(
+        diff(&[(1, 1)], &[(1, 2)]).modified,
+        vec![([1; 32].into(), [1; 32].into(), [2; 32].into())]
+    );
+    let replaced = diff(&[(1, 1)], &[(2, 2)]);
+    assert_eq!(replaced.added, vec![([2; 32].into(), [2; 32].into())]);
+    assert_eq!(replaced.removed, vec![([1; 32].into(), [1; 32].into())]);
+    assert!(replaced.modified.is_empty());
+    // Single leaf roots against roots of branches, both ways
+    let grown = diff(&[(1, 1)], &[(1, 1), (2, 2)]);
+    assert_eq!(grown.added, vec![([2; 32].into(), [2; 32].into())]);
+    assert!(grown.removed.is_empty() && grown.modified.is_empty());
+    let shrunk = diff(&[(1, 1), (2, 2)], &[(2, 3)]);
+    assert_eq!(shrunk.removed, vec![([1; 32].into(), [1; 32].into())]);
+    assert_eq!(
+        shrunk.modified,
+        vec![([2; 32].into(), [2; 32].into(), [3; 32].into())]
+    );
+    assert!(shrunk.added.is_empty());
+}
+
+#[test]
+pub fn test_run_parallel_reruns_conflicts() {
+    let mut tree: SparseMerkleTree<CkbBlake2bHasher, H256, DefaultStore<H256>> =
+        SparseMerkleTree::default();
+    tree.update([3; 32].into(), [3; 32].into()).unwrap();
+    let config = build_dummy_config();
+    let mut programs = vec![Vec::new(); 3];
+    push_op(&mut programs[0], b'W', 1, 1);
+    // Fails against the base tree, succeeds once the first write is seen
+    push_op(&mut programs[1], b'R', 1, 1);
+    push_op(&mut programs[1], b'W', 2, 2);
+    push_op(&mut programs[2], b'R', 3, 3);
+    let programs: Vec<Bytes> = programs.into_iter().map(Bytes::from).collect();
+    assert!(run(&config, &tree, &programs[1]).is_err());
+
+    let results = run_parallel(&config, &tree, &programs, 2).unwrap();
+    assert_eq!(results.len(), 3);
+    assert_eq!(
+        results[1].read_values.get(&[1; 32].into()),
+        Some(&[1; 32].into())
+    );
+    assert_eq!(
+        results[1].write_values.get(&[2; 32].into()),
+        Some(&[2; 32].into())
+    );
+    let mut sequential = SparseMerkleTree::<CkbBlake2bHasher, H256, _>::new(
+        *tree.root(),
+        StoreTransaction::new(tree.store()),
+    );
+    for (program, result) in programs.iter().zip(&results) {
+        assert_eq!(&run(&config, &sequential, program).unwrap(), result);
+        result.commit(&mut sequential).unwrap();
+    }
+
+    // A failure without conflicting reads is reported, not run again
+    let mut failing = Vec::new();
+    push_op(&mut failing, b'R', 3, 4);
+    let programs = vec![programs[0].clone(), Bytes::from(failing)];
+    assert!(run_parallel(&config, &tree, &programs, 2).is_err());
+}
+
+#[test]
+pub fn test_scan_prefix_skips_unmatched_subtrees() {
+    // Keys only differ in their first byte, which sits at the bottom 8 levels
+    let mut account = SmtAccount::<CountingStore>::empty(Config::default());
+    for i in 0..=255u8 {
+        let mut key = [0u8; 32];
+        key[0] = i;
+        account.state.update(key.into(), [1; 32].into()).unwrap();
+    }
+    let reads = &account.state.store().branch_reads;
+
+    reads.set(0);
+    assert_eq!(account.iter_leaves().unwrap().count(), 256);
+    assert_eq!(reads.get(), 255);
+
+    reads.set(0);
+    let scanned: Vec<(H256, H256)> = account.scan_prefix(&[5]).unwrap().collect();
+    let mut key = [0u8; 32];
+    key[0] = 5;
+    assert_eq!(scanned, vec![(key.into(), [1; 32].into())]);
+    assert_eq!(reads.get(), 8);
+
+    assert_eq!(account.scan_prefix(&key[..]).unwrap().count(), 1);
+    assert_eq!(account.scan_prefix(&[5, 1]).unwrap().count(), 0);
+    let mut longer = key.to_vec();
+    longer.push(0);
+    assert_eq!(account.scan_prefix(&longer).unwrap().count(), 0);
+}